@@ -0,0 +1,9 @@
+-- Lua plugin using the request envelope: sees headers, body and route
+-- params, and sets the status and response headers.
+function handle_request(req)
+  return {
+    status = 200,
+    headers = { ["content-type"] = "text/plain" },
+    body = req.method .. " " .. req.uri .. " from " .. (req.client or "?") .. "\n" .. req.body,
+  }
+end
@@ -0,0 +1,97 @@
+use crate::handler_trait::{Context, RequestBody, full};
+use crate::middleware_trait::{Flow, Middleware, MiddlewareFuture};
+use hyper::header::{AUTHORIZATION, HeaderName, WWW_AUTHENTICATE};
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// `auth` options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthOptions {
+    /// Client names and their API keys; use `${VAR}` to keep keys out of
+    /// the config file.
+    pub api_keys: BTreeMap<String, String>,
+    /// Header carrying the key. Default `Authorization: Bearer <key>`.
+    pub header: Option<String>,
+}
+
+/// The client an `auth` middleware let in, for the handlers after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthUser(pub String);
+
+/// Answers 401 unless the request carries one of the configured API keys.
+pub struct AuthMiddleware {
+    keys: Vec<(String, String)>,
+    header: Option<HeaderName>,
+}
+
+impl AuthMiddleware {
+    pub fn new(options: AuthOptions) -> Result<Self, String> {
+        if options.api_keys.is_empty() {
+            return Err("api_keys must not be empty".to_string());
+        }
+        if let Some((name, _)) = options.api_keys.iter().find(|(_, key)| key.is_empty()) {
+            return Err(format!("empty api key for {}", name));
+        }
+        let header = options
+            .header
+            .map(|h| {
+                HeaderName::from_bytes(h.as_bytes())
+                    .map_err(|_| format!("invalid header name {:?}", h))
+            })
+            .transpose()?;
+        Ok(AuthMiddleware {
+            keys: options.api_keys.into_iter().collect(),
+            header,
+        })
+    }
+
+    fn presented_key<'r>(&self, req: &'r Request<RequestBody>) -> Option<&'r [u8]> {
+        match self.header {
+            Some(ref name) => req.headers().get(name).map(|v| v.as_bytes()),
+            None => req
+                .headers()
+                .get(AUTHORIZATION)?
+                .as_bytes()
+                .strip_prefix(b"Bearer "),
+        }
+    }
+
+    /// The client owning `key`. Every key is compared in full so the time
+    /// taken does not tell how much of a key matched.
+    fn client(&self, key: &[u8]) -> Option<&str> {
+        self.keys.iter().fold(None, |found, (name, expected)| {
+            let matches = constant_time_eq(expected.as_bytes(), key);
+            found.or(matches.then_some(name.as_str()))
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl Middleware for AuthMiddleware {
+    fn before<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        ctx: &'a Context,
+    ) -> MiddlewareFuture<'a, Flow> {
+        let client = self.presented_key(&req).and_then(|key| self.client(key));
+        let flow = match client {
+            Some(name) => {
+                ctx.insert(AuthUser(name.to_string()));
+                Flow::Continue(req)
+            }
+            None => {
+                let mut resp = Response::builder().status(StatusCode::UNAUTHORIZED);
+                if self.header.is_none() {
+                    resp = resp.header(WWW_AUTHENTICATE, "Bearer");
+                }
+                Flow::Respond(resp.body(full("Unauthorized")).unwrap())
+            }
+        };
+        Box::pin(async move { flow })
+    }
+}
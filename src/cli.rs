@@ -0,0 +1,143 @@
+//! Command-line options, modelled on nginx: `-c`, `-t`, `-T` and `-s`.
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG: &str = "config.yaml";
+
+pub const USAGE: &str = "\
+usage: wigspace-rust [-c file] [-t | -T | -s signal]
+
+  -c file    use this config file (default config.yaml)
+  -t, --test-config
+             test the config and plugins, then exit
+  -T         like -t, then print the effective config
+  -s signal  send reload, stop or reopen to the running server
+  -h         show this help";
+
+/// What the process was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Run,
+    Test,
+    /// Test, then dump the config.
+    Dump,
+    Signal(SignalCommand),
+    Help,
+}
+
+/// `-s` commands and the signals they send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalCommand {
+    /// SIGHUP: reload the config.
+    Reload,
+    /// SIGTERM: drain and exit.
+    Stop,
+    /// SIGUSR1: reopen the log files.
+    Reopen,
+}
+
+impl fmt::Display for SignalCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SignalCommand::Reload => "reload",
+            SignalCommand::Stop => "stop",
+            SignalCommand::Reopen => "reopen",
+        })
+    }
+}
+
+impl SignalCommand {
+    pub fn signal(self) -> libc::c_int {
+        match self {
+            SignalCommand::Reload => libc::SIGHUP,
+            SignalCommand::Stop => libc::SIGTERM,
+            SignalCommand::Reopen => libc::SIGUSR1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub config: PathBuf,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliError {
+    MissingValue(&'static str),
+    UnknownSignal(String),
+    UnknownOption(String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::MissingValue(opt) => write!(f, "option {} requires a value", opt),
+            CliError::UnknownSignal(s) => {
+                write!(f, "invalid signal {:?}, expected reload, stop or reopen", s)
+            }
+            CliError::UnknownOption(o) => write!(f, "invalid option {:?}", o),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl Options {
+    /// Parse the arguments after the program name. When several actions
+    /// are given the last one wins.
+    pub fn parse<I, S>(args: I) -> Result<Self, CliError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut options = Options {
+            config: PathBuf::from(DEFAULT_CONFIG),
+            action: Action::Run,
+        };
+        let mut args = args.into_iter().map(Into::into);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" => {
+                    let path = args.next().ok_or(CliError::MissingValue("-c"))?;
+                    options.config = PathBuf::from(path);
+                }
+                "-t" | "--test-config" => options.action = Action::Test,
+                "-T" => options.action = Action::Dump,
+                "-s" => {
+                    let name = args.next().ok_or(CliError::MissingValue("-s"))?;
+                    options.action = Action::Signal(match name.as_str() {
+                        "reload" => SignalCommand::Reload,
+                        "stop" => SignalCommand::Stop,
+                        "reopen" => SignalCommand::Reopen,
+                        _ => return Err(CliError::UnknownSignal(name)),
+                    });
+                }
+                "-h" | "--help" => options.action = Action::Help,
+                _ => return Err(CliError::UnknownOption(arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Send `command` to the process whose id is in `pid_file`. Returns the pid.
+pub fn send_signal(pid_file: &Path, command: SignalCommand) -> std::io::Result<i32> {
+    let content = std::fs::read_to_string(pid_file)?;
+    let pid = content
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .filter(|&pid| pid > 0)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid pid {:?} in {}", content.trim(), pid_file.display()),
+            )
+        })?;
+    // SAFETY: kill has no memory-safety requirements.
+    if unsafe { libc::kill(pid, command.signal()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(pid)
+}
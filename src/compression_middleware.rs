@@ -0,0 +1,257 @@
+use crate::handler_trait::{BoxError, Context, RequestBody, ResponseBody};
+use crate::middleware_trait::{Flow, Middleware, MiddlewareFuture, RequestHead};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{
+    ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, HeaderValue, VARY,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::io::Write;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll, ready};
+
+/// Responses shorter than this are sent as they are (default for
+/// `min_length`).
+pub const DEFAULT_MIN_LENGTH: u64 = 256;
+
+/// Content types compressed when `types` is not set; entries ending in `/`
+/// match the whole type.
+pub const DEFAULT_TYPES: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// `compression` options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionOptions {
+    /// gzip level, 1 (fastest) to 9 (smallest). Default 6.
+    #[serde(default, deserialize_with = "crate::config::opt_scalar")]
+    pub level: Option<u32>,
+    #[serde(default, deserialize_with = "crate::config::opt_scalar")]
+    pub min_length: Option<u64>,
+    pub types: Option<Vec<String>>,
+}
+
+/// The request said it accepts gzip.
+#[derive(Clone)]
+struct AcceptsGzip;
+
+/// gzips responses for clients that accept it, streaming the body through
+/// the encoder frame by frame.
+pub struct CompressionMiddleware {
+    level: Compression,
+    min_length: u64,
+    types: Vec<String>,
+}
+
+impl CompressionMiddleware {
+    pub fn new(options: CompressionOptions) -> Result<Self, String> {
+        let level = match options.level {
+            None => Compression::default(),
+            Some(level @ 1..=9) => Compression::new(level),
+            Some(level) => return Err(format!("level {} is not between 1 and 9", level)),
+        };
+        Ok(CompressionMiddleware {
+            level,
+            min_length: options.min_length.unwrap_or(DEFAULT_MIN_LENGTH),
+            types: options
+                .types
+                .unwrap_or_else(|| DEFAULT_TYPES.iter().map(|t| t.to_string()).collect()),
+        })
+    }
+
+    /// Partial responses are left alone: their ranges refer to the
+    /// uncompressed bytes.
+    fn compressible(&self, head: &RequestHead, resp: &Response<ResponseBody>) -> bool {
+        if head.method == Method::HEAD
+            || matches!(
+                resp.status(),
+                StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED | StatusCode::PARTIAL_CONTENT
+            )
+            || resp.headers().contains_key(CONTENT_ENCODING)
+            || resp.headers().contains_key(CONTENT_RANGE)
+        {
+            return false;
+        }
+        if let Some(len) = resp.body().size_hint().exact()
+            && len < self.min_length
+        {
+            return false;
+        }
+        let Some(content_type) = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        self.types.iter().any(|t| {
+            if t.ends_with('/') {
+                mime.starts_with(t.as_str())
+            } else {
+                mime == *t
+            }
+        })
+    }
+}
+
+/// Whether an `Accept-Encoding` value allows gzip.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or("");
+        let refused = parts.any(|p| {
+            p.strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !refused
+    })
+}
+
+/// The weak form of a strong ETag; `None` when it is weak already.
+fn weak_etag(etag: &HeaderValue) -> Option<HeaderValue> {
+    let etag = etag.to_str().ok()?;
+    if etag.starts_with("W/") {
+        return None;
+    }
+    HeaderValue::from_str(&format!("W/{}", etag)).ok()
+}
+
+impl Middleware for CompressionMiddleware {
+    fn before<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        ctx: &'a Context,
+    ) -> MiddlewareFuture<'a, Flow> {
+        let gzip = req
+            .headers()
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(accepts_gzip);
+        if gzip {
+            ctx.insert(AcceptsGzip);
+        }
+        Box::pin(async move { Flow::Continue(req) })
+    }
+
+    fn after<'a>(
+        &'a self,
+        head: &'a RequestHead,
+        ctx: &'a Context,
+        resp: Response<ResponseBody>,
+    ) -> MiddlewareFuture<'a, Response<ResponseBody>> {
+        let resp = if self.compressible(head, &resp) {
+            let gzip = ctx.get::<AcceptsGzip>().is_some();
+            let (mut parts, body) = resp.into_parts();
+            parts.headers.append(VARY, ACCEPT_ENCODING.into());
+            if gzip {
+                parts.headers.remove(CONTENT_LENGTH);
+                // The gzipped bytes differ from the ones the validator and
+                // any byte ranges describe.
+                parts.headers.remove(ACCEPT_RANGES);
+                if let Some(etag) = parts.headers.get(ETAG).and_then(weak_etag) {
+                    parts.headers.insert(ETAG, etag);
+                }
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+                Response::from_parts(parts, GzipBody::new(body, self.level).boxed())
+            } else {
+                Response::from_parts(parts, body)
+            }
+        } else {
+            resp
+        };
+        Box::pin(async move { resp })
+    }
+}
+
+/// A body gzipped as it streams; each frame is flushed through the encoder
+/// so streamed responses are not held back.
+struct GzipBody {
+    inner: ResponseBody,
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    /// Trailers read before the gzip footer was sent.
+    trailers: Option<Frame<Bytes>>,
+}
+
+impl GzipBody {
+    fn new(inner: ResponseBody, level: Compression) -> Self {
+        GzipBody {
+            inner,
+            encoder: Some(GzEncoder::new(Vec::new(), level)),
+            trailers: None,
+        }
+    }
+
+    fn finish(&mut self) -> Option<Result<Frame<Bytes>, BoxError>> {
+        let encoder = self.encoder.take()?;
+        Some(
+            encoder
+                .finish()
+                .map(|out| Frame::data(Bytes::from(out)))
+                .map_err(BoxError::from),
+        )
+    }
+}
+
+impl Body for GzipBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.get_mut();
+        loop {
+            let Some(encoder) = this.encoder.as_mut() else {
+                return Poll::Ready(this.trailers.take().map(Ok));
+            };
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(this.finish()),
+            };
+            match frame.into_data() {
+                Ok(data) => {
+                    if let Err(e) = encoder.write_all(&data).and_then(|_| encoder.flush()) {
+                        return Poll::Ready(Some(Err(e.into())));
+                    }
+                    let out = std::mem::take(encoder.get_mut());
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(Bytes::from(out)))));
+                    }
+                }
+                Err(trailers) => {
+                    this.trailers = Some(trailers);
+                    return Poll::Ready(this.finish());
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.encoder.is_none() && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::default()
+    }
+}
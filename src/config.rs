@@ -5,29 +5,1021 @@ impl Default for Config {
             port: 8080,
             static_dir: None,
             proxy_pass: None,
+            proxy_connect_timeout: None,
+            proxy_read_timeout: None,
             access_log: None,
             error_log: None,
             plugins_dir: Some("plugins".to_string()),
             plugin_endpoints: None,
+            plugin_timeout: None,
+            plugin_concurrency: None,
+            routes: None,
+            upstreams: None,
+            index: None,
+            autoindex: None,
+            autoindex_format: None,
+            tls: None,
+            http2: None,
+            servers: None,
+            middleware: None,
+            shutdown_timeout: None,
+            pid_file: None,
         }
     }
 }
-use serde::Deserialize;
+use crate::middleware_registry::MiddlewareRegistry;
+use crate::router::{self, RouteTarget};
+use crate::vhost::{Listen, VirtualHosts};
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::{fs, path::Path};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     pub address: String,
+    #[serde(deserialize_with = "scalar")]
     pub port: u16,
     pub static_dir: Option<String>,
     pub proxy_pass: Option<String>,
+    /// Seconds to wait for an upstream connection (default 60, read at startup).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub proxy_connect_timeout: Option<u64>,
+    /// Seconds to wait for the upstream response and between body reads (default 60).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub proxy_read_timeout: Option<u64>,
     pub access_log: Option<String>,
     pub error_log: Option<String>,
     pub plugins_dir: Option<String>,
     pub plugin_endpoints: Option<std::collections::HashMap<String, String>>,
+    /// Seconds a plugin call may take before the request is answered 504
+    /// (default 30).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub plugin_timeout: Option<u64>,
+    /// Calls one plugin runs at once; further requests wait for a free slot
+    /// within `plugin_timeout` (default 16, read when the plugin is loaded).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub plugin_concurrency: Option<usize>,
+    pub routes: Option<Vec<RouteConfig>>,
+    /// Named backend groups, referenced as `proxy_pass: http://<name>`.
+    pub upstreams: Option<std::collections::HashMap<String, UpstreamConfig>>,
+    /// Index files tried for directory requests (default `[index.html]`).
+    pub index: Option<Vec<String>>,
+    /// List directories that have no index file (default false).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub autoindex: Option<bool>,
+    pub autoindex_format: Option<AutoindexFormat>,
+    /// HTTPS listener, served next to the plaintext one.
+    pub tls: Option<TlsConfig>,
+    /// HTTP/2 settings, applied to connections accepted after a reload.
+    pub http2: Option<Http2Config>,
+    /// Virtual hosts. Without it a single server is built from the
+    /// top-level `address`, `port`, `tls` and site settings.
+    pub servers: Option<Vec<ServerConfig>>,
+    /// Middleware of the single server built when there are no `servers`.
+    pub middleware: Option<Vec<MiddlewareConfig>>,
+    /// Seconds to let in-flight requests finish on SIGTERM/SIGINT (default 30).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub shutdown_timeout: Option<u64>,
+    /// Where the server writes its process id (default `server.pid`).
+    pub pid_file: Option<String>,
 }
 
-pub fn load_config<P: AsRef<Path>>(path: P) -> Config {
-    let content = fs::read_to_string(path).expect("Failed to read config file");
-    serde_yaml::from_str(&content).expect("Failed to parse config file")
+/// One `servers:` entry, like an nginx `server {}` block.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ServerConfig {
+    /// `host:port` or `port`, optionally followed by ` ssl`; a bare port
+    /// binds the top-level `address`. Default `address:port`.
+    pub listen: Option<Vec<String>>,
+    /// Exact names, `*.example.com`, `www.example.*`, `.example.com` (the
+    /// name and all its subdomains) or `~regex`.
+    pub server_name: Option<Vec<String>>,
+    /// Answer requests on this server's listeners that match no name
+    /// (default: the first server listening there).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub default_server: Option<bool>,
+    /// The site settings below belong to this server only and are not
+    /// inherited from the top level, except `index` and `autoindex*`.
+    pub static_dir: Option<String>,
+    pub proxy_pass: Option<String>,
+    pub plugin_endpoints: Option<std::collections::HashMap<String, String>>,
+    pub routes: Option<Vec<RouteConfig>>,
+    pub index: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub autoindex: Option<bool>,
+    pub autoindex_format: Option<AutoindexFormat>,
+    /// Middleware wrapped around all of this server's handlers, outermost
+    /// first (default `[logging]`).
+    pub middleware: Option<Vec<MiddlewareConfig>>,
+}
+
+/// A `middleware:` entry: a registered name, or a mapping with the `name`
+/// and that middleware's options.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum MiddlewareConfig {
+    Name(String),
+    Options {
+        name: String,
+        #[serde(flatten)]
+        options: serde_yaml::Mapping,
+    },
+}
+
+impl MiddlewareConfig {
+    pub fn name(&self) -> &str {
+        match self {
+            MiddlewareConfig::Name(name) | MiddlewareConfig::Options { name, .. } => name,
+        }
+    }
+
+    /// The options as a mapping, empty for a bare name.
+    pub fn options(&self) -> serde_yaml::Value {
+        match self {
+            MiddlewareConfig::Name(_) => serde_yaml::Value::Mapping(Default::default()),
+            MiddlewareConfig::Options { options, .. } => {
+                serde_yaml::Value::Mapping(options.clone())
+            }
+        }
+    }
+}
+
+/// HTTP/2 is negotiated with ALPN over TLS and detected from the connection
+/// preface (prior knowledge) on plaintext listeners.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct Http2Config {
+    /// Accept HTTP/2 at all (default true).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub enabled: Option<bool>,
+    /// Concurrent streams per connection (default 200).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub max_concurrent_streams: Option<u32>,
+    /// Flow-control window per stream, in bytes.
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub initial_stream_window_size: Option<u32>,
+    /// Flow-control window per connection, in bytes.
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub initial_connection_window_size: Option<u32>,
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub max_frame_size: Option<u32>,
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub max_header_list_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Listen port (default 8443); the address is shared with `address`.
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub port: Option<u16>,
+    /// Certificates picked by SNI. The first one without `server_names`, or
+    /// else the first one, answers clients that match no name.
+    pub certificates: Vec<TlsCertificateConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TlsCertificateConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: String,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key: String,
+    /// Host names served with this certificate; `*.example.com` matches one
+    /// label.
+    pub server_names: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoindexFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalance {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastConn,
+    /// Consistent hash on `hash_key`.
+    Hash,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpstreamConfig {
+    pub servers: Vec<UpstreamServerConfig>,
+    #[serde(default)]
+    pub strategy: LoadBalance,
+    /// `client_ip` (default) or `header:<name>`, for the `hash` strategy.
+    pub hash_key: Option<String>,
+    /// Consecutive failures before a backend is marked down (default 1, 0 disables).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub max_fails: Option<u32>,
+    /// Seconds a failed backend stays down (default 10).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub fail_timeout: Option<u64>,
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// `GET <path>`; 2xx and 3xx responses pass.
+    #[default]
+    Http,
+    /// A plain TCP connect.
+    Tcp,
+}
+
+/// Active health check for every backend of an upstream group.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    #[serde(default, rename = "type")]
+    pub kind: HealthCheckKind,
+    /// Request path for `http` checks (default `/`).
+    pub path: Option<String>,
+    /// Seconds between checks (default 5).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub interval: Option<u64>,
+    /// Seconds before a probe counts as failed (default 2).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub timeout: Option<u64>,
+    /// Consecutive passes before a backend is healthy again (default 2).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub rise: Option<u32>,
+    /// Consecutive failures before a backend is unhealthy (default 3).
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub fall: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpstreamServerConfig {
+    /// `host:port` of the backend.
+    pub address: String,
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub weight: Option<u32>,
+}
+
+/// One entry of `routes:`. Exactly one of `path`, `prefix` or `regex` selects
+/// the match kind, and exactly one of `plugin` or `handler` the target.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteConfig {
+    /// Exact path, or a pattern with `:name` params and a trailing `*`/`*name`.
+    pub path: Option<String>,
+    pub prefix: Option<String>,
+    pub regex: Option<String>,
+    /// Allowed HTTP methods; all methods when omitted.
+    pub methods: Option<Vec<String>>,
+    /// Plugin file name in `plugins_dir`.
+    pub plugin: Option<String>,
+    /// Built-in handler name (`static` or `proxy`).
+    pub handler: Option<String>,
+    /// Per-route overrides of the top-level `index`, `autoindex` and
+    /// `autoindex_format`, for `static` routes.
+    pub index: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "opt_scalar")]
+    pub autoindex: Option<bool>,
+    pub autoindex_format: Option<AutoindexFormat>,
+    /// Middleware for this route only, inside the server's.
+    pub middleware: Option<Vec<MiddlewareConfig>>,
+}
+
+impl Config {
+    /// The configured `servers`, or one server made from the top-level
+    /// settings when there are none.
+    pub fn servers(&self) -> Vec<ServerConfig> {
+        if let Some(ref servers) = self.servers
+            && !servers.is_empty()
+        {
+            return servers.clone();
+        }
+        let mut listen = vec![format!("{}:{}", self.address, self.port)];
+        if let Some(ref tls) = self.tls {
+            listen.push(format!(
+                "{}:{} ssl",
+                self.address,
+                tls.port.unwrap_or(crate::tls::DEFAULT_PORT)
+            ));
+        }
+        vec![ServerConfig {
+            listen: Some(listen),
+            static_dir: self.static_dir.clone(),
+            proxy_pass: self.proxy_pass.clone(),
+            plugin_endpoints: self.plugin_endpoints.clone(),
+            routes: self.routes.clone(),
+            middleware: self.middleware.clone(),
+            ..ServerConfig::default()
+        }]
+    }
+
+    /// The config as YAML, leaving out unset options, for `-T`.
+    pub fn dump(&self) -> String {
+        let mut value = serde_yaml::to_value(self).unwrap_or(serde_yaml::Value::Null);
+        strip_nulls(&mut value);
+        serde_yaml::to_string(&value).unwrap_or_default()
+    }
+
+    /// The config the handlers of `server` see: this config with the site
+    /// settings replaced by the server's own.
+    pub fn for_server(&self, server: &ServerConfig) -> Config {
+        Config {
+            static_dir: server.static_dir.clone(),
+            proxy_pass: server.proxy_pass.clone(),
+            plugin_endpoints: server.plugin_endpoints.clone(),
+            routes: server.routes.clone(),
+            index: server.index.clone().or_else(|| self.index.clone()),
+            autoindex: server.autoindex.or(self.autoindex),
+            autoindex_format: server
+                .autoindex_format
+                .clone()
+                .or_else(|| self.autoindex_format.clone()),
+            servers: None,
+            middleware: server.middleware.clone(),
+            ..self.clone()
+        }
+    }
+}
+
+fn strip_nulls(value: &mut serde_yaml::Value) {
+    match value {
+        serde_yaml::Value::Mapping(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        serde_yaml::Value::Sequence(seq) => seq.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// One semantic problem, at a field path like `servers[0].routes[1].plugin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    /// The file is not valid YAML or does not match the schema.
+    Parse {
+        path: PathBuf,
+        /// Field path, when the error is inside a value.
+        field: Option<String>,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    /// An `include:` that cannot be followed.
+    Include { path: PathBuf, message: String },
+    /// The config parsed but failed `Config::validate`, or names an unset
+    /// environment variable.
+    Invalid {
+        path: PathBuf,
+        errors: Vec<FieldError>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            ConfigError::Parse {
+                path,
+                field,
+                line,
+                column,
+                message,
+            } => {
+                write!(f, "{}", path.display())?;
+                if let Some(line) = line {
+                    write!(f, ":{}", line)?;
+                }
+                if let Some(column) = column {
+                    write!(f, ":{}", column)?;
+                }
+                if let Some(field) = field {
+                    write!(f, ": {}", field)?;
+                }
+                write!(f, ": {}", message)
+            }
+            ConfigError::Include { path, message } => {
+                write!(f, "{}: {}", path.display(), message)
+            }
+            ConfigError::Invalid { path, errors } => {
+                write!(f, "{}: ", path.display())?;
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Read, parse and validate the config file.
+pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+    let path = path.as_ref();
+    let config = parse_config(path)?;
+    config.validate().map_err(|errors| ConfigError::Invalid {
+        path: path.to_path_buf(),
+        errors,
+    })?;
+    Ok(config)
+}
+
+/// Read and parse the config file without validating it.
+///
+/// The format follows the extension: `.toml`, `.json`, and YAML for anything
+/// else. `${VAR}` and `${VAR:-default}` are expanded in every string value
+/// (`$$` is a literal `$`), and number and bool fields also take their value
+/// as a string, so `port: ${PORT}` works. The top-level `include:` takes a
+/// glob pattern or a list of them, relative to the including file; matches
+/// are merged in name order: mappings key by key, lists appended, and the
+/// including file keeps its own scalar values.
+pub fn parse_config<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+    let path = path.as_ref();
+    let value = read_value(path, &mut Vec::new(), &mut ConfigSources::default())?;
+    serde_path_to_error::deserialize(value).map_err(|error| {
+        let field = error.path().to_string();
+        let field = (field != ".").then_some(field);
+        let message = error.into_inner().to_string();
+        let message = strip_field(&message, field.as_deref()).to_string();
+        // The merged tree has no positions. Errors in the main file show up
+        // again when it is parsed on its own, this time with a location;
+        // unless a variable made the difference.
+        if let Ok(content) = fs::read_to_string(path)
+            && let Err(located @ ConfigError::Parse { .. }) =
+                deserialize::<Config>(path, Format::from_path(path), &content)
+            && matches!(located, ConfigError::Parse { field: ref f, message: ref m, .. }
+                if *f == field && *m == message)
+        {
+            return located;
+        }
+        ConfigError::Parse {
+            path: path.to_path_buf(),
+            message,
+            field,
+            line: None,
+            column: None,
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+}
+
+/// The files a config is read from and the include patterns that may add
+/// more, with absolute paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigSources {
+    pub files: Vec<PathBuf>,
+    pub patterns: Vec<String>,
+}
+
+impl ConfigSources {
+    /// Whether `path` is one of the files or would be picked up by a pattern.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.files.iter().any(|f| f == path)
+            || self
+                .patterns
+                .iter()
+                .filter_map(|p| glob::Pattern::new(p).ok())
+                .any(|p| p.matches_path(path))
+    }
+
+    /// The directories holding the files and the patterns' matches, as far
+    /// as they are known without expanding wildcards.
+    pub fn dirs(&self) -> BTreeSet<PathBuf> {
+        let files = self.files.iter().map(PathBuf::as_path);
+        let patterns = self.patterns.iter().map(Path::new);
+        files
+            .chain(patterns)
+            .filter_map(Path::parent)
+            .filter(|dir| !dir.to_string_lossy().contains(['*', '?', '[']))
+            .map(Path::to_path_buf)
+            .collect()
+    }
+}
+
+/// The files `path` reads, following includes as far as they can be read,
+/// so a watcher also sees fixes to a config that currently fails to load.
+pub fn config_sources<P: AsRef<Path>>(path: P) -> ConfigSources {
+    let path = path.as_ref();
+    let mut sources = ConfigSources::default();
+    let _ = read_value(path, &mut Vec::new(), &mut sources);
+    if sources.files.is_empty()
+        && let Ok(path) = std::path::absolute(path)
+    {
+        sources.files.push(path);
+    }
+    sources
+}
+
+/// Parse one file into a tree with its variables expanded and includes
+/// merged. `stack` holds the files being read, to catch include cycles;
+/// every file and pattern met is added to `sources`.
+fn read_value(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    sources: &mut ConfigSources,
+) -> Result<serde_yaml::Value, ConfigError> {
+    let io_error = |error| ConfigError::Io {
+        path: path.to_path_buf(),
+        error,
+    };
+    let canonical = fs::canonicalize(path).map_err(io_error)?;
+    if !sources.files.contains(&canonical) {
+        sources.files.push(canonical.clone());
+    }
+    if stack.contains(&canonical) {
+        return Err(ConfigError::Include {
+            path: path.to_path_buf(),
+            message: "include cycle".to_string(),
+        });
+    }
+    let content = fs::read_to_string(path).map_err(io_error)?;
+    let mut value: serde_yaml::Value = deserialize(path, Format::from_path(path), &content)?;
+
+    let mut errors = Vec::new();
+    expand_value(&mut value, "", &mut errors);
+    if !errors.is_empty() {
+        return Err(ConfigError::Invalid {
+            path: path.to_path_buf(),
+            errors,
+        });
+    }
+
+    let include = value.as_mapping_mut().and_then(|map| map.remove("include"));
+    let patterns: Vec<String> = match include {
+        None => Vec::new(),
+        Some(serde_yaml::Value::String(pattern)) => vec![pattern],
+        Some(list) => serde_yaml::from_value(list).map_err(|_| ConfigError::Include {
+            path: path.to_path_buf(),
+            message: "include must be a pattern or a list of patterns".to_string(),
+        })?,
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    if let Some(canonical_dir) = canonical.parent() {
+        for pattern in &patterns {
+            sources.patterns.push(if Path::new(pattern).is_absolute() {
+                pattern.clone()
+            } else {
+                let escaped = glob::Pattern::escape(&canonical_dir.to_string_lossy());
+                format!("{}/{}", escaped, pattern)
+            });
+        }
+    }
+    stack.push(canonical);
+    for pattern in patterns {
+        for included in include_paths(path, dir, &pattern)? {
+            let included = read_value(&included, stack, sources)?;
+            merge(&mut value, included);
+        }
+    }
+    stack.pop();
+    Ok(value)
+}
+
+/// The files matching an `include:` pattern, in name order. A pattern
+/// without wildcards must name an existing file.
+fn include_paths(path: &Path, dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let include_error = |message: String| ConfigError::Include {
+        path: path.to_path_buf(),
+        message,
+    };
+    let full = if Path::new(pattern).is_absolute() {
+        pattern.to_string()
+    } else {
+        let dir = dir
+            .to_str()
+            .ok_or_else(|| include_error(format!("{} is not valid UTF-8", dir.display())))?;
+        if dir.is_empty() {
+            pattern.to_string()
+        } else {
+            format!("{}/{}", glob::Pattern::escape(dir), pattern)
+        }
+    };
+    let paths = glob::glob(&full)
+        .map_err(|e| include_error(format!("invalid include pattern {:?}: {}", pattern, e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| include_error(format!("include {:?}: {}", pattern, e)))?;
+    let has_wildcard = pattern.contains(['*', '?', '[']);
+    if paths.is_empty() && !has_wildcard {
+        return Err(include_error(format!(
+            "include {:?}: no such file",
+            pattern
+        )));
+    }
+    Ok(paths)
+}
+
+/// Merge an included tree into `base`.
+fn merge(base: &mut serde_yaml::Value, included: serde_yaml::Value) {
+    use serde_yaml::Value;
+    match (base, included) {
+        (Value::Mapping(base), Value::Mapping(included)) => {
+            for (key, value) in included {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(included)) => base.extend(included),
+        (base @ Value::Null, included) => *base = included,
+        // The including file's own value wins.
+        _ => {}
+    }
+}
+
+/// Expand variables in every string of `value`, collecting unset ones.
+fn expand_value(value: &mut serde_yaml::Value, field: &str, errors: &mut Vec<FieldError>) {
+    use serde_yaml::Value;
+    match value {
+        Value::String(s) => match expand_env(s, |name| std::env::var(name).ok()) {
+            Ok(expanded) => *s = expanded,
+            Err(message) => errors.push(FieldError {
+                field: field.to_string(),
+                message,
+            }),
+        },
+        Value::Sequence(list) => {
+            for (i, item) in list.iter_mut().enumerate() {
+                expand_value(item, &format!("{}[{}]", field, i), errors);
+            }
+        }
+        Value::Mapping(map) => {
+            for (key, item) in map.iter_mut() {
+                let key = match key {
+                    Value::String(k) => k.clone(),
+                    other => serde_yaml::to_string(other)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string(),
+                };
+                let field = if field.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", field, key)
+                };
+                expand_value(item, &field, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A number or bool field's value, also given as a string: what a `${VAR}`
+/// expands to.
+pub(crate) fn scalar<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + std::str::FromStr,
+{
+    deserializer.deserialize_any(ScalarVisitor(std::marker::PhantomData))
+}
+
+/// `scalar` for optional fields; they also need `#[serde(default)]`.
+pub(crate) fn opt_scalar<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + std::str::FromStr,
+{
+    struct Scalar<T>(T);
+    impl<'de, T: Deserialize<'de> + std::str::FromStr> Deserialize<'de> for Scalar<T> {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            scalar(deserializer).map(Scalar)
+        }
+    }
+    Ok(Option::<Scalar<T>>::deserialize(deserializer)?.map(|s| s.0))
+}
+
+/// Hands numbers and bools to `T` and parses strings, so errors read as
+/// they would without `scalar`.
+struct ScalarVisitor<T>(std::marker::PhantomData<T>);
+
+impl<'de, T: Deserialize<'de> + std::str::FromStr> serde::de::Visitor<'de> for ScalarVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(std::any::type_name::<T>())
+    }
+
+    fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<T, E> {
+        T::deserialize(v.into_deserializer())
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<T, E> {
+        T::deserialize(v.into_deserializer())
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<T, E> {
+        T::deserialize(v.into_deserializer())
+    }
+
+    fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<T, E> {
+        T::deserialize(v.into_deserializer())
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<T, E> {
+        match v.trim().parse() {
+            Ok(parsed) => Ok(parsed),
+            Err(_) => T::deserialize(v.into_deserializer()),
+        }
+    }
+}
+
+/// Replace `${NAME}` and `${NAME:-default}` in `s` using `lookup`. A default
+/// is used when the variable is unset or empty; `$$` gives a literal `$`.
+pub fn expand_env<F>(s: &str, lookup: F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated variable in {:?}", s))?;
+            let expr = &after[..end];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            if name.is_empty() {
+                return Err(format!("empty variable name in {:?}", s));
+            }
+            match (lookup(name).filter(|v| !v.is_empty()), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(format!("environment variable {} is not set", name));
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Deserialize `content` as `format`, reporting the field path and position
+/// of any error.
+fn deserialize<T: serde::de::DeserializeOwned>(
+    path: &Path,
+    format: Format,
+    content: &str,
+) -> Result<T, ConfigError> {
+    let parse_error = |field: String, position: Option<(usize, usize)>, message: String| {
+        let field = (field != ".").then_some(field);
+        ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: strip_field(&message, field.as_deref()).to_string(),
+            field,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+        }
+    };
+    match format {
+        Format::Yaml => {
+            let de = serde_yaml::Deserializer::from_str(content);
+            serde_path_to_error::deserialize(de).map_err(|error| {
+                let field = error.path().to_string();
+                let error = error.into_inner();
+                let position = error.location().map(|l| (l.line(), l.column()));
+                // serde_yaml repeats the location in its message.
+                let mut message = error.to_string();
+                if let Some((line, column)) = position {
+                    let at = format!(" at line {} column {}", line, column);
+                    message = message.replacen(&at, "", 1);
+                }
+                parse_error(field, position, message)
+            })
+        }
+        Format::Json => {
+            let mut de = serde_json::Deserializer::from_str(content);
+            serde_path_to_error::deserialize(&mut de).map_err(|error| {
+                let field = error.path().to_string();
+                let error = error.into_inner();
+                let position = (error.line() > 0).then(|| (error.line(), error.column()));
+                let message = error.to_string();
+                let message = match position {
+                    Some((line, column)) => {
+                        message.replacen(&format!(" at line {} column {}", line, column), "", 1)
+                    }
+                    None => message,
+                };
+                parse_error(field, position, message)
+            })
+        }
+        Format::Toml => {
+            let de = toml::Deserializer::new(content);
+            serde_path_to_error::deserialize(de).map_err(|error| {
+                let field = error.path().to_string();
+                let error = error.into_inner();
+                let position = error.span().map(|span| line_col(content, span.start));
+                parse_error(field, position, error.message().to_string())
+            })
+        }
+    }
+}
+
+/// 1-based line and column of a byte offset.
+fn line_col(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+/// Drop the `field: ` prefix some deserializers put in their messages.
+fn strip_field<'a>(message: &'a str, field: Option<&str>) -> &'a str {
+    field
+        .and_then(|field| message.strip_prefix(field))
+        .and_then(|rest| rest.strip_prefix(": "))
+        .unwrap_or(message)
+}
+
+impl Config {
+    /// Check what parsing cannot: addresses and ports, routes and server
+    /// names, and that referenced files and directories can be read.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut error = |field: String, message: String| errors.push(FieldError { field, message });
+        if self.address.parse::<IpAddr>().is_err() {
+            error(
+                "address".into(),
+                format!("{:?} is not an IP address", self.address),
+            );
+        }
+        if self.port == 0 {
+            error("port".into(), "must be between 1 and 65535".into());
+        }
+        if let Some(ref tls) = self.tls {
+            if tls.port == Some(0) {
+                error("tls.port".into(), "must be between 1 and 65535".into());
+            }
+            for (i, cert) in tls.certificates.iter().enumerate() {
+                if let Err(e) = check_file(&cert.cert) {
+                    error(format!("tls.certificates[{}].cert", i), e);
+                }
+                if let Err(e) = check_file(&cert.key) {
+                    error(format!("tls.certificates[{}].key", i), e);
+                }
+            }
+        }
+        if self.plugin_concurrency == Some(0) {
+            error("plugin_concurrency".into(), "must be at least 1".into());
+        }
+        let plugins_dir = self.plugins_dir.as_deref().unwrap_or("./plugins");
+        let top = ServerConfig {
+            static_dir: self.static_dir.clone(),
+            plugin_endpoints: self.plugin_endpoints.clone(),
+            routes: self.routes.clone(),
+            middleware: self.middleware.clone(),
+            ..ServerConfig::default()
+        };
+        check_site(&top, "", plugins_dir, &mut error);
+        for (i, server) in self.servers.iter().flatten().enumerate() {
+            let prefix = format!("servers[{}].", i);
+            check_site(server, &prefix, plugins_dir, &mut error);
+            for (j, listen) in server.listen.iter().flatten().enumerate() {
+                if let Err(e) = Listen::parse(listen, &self.address) {
+                    error(format!("{}listen[{}]", prefix, j), e.to_string());
+                }
+            }
+            if let Some(ref names) = server.server_name
+                && let Err(e) = VirtualHosts::new().add(names, (), false)
+            {
+                error(format!("{}server_name", prefix), e.to_string());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Check the site settings shared by the top level and `servers:` entries.
+fn check_site(
+    site: &ServerConfig,
+    prefix: &str,
+    plugins_dir: &str,
+    error: &mut impl FnMut(String, String),
+) {
+    if let Some(ref dir) = site.static_dir
+        && let Err(e) = check_dir(dir)
+    {
+        error(format!("{}static_dir", prefix), e);
+    }
+    let check_plugin = |filename: &str| check_file(Path::new(plugins_dir).join(filename));
+    for (endpoint, filename) in site.plugin_endpoints.iter().flatten() {
+        if let Err(e) = check_plugin(filename) {
+            error(format!("{}plugin_endpoints.{}", prefix, endpoint), e);
+        }
+    }
+    for (i, route) in site.routes.iter().flatten().enumerate() {
+        let field = format!("{}routes[{}]", prefix, i);
+        match router::check_route(route) {
+            Ok(RouteTarget::Plugin(filename)) => {
+                if let Err(e) = check_plugin(&filename) {
+                    error(format!("{}.plugin", field), e);
+                }
+            }
+            Ok(RouteTarget::Handler(name)) => {
+                if name != "static" && name != "proxy" {
+                    error(
+                        format!("{}.handler", field),
+                        format!("unknown handler {:?}", name),
+                    );
+                }
+            }
+            Err(e) => error(field.clone(), e.to_string()),
+        }
+        check_middleware(route.middleware.as_deref(), &format!("{}.", field), error);
+    }
+    check_middleware(site.middleware.as_deref(), prefix, error);
+}
+
+fn check_middleware(
+    middleware: Option<&[MiddlewareConfig]>,
+    prefix: &str,
+    error: &mut impl FnMut(String, String),
+) {
+    let registry = MiddlewareRegistry::default();
+    for (i, config) in middleware.into_iter().flatten().enumerate() {
+        if let Err(e) = registry.build(config) {
+            error(format!("{}middleware[{}]", prefix, i), e);
+        }
+    }
+}
+
+fn check_file<P: AsRef<Path>>(path: P) -> Result<(), String> {
+    let path = path.as_ref();
+    match fs::File::open(path).and_then(|f| f.metadata()) {
+        Ok(meta) if meta.is_file() => Ok(()),
+        Ok(_) => Err(format!("{} is not a file", path.display())),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn check_dir(path: &str) -> Result<(), String> {
+    match fs::read_dir(path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}: {}", path, e)),
+    }
 }
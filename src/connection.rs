@@ -0,0 +1,48 @@
+//! Per-connection protocol setup shared by the plaintext and TLS listeners.
+//!
+//! Connections are served by hyper-util's auto builder, which reads the
+//! first bytes to choose HTTP/1.1 or HTTP/2. Over TLS the client picks the
+//! protocol with ALPN; on plaintext listeners HTTP/2 needs prior knowledge
+//! (h2c), as `Upgrade: h2c` is not supported.
+use crate::config::Http2Config;
+use hyper_util::rt::TokioExecutor;
+use hyper_util::server::conn::auto;
+
+pub const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 200;
+
+/// Whether `config` allows HTTP/2.
+pub fn http2_enabled(config: Option<&Http2Config>) -> bool {
+    config.and_then(|c| c.enabled).unwrap_or(true)
+}
+
+/// ALPN protocols to offer, most preferred first.
+pub fn alpn_protocols(config: Option<&Http2Config>) -> Vec<Vec<u8>> {
+    if http2_enabled(config) {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    }
+}
+
+/// Connection builder configured from the `http2:` section.
+pub fn builder(config: Option<&Http2Config>) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if !http2_enabled(config) {
+        return builder.http1_only();
+    }
+    let config = config.cloned().unwrap_or_default();
+    let mut http2 = builder.http2();
+    http2
+        .max_concurrent_streams(
+            config
+                .max_concurrent_streams
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_STREAMS),
+        )
+        .initial_stream_window_size(config.initial_stream_window_size)
+        .initial_connection_window_size(config.initial_connection_window_size)
+        .max_frame_size(config.max_frame_size);
+    if let Some(size) = config.max_header_list_size {
+        http2.max_header_list_size(size);
+    }
+    builder
+}
@@ -1,17 +1,84 @@
 use crate::config::Config;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::body::{Body, Bytes};
+use hyper::http::Extensions;
 use hyper::{Request, Response};
 use std::convert::Infallible;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use std::sync::RwLock;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Response body shared by all handlers and middleware; boxed so handlers can
+/// stream (proxied responses, files) as well as return buffered bodies.
+pub type ResponseBody = BoxBody<Bytes, BoxError>;
+
+/// Request body handed to handlers and middleware; boxed so middleware can
+/// wrap or replace it and requests can be built without a connection.
+pub type RequestBody = BoxBody<Bytes, BoxError>;
+
+pub type HandlerFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Response<ResponseBody>, Infallible>> + Send + 'a>>;
+
+/// Wrap a buffered chunk as a `ResponseBody`.
+pub fn full<T: Into<Bytes>>(chunk: T) -> ResponseBody {
+    Full::new(chunk.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+/// Box any body, e.g. hyper's `Incoming`, as a `RequestBody`.
+pub fn request_body<B>(body: B) -> RequestBody
+where
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    body.map_err(Into::into).boxed()
+}
+
+/// Typed per-request values passed down the chain, such as the user an auth
+/// middleware found. Clones share one map, so what a handler inserts is
+/// visible to the `after` hooks of the middleware around it.
+#[derive(Clone, Default)]
+pub struct Context(Arc<Mutex<Extensions>>);
+
+impl Context {
+    /// The context stored in `req`, added to it if it has none yet.
+    pub fn of<B>(req: &mut Request<B>) -> Context {
+        req.extensions_mut()
+            .get_or_insert_default::<Context>()
+            .clone()
+    }
+
+    pub fn insert<T: Clone + Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        self.0.lock().unwrap().insert(value)
+    }
+
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.0.lock().unwrap().get::<T>().cloned()
+    }
+
+    pub fn remove<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.0.lock().unwrap().remove::<T>()
+    }
+}
+
+/// Per-connection details the accept loop stores in the request extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionInfo {
+    pub remote_addr: SocketAddr,
+    /// The connection came in over TLS.
+    pub tls: bool,
+}
+
 pub trait Handler: Send + Sync {
     fn handle<'a>(
         &'a self,
-        req: Request<Incoming>,
+        req: Request<RequestBody>,
         config: Arc<RwLock<Config>>,
-    ) -> Pin<Box<dyn Future<Output = Result<Response<Full<Bytes>>, Infallible>> + Send + 'a>>;
+    ) -> HandlerFuture<'a>;
 }
@@ -0,0 +1,62 @@
+use crate::handler_trait::{Context, ResponseBody};
+use crate::middleware_trait::{Middleware, MiddlewareFuture, RequestHead};
+use hyper::Response;
+use hyper::header::{HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// `headers` options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersOptions {
+    /// Response headers to set, replacing what the handler sent.
+    pub set: Option<BTreeMap<String, String>>,
+    /// Response headers to remove.
+    pub remove: Option<Vec<String>>,
+}
+
+/// Sets and removes response headers, like nginx `add_header`.
+pub struct HeadersMiddleware {
+    set: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl HeadersMiddleware {
+    pub fn new(options: HeadersOptions) -> Result<Self, String> {
+        let name = |name: &str| {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name {:?}", name))
+        };
+        let mut set = Vec::new();
+        for (key, value) in options.set.unwrap_or_default() {
+            let value = HeaderValue::from_str(&value)
+                .map_err(|_| format!("invalid value for header {}", key))?;
+            set.push((name(&key)?, value));
+        }
+        let remove = options
+            .remove
+            .unwrap_or_default()
+            .iter()
+            .map(|key| name(key))
+            .collect::<Result<_, _>>()?;
+        Ok(HeadersMiddleware { set, remove })
+    }
+}
+
+impl Middleware for HeadersMiddleware {
+    fn after<'a>(
+        &'a self,
+        _head: &'a RequestHead,
+        _ctx: &'a Context,
+        mut resp: Response<ResponseBody>,
+    ) -> MiddlewareFuture<'a, Response<ResponseBody>> {
+        let headers = resp.headers_mut();
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+        Box::pin(async move { resp })
+    }
+}
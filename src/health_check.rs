@@ -0,0 +1,120 @@
+//! Active health checks for upstream backends.
+//!
+//! A background task probes the backends of every upstream group that has a
+//! `health_check:` section, either with an HTTP `GET` or a TCP connect. The
+//! verdict feeds `Backend::is_available`, so unhealthy backends are skipped
+//! by every load-balancing strategy. State changes go to the error log.
+use crate::config::{HealthCheckConfig, HealthCheckKind};
+use crate::proxy::ProxyHandler;
+use crate::upstream::{Backend, UpstreamPool};
+use http_body_util::Empty;
+use hyper::Request;
+use hyper::body::Bytes;
+use hyper::header::HOST;
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+
+pub const DEFAULT_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 2;
+pub const DEFAULT_RISE: u32 = 2;
+pub const DEFAULT_FALL: u32 = 3;
+
+/// flexi_logger target that writes to both the error log and the main log.
+pub const ERROR_LOG_TARGET: &str = "{_Default,error}";
+
+/// Start the checker. Every second it looks at the proxy's current upstream
+/// groups, so groups added or changed by a hot-reload are picked up.
+pub fn spawn(proxy: Arc<ProxyHandler>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut next_due: HashMap<String, Instant> = HashMap::new();
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            let now = Instant::now();
+            let upstreams = proxy.upstreams();
+            for pool in upstreams.pools() {
+                let Some(ref check) = pool.health_check else {
+                    continue;
+                };
+                if next_due.get(&pool.name).is_some_and(|due| now < *due) {
+                    continue;
+                }
+                let interval = check.interval.unwrap_or(DEFAULT_INTERVAL_SECS).max(1);
+                next_due.insert(pool.name.clone(), now + Duration::from_secs(interval));
+                tokio::spawn(check_pool(pool.clone()));
+            }
+            next_due.retain(|name, _| upstreams.get(name).is_some());
+        }
+    })
+}
+
+/// Probe every backend of `pool` once and apply the results.
+pub async fn check_pool(pool: Arc<UpstreamPool>) {
+    let Some(check) = pool.health_check.clone() else {
+        return;
+    };
+    let mut probes = JoinSet::new();
+    for backend in pool.backends() {
+        let backend = backend.clone();
+        let check = check.clone();
+        probes.spawn(async move {
+            let ok = probe(&backend.address, &check).await;
+            (backend, ok)
+        });
+    }
+    let rise = check.rise.unwrap_or(DEFAULT_RISE);
+    let fall = check.fall.unwrap_or(DEFAULT_FALL);
+    while let Some(result) = probes.join_next().await {
+        let Ok((backend, ok)) = result else {
+            continue;
+        };
+        report(&pool.name, &backend, backend.record_check(ok, rise, fall));
+    }
+}
+
+fn report(pool: &str, backend: &Backend, change: Option<bool>) {
+    match change {
+        Some(true) => log::warn!(
+            target: ERROR_LOG_TARGET,
+            "[health] upstream {} backend {} is healthy again",
+            pool,
+            backend.address
+        ),
+        Some(false) => log::error!(
+            target: ERROR_LOG_TARGET,
+            "[health] upstream {} backend {} is unhealthy",
+            pool,
+            backend.address
+        ),
+        None => {}
+    }
+}
+
+/// Run one probe against `address`; any error or timeout counts as a failure.
+pub async fn probe(address: &str, check: &HealthCheckConfig) -> bool {
+    let timeout = Duration::from_secs(check.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    let attempt = async {
+        let stream = TcpStream::connect(address).await.ok()?;
+        if check.kind == HealthCheckKind::Tcp {
+            return Some(true);
+        }
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .ok()?;
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+        let req = Request::get(check.path.as_deref().unwrap_or("/"))
+            .header(HOST, address)
+            .body(Empty::<Bytes>::new())
+            .ok()?;
+        let resp = sender.send_request(req).await.ok()?;
+        Some(resp.status().is_success() || resp.status().is_redirection())
+    };
+    matches!(tokio::time::timeout(timeout, attempt).await, Ok(Some(true)))
+}
@@ -1,10 +1,29 @@
 pub mod modules {
     pub mod dynamic_loader;
+    pub mod envelope;
 }
+pub mod auth_middleware;
+pub mod cli;
+pub mod compression_middleware;
 pub mod config;
+pub mod connection;
 pub mod handler_trait;
 pub mod handlers;
+pub mod headers_middleware;
+pub mod health_check;
 pub mod logging_middleware;
 pub mod middleware_chain;
+pub mod middleware_registry;
 pub mod middleware_trait;
+pub mod plugin_handler;
+pub mod proxy;
+pub mod rate_limit_middleware;
+pub mod reload;
+pub mod router;
+pub mod shutdown;
 pub mod simple_handler;
+pub mod static_files;
+pub mod tls;
+pub mod upgrade;
+pub mod upstream;
+pub mod vhost;
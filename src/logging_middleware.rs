@@ -1,16 +1,15 @@
-use crate::config::Config;
-use crate::handler_trait::Handler;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::{Request, Response};
-use std::convert::Infallible;
-use std::future::Future;
-use std::pin::Pin;
-use std::sync::Arc;
-use std::sync::RwLock;
+use crate::handler_trait::{Context, ResponseBody};
+use crate::middleware_trait::{MiddlewareFuture, RequestHead};
+use hyper::Response;
 
 pub struct LoggingMiddleware;
 
+impl Default for LoggingMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LoggingMiddleware {
     pub fn new() -> Self {
         LoggingMiddleware
@@ -18,20 +17,18 @@ impl LoggingMiddleware {
 }
 
 impl super::middleware_trait::Middleware for LoggingMiddleware {
-    fn handle<'a>(
+    fn after<'a>(
         &'a self,
-        req: Request<Incoming>,
-        config: Arc<RwLock<Config>>,
-        next: Arc<dyn Handler + Send + Sync>,
-    ) -> Pin<Box<dyn Future<Output = Result<Response<Full<Bytes>>, Infallible>> + Send + 'a>> {
-        let next = next.clone();
-        let method = req.method().clone();
-        let uri = req.uri().clone();
-        Box::pin(async move {
-            let response = next.handle(req, config).await;
-            let status = response.as_ref().map(|r| r.status().as_u16()).unwrap_or(0);
-            log::info!("[access] {} {} -> {}", method, uri, status);
-            response
-        })
+        head: &'a RequestHead,
+        _ctx: &'a Context,
+        resp: Response<ResponseBody>,
+    ) -> MiddlewareFuture<'a, Response<ResponseBody>> {
+        log::info!(
+            "[access] {} {} -> {}",
+            head.method,
+            head.uri,
+            resp.status().as_u16()
+        );
+        Box::pin(async move { resp })
     }
 }
@@ -1,58 +1,640 @@
-mod config;
-mod handler_trait;
-mod handlers;
-mod logging_middleware;
-mod middleware_chain;
-mod middleware_trait;
-mod simple_handler;
-
-use config::load_config;
-use flexi_logger::{Cleanup, Criterion, FileSpec, Logger, Naming, WriteMode};
-use handler_trait::Handler;
-use hyper::server::conn::http1;
+use arc_swap::ArcSwap;
+use flexi_logger::writers::FileLogWriter;
+use flexi_logger::{Cleanup, Criterion, DeferredNow, FileSpec, Logger, Naming, WriteMode};
 use hyper::service::service_fn;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::{self, GracefulShutdown};
 use log::info;
-use logging_middleware::LoggingMiddleware;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use simple_handler::SimpleHandler;
-use std::sync::RwLock;
-mod modules {
-    pub mod dynamic_loader;
-}
-use modules::dynamic_loader::{
-    CAbiModule, DynamicModule, PluginLifecycle, RustDylibModule, ScriptingModule, WasmModule,
+use notify::RecommendedWatcher;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::TlsAcceptor;
+use wigspace_rust::cli::{self, Action, Options};
+use wigspace_rust::config::{
+    Config, ConfigError, TlsConfig, config_sources, load_config, parse_config,
+};
+use wigspace_rust::connection;
+use wigspace_rust::handler_trait::{
+    ConnectionInfo, Handler, HandlerFuture, RequestBody, ResponseBody, full, request_body,
+};
+use wigspace_rust::health_check::{self, ERROR_LOG_TARGET};
+use wigspace_rust::logging_middleware::LoggingMiddleware;
+use wigspace_rust::middleware_registry::{self, MiddlewareRegistry};
+use wigspace_rust::middleware_trait::Middleware;
+use wigspace_rust::modules::dynamic_loader::{
+    CAbiModule, PluginLifecycle, RustDylibModule, ScriptingModule, WasmModule,
+};
+use wigspace_rust::plugin_handler::{
+    self, DEFAULT_CONCURRENCY, LifecycleAction, LifecycleHandler, Plugin, PluginHandler,
+    PluginLoader, SharedPlugin, Unloader,
 };
+use wigspace_rust::proxy::ProxyHandler;
+use wigspace_rust::reload::{ConfigWatcher, DEBOUNCE, PluginCache, ReloadStatus, diff_listens};
+use wigspace_rust::router::{RouteLookup, RouteTarget, Router};
+use wigspace_rust::shutdown::{self, PidFile, Signal, Signals};
+use wigspace_rust::simple_handler::SimpleHandler;
+use wigspace_rust::static_files::DirectoryOptions;
+use wigspace_rust::tls::{self, CertResolver};
+use wigspace_rust::upgrade;
+use wigspace_rust::vhost::{Listen, VirtualHosts, request_host};
 
-#[derive(Clone)]
-enum PluginInstance {
-    CAbi(Arc<CAbiModule>),
-    Lua(Arc<ScriptingModule>),
-    Wasm(Arc<WasmModule>),
+/// Static files with a route's directory options.
+struct StaticEndpoint(DirectoryOptions);
+
+impl Handler for StaticEndpoint {
+    fn handle<'a>(
+        &'a self,
+        mut req: hyper::Request<RequestBody>,
+        config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        req.extensions_mut().insert(self.0.clone());
+        SimpleHandler.handle(req, config)
+    }
+}
+
+/// `/reload-status`: the outcome of the last config reloads, as JSON.
+struct ReloadStatusEndpoint(Arc<Mutex<ReloadStatus>>);
+
+impl Handler for ReloadStatusEndpoint {
+    fn handle<'a>(
+        &'a self,
+        _req: hyper::Request<RequestBody>,
+        _config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        let status = self.0.lock().unwrap().clone();
+        let resp = hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(full(serde_json::to_string(&status).unwrap()))
+            .unwrap();
+        Box::pin(async move { Ok(resp) })
+    }
+}
+
+/// The server's own endpoints: reload status and the Rust dylib plugin's
+/// lifecycle hooks. A reload loads the plugin if it is not loaded yet.
+fn control_endpoints(
+    rust_plugin: &Arc<Mutex<Option<RustDylibModule>>>,
+    rust_path: PathBuf,
+    reload_status: Arc<Mutex<ReloadStatus>>,
+) -> ControlEndpoints {
+    let lifecycle = |action| LifecycleHandler::new("rust_plugin", rust_plugin.clone(), action);
+    let loader: PluginLoader<RustDylibModule> =
+        Arc::new(move || unsafe { RustDylibModule::load(&rust_path) }.map_err(|e| e.to_string()));
+    vec![
+        (
+            "/reload-status",
+            Arc::new(ReloadStatusEndpoint(reload_status)),
+        ),
+        (
+            "/reload-rust-plugin",
+            Arc::new(lifecycle(LifecycleAction::Reload).with_loader(loader)),
+        ),
+        (
+            "/init-rust-plugin",
+            Arc::new(lifecycle(LifecycleAction::Init)),
+        ),
+        (
+            "/shutdown-rust-plugin",
+            Arc::new(lifecycle(LifecycleAction::Shutdown)),
+        ),
+    ]
+}
+
+/// Server control paths and their handlers, before middleware.
+type ControlEndpoints = Vec<(&'static str, Arc<dyn Handler>)>;
+
+/// A loaded plugin's handler, shared by every route using the plugin.
+type LoadedPlugin = Arc<PluginHandler<dyn Plugin>>;
+
+/// Load a plugin and run its init hook; its handler runs `concurrency`
+/// calls at once and hands the plugin to `unloader` once it is dropped.
+fn load_plugin(path: &Path, concurrency: usize, unloader: &Unloader) -> Option<LoadedPlugin> {
+    let plugin = open_plugin(path)?;
+    info!("{}", plugin.write().unwrap().init());
+    Some(Arc::new(
+        PluginHandler::new(plugin.clone())
+            .with_concurrency(concurrency)
+            .on_release(unloader.release(plugin)),
+    ))
+}
+
+/// Load a plugin without initialising it; failures go to stderr.
+fn open_plugin(path: &Path) -> Option<SharedPlugin> {
+    let Some(kind) = plugin_kind(path) else {
+        eprintln!("Unknown plugin extension: {}", path.display());
+        return None;
+    };
+    let loaded: Result<SharedPlugin, String> = match kind {
+        "C ABI" => unsafe { CAbiModule::load(path) }
+            .map(|m| Arc::new(RwLock::new(m)) as SharedPlugin)
+            .map_err(|e| e.to_string()),
+        "Lua" => ScriptingModule::load(path)
+            .map(|m| Arc::new(RwLock::new(m)) as SharedPlugin)
+            .map_err(|e| e.to_string()),
+        _ => WasmModule::load(path)
+            .map(|m| Arc::new(RwLock::new(m)) as SharedPlugin)
+            .map_err(|e| e.to_string()),
+    };
+    loaded
+        .map_err(|e| eprintln!("Failed to load {} plugin {}: {}", kind, path.display(), e))
+        .ok()
+}
+
+/// The kind of plugin a file holds, by its extension.
+fn plugin_kind(path: &Path) -> Option<&'static str> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("so") => Some("C ABI"),
+        Some("lua") => Some("Lua"),
+        Some("wasm") => Some("WASM"),
+        _ => None,
+    }
+}
+
+/// Build the routing table from `routes` and `plugin_endpoints`, taking the
+/// plugins it refers to from `plugins`. Each route gets the server's
+/// `middleware` and then its own around its handler.
+fn build_router(
+    config: &Config,
+    middleware: &[Arc<dyn Middleware>],
+    proxy: &Arc<ProxyHandler>,
+    plugins: &mut PluginCache<LoadedPlugin>,
+    unloader: &Unloader,
+) -> Router<Arc<dyn Handler>> {
+    let plugins_dir = config
+        .plugins_dir
+        .clone()
+        .unwrap_or_else(|| "./plugins".to_string());
+    let concurrency = config.plugin_concurrency.unwrap_or(DEFAULT_CONCURRENCY);
+    let registry = MiddlewareRegistry::default();
+    let mut loaded_plugins_log = Vec::new();
+    let router = Router::from_config(config, |target, route| {
+        let handler: Arc<dyn Handler> = match target {
+            RouteTarget::Plugin(filename) => {
+                let path = Path::new(&plugins_dir).join(filename);
+                let plugin =
+                    plugins.get_or_load(&path, |path| load_plugin(path, concurrency, unloader))?;
+                let kind = plugin_kind(&path).unwrap_or("");
+                loaded_plugins_log.push(format!("{} [{}]", filename, kind));
+                plugin
+            }
+            RouteTarget::Handler(name) if name == "static" => {
+                Arc::new(StaticEndpoint(DirectoryOptions::from_config(config, route)))
+            }
+            RouteTarget::Handler(name) if name == "proxy" => proxy.clone(),
+            RouteTarget::Handler(name) => {
+                eprintln!("Unknown handler in route: {}", name);
+                return None;
+            }
+        };
+        let mut route_middleware = middleware.to_vec();
+        if let Some(configs) = route.and_then(|r| r.middleware.as_deref()) {
+            route_middleware.extend(registry.build_all(configs));
+        }
+        Some(middleware_registry::chain(&route_middleware, handler))
+    });
+    if !loaded_plugins_log.is_empty() {
+        info!("Plugins: {:?}", loaded_plugins_log);
+    } else {
+        info!("No plugins loaded from mapping");
+    }
+    info!("Router built with {} routes", router.len());
+    router
+}
+
+/// One virtual server: its view of the config, routes and handler chains.
+struct Site {
+    config: Arc<RwLock<Config>>,
+    router: Router<Arc<dyn Handler>>,
+    /// Requests no route matched: static files and the fallback.
+    chain: Arc<dyn Handler>,
+    /// Requests no route matched when `proxy_pass` is set.
+    proxy_chain: Arc<dyn Handler>,
+    /// Server control paths, answered when no route matched.
+    control: Router<Arc<dyn Handler>>,
+}
+
+/// Virtual hosts of every listen address.
+type Sites = HashMap<SocketAddr, VirtualHosts<Arc<Site>>>;
+
+/// Build every server of `config` and the listen addresses they need.
+fn build_sites(
+    config: &Config,
+    proxy: &Arc<ProxyHandler>,
+    plugins: &mut PluginCache<LoadedPlugin>,
+    unloader: &Unloader,
+    control: &ControlEndpoints,
+) -> (Sites, Vec<Listen>) {
+    let mut sites = Sites::new();
+    let mut listens: Vec<Listen> = Vec::new();
+    let registry = MiddlewareRegistry::default();
+    for server in config.servers() {
+        let site_config = config.for_server(&server);
+        // Built once per server, so a rate limit covers all its routes.
+        let middleware = match server.middleware {
+            Some(ref configs) => registry.build_all(configs),
+            None => vec![Arc::new(LoggingMiddleware::new()) as Arc<dyn Middleware>],
+        };
+        let mut control_router = Router::new();
+        for (path, handler) in control {
+            let handler = middleware_registry::chain(&middleware, handler.clone());
+            control_router.add(path, None, handler).unwrap();
+        }
+        let site = Arc::new(Site {
+            router: build_router(&site_config, &middleware, proxy, plugins, unloader),
+            control: control_router,
+            chain: middleware_registry::chain(&middleware, Arc::new(SimpleHandler)),
+            proxy_chain: middleware_registry::chain(&middleware, proxy.clone()),
+            config: Arc::new(RwLock::new(site_config)),
+        });
+        let names = server.server_name.clone().unwrap_or_default();
+        let is_default = server.default_server.unwrap_or(false);
+        let listen_entries = server
+            .listen
+            .clone()
+            .unwrap_or_else(|| vec![format!("{}:{}", config.address, config.port)]);
+        for entry in &listen_entries {
+            let listen = match Listen::parse(entry, &config.address) {
+                Ok(listen) => listen,
+                Err(e) => {
+                    log::error!("[vhost] {}", e);
+                    continue;
+                }
+            };
+            match listens.iter().find(|l| l.addr == listen.addr) {
+                Some(l) if l.tls != listen.tls => {
+                    log::error!(
+                        "[vhost] {} is both plain and ssl; using the first",
+                        listen.addr
+                    )
+                }
+                Some(_) => {}
+                None => listens.push(listen),
+            }
+            let hosts = sites.entry(listen.addr).or_default();
+            if let Err(e) = hosts.add(&names, site.clone(), is_default) {
+                log::error!("[vhost] server {:?} on {}: {}", names, listen.addr, e);
+            }
+        }
+    }
+    (sites, listens)
+}
+
+/// Applies config reloads: rebuilds the routing table, swaps it in, and
+/// brings plugins, listeners and certificates in line with the new config.
+struct Reloader {
+    path: PathBuf,
+    config: Arc<RwLock<Config>>,
+    sites: Arc<ArcSwap<Sites>>,
+    proxy: Arc<ProxyHandler>,
+    plugins: PluginCache<LoadedPlugin>,
+    /// Shuts down the plugins a reload leaves unused.
+    unloader: Unloader,
+    control: ControlEndpoints,
+    tls: Option<Arc<CertResolver>>,
+    /// Keeps the certificate watcher of `tls` alive.
+    tls_watcher: Option<RecommendedWatcher>,
+    watcher: ConfigWatcher,
+    status: Arc<Mutex<ReloadStatus>>,
+}
+
+impl Reloader {
+    /// Load the config file again and apply it, recording the outcome in
+    /// `status`. A config that fails to load leaves everything as is.
+    async fn reload(&mut self, listeners: &mut Listeners) {
+        let result = self.apply(listeners).await;
+        if let Err(ref e) = result {
+            log::error!(target: ERROR_LOG_TARGET, "[hot-reload] {}", e);
+        }
+        // Includes may have come or gone.
+        let sources = tokio::task::block_in_place(|| config_sources(&self.path));
+        self.watcher.update(sources);
+        self.status.lock().unwrap().record(result);
+    }
+
+    async fn apply(&mut self, listeners: &mut Listeners) -> Result<(), String> {
+        let built = tokio::task::block_in_place(|| {
+            let config = load_config(&self.path)?;
+            let (sites, listens) = build_sites(
+                &config,
+                &self.proxy,
+                &mut self.plugins,
+                &self.unloader,
+                &self.control,
+            );
+            Ok::<_, ConfigError>((config, sites, listens))
+        });
+        let (new_config, new_sites, listens) = match built {
+            Ok(built) => built,
+            Err(e) => return Err(format!("{}; keeping the last good config", e)),
+        };
+        self.proxy.update_upstreams(&new_config);
+        self.update_tls(new_config.tls.as_ref());
+        *self.config.write().unwrap() = new_config;
+        // Requests already running keep the table they loaded.
+        self.sites.store(Arc::new(new_sites));
+
+        let (removed, added) = diff_listens(&listeners.listens(), &listens);
+        for listen in &removed {
+            listeners.close(listen.addr).await;
+        }
+        let mut bind_errors = Vec::new();
+        for listen in &added {
+            if let Err(e) = listeners
+                .bind(*listen, self.tls.as_ref(), &mut HashMap::new())
+                .await
+            {
+                bind_errors.push(format!("{}: {}", listen.addr, e));
+            }
+        }
+        // Shut down once the requests still using them are done.
+        let unloaded = self.plugins.sweep().len();
+        info!(
+            "[hot-reload] {} reloaded: {} listener(s) closed, {} bound, {} plugin(s) unloaded",
+            self.path.display(),
+            removed.len(),
+            added.len() - bind_errors.len(),
+            unloaded
+        );
+        if bind_errors.is_empty() {
+            Ok(())
+        } else {
+            Err(bind_errors.join("; "))
+        }
+    }
+
+    /// Switch to the certificates of `tls`, creating the resolver if the
+    /// server had none. Removing `tls` keeps the last certificates around.
+    fn update_tls(&mut self, tls: Option<&TlsConfig>) {
+        let Some(tls) = tls else {
+            return;
+        };
+        match &self.tls {
+            Some(resolver) if resolver.config() != *tls => match resolver.reload_config(tls) {
+                // The certificates may be in other directories now.
+                Ok(()) => {
+                    self.tls_watcher = tls::watch(resolver)
+                        .map_err(|e| log::error!("[tls] watching certificates: {}", e))
+                        .ok();
+                }
+                Err(e) => log::error!("[hot-reload] {}", e),
+            },
+            Some(_) => {}
+            None => match CertResolver::from_config(tls) {
+                Ok(resolver) => {
+                    let resolver = Arc::new(resolver);
+                    self.tls_watcher = tls::watch(&resolver)
+                        .map_err(|e| log::error!("[tls] watching certificates: {}", e))
+                        .ok();
+                    self.tls = Some(resolver);
+                }
+                Err(e) => log::error!("[hot-reload] {}", e),
+            },
+        }
+    }
+}
+
+/// A bound listen address and its accept loop.
+struct Running {
+    listen: Listen,
+    fd: RawFd,
+    stop: watch::Sender<bool>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+/// The listeners being served, bound and closed as the config changes.
+struct Listeners {
+    state: AppState,
+    /// Tracks open connections so shutdown can drain them.
+    graceful: Arc<GracefulShutdown>,
+    running: Vec<Running>,
+}
+
+impl Listeners {
+    /// Start accepting on `listen`, adopting its socket from `inherited` if
+    /// there is one.
+    async fn bind(
+        &mut self,
+        listen: Listen,
+        tls: Option<&Arc<CertResolver>>,
+        inherited: &mut HashMap<SocketAddr, std::net::TcpListener>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let acceptor = if listen.tls {
+            let Some(resolver) = tls else {
+                return Err(format!("listen {} ssl needs tls certificates", listen.addr).into());
+            };
+            let alpn = connection::alpn_protocols(self.state.config.read().unwrap().http2.as_ref());
+            Some(TlsAcceptor::from(tls::server_config(
+                resolver.clone(),
+                alpn,
+            )?))
+        } else {
+            None
+        };
+        let listener = match inherited.remove(&listen.addr) {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(listen.addr).await?,
+        };
+        let fd = listener.as_raw_fd();
+        let scheme = if listen.tls { "https" } else { "http" };
+        info!("Server running on {}://{}", scheme, listen.addr);
+        let (stop, stop_rx) = watch::channel(false);
+        let task = tokio::spawn(accept_loop(
+            listener,
+            listen.addr,
+            acceptor,
+            self.state.clone(),
+            self.graceful.clone(),
+            stop_rx,
+        ));
+        self.running.push(Running {
+            listen,
+            fd,
+            stop,
+            task,
+        });
+        Ok(())
+    }
+
+    /// Stop accepting on `addr` and close the socket. Connections it
+    /// accepted carry on.
+    async fn close(&mut self, addr: SocketAddr) {
+        let Some(i) = self.running.iter().position(|r| r.listen.addr == addr) else {
+            return;
+        };
+        let running = self.running.remove(i);
+        let _ = running.stop.send(true);
+        let _ = running.task.await;
+        info!("[listen] closed {}", addr);
+    }
+
+    fn listens(&self) -> Vec<Listen> {
+        self.running.iter().map(|r| r.listen).collect()
+    }
+
+    /// The sockets to hand to a new binary.
+    fn fds(&self) -> Vec<(SocketAddr, RawFd)> {
+        self.running.iter().map(|r| (r.listen.addr, r.fd)).collect()
+    }
+
+    /// Close every listener and return the connection tracker.
+    async fn close_all(self) -> GracefulShutdown {
+        for running in &self.running {
+            let _ = running.stop.send(true);
+        }
+        for running in self.running {
+            let _ = running.task.await;
+        }
+        // Connections only hold watchers; once the accept loops have exited
+        // the `GracefulShutdown` is ours again.
+        let Ok(graceful) = Arc::try_unwrap(self.graceful) else {
+            unreachable!("accept loops have exited");
+        };
+        graceful
+    }
+}
+
+/// `-t` and `-T`: validate the config and load every plugin it names, like
+/// `nginx -t`. Returns the exit code.
+fn test_config(path: &Path, dump: bool) -> i32 {
+    let config = match load_config(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("wigspace: {}", e);
+            eprintln!(
+                "wigspace: configuration file {} test failed",
+                path.display()
+            );
+            return 1;
+        }
+    };
+    eprintln!(
+        "wigspace: the configuration file {} syntax is ok",
+        path.display()
+    );
+    let plugins_dir = config.plugins_dir.as_deref().unwrap_or("./plugins");
+    let mut plugins = BTreeSet::new();
+    for server in config.servers() {
+        plugins.extend(
+            server
+                .plugin_endpoints
+                .iter()
+                .flatten()
+                .map(|(_, f)| f.clone()),
+        );
+        plugins.extend(
+            server
+                .routes
+                .iter()
+                .flatten()
+                .filter_map(|r| r.plugin.clone()),
+        );
+    }
+    let failed = plugins
+        .iter()
+        .filter(|filename| open_plugin(&Path::new(plugins_dir).join(filename)).is_none())
+        .count();
+    if failed > 0 {
+        eprintln!("wigspace: {} plugin(s) failed to load", failed);
+        eprintln!(
+            "wigspace: configuration file {} test failed",
+            path.display()
+        );
+        return 1;
+    }
+    eprintln!(
+        "wigspace: configuration file {} test is successful",
+        path.display()
+    );
+    if dump {
+        println!("# configuration file {}:", path.display());
+        print!("{}", config.dump());
+    }
+    0
+}
+
+/// Format mirip Nginx: [time] LEVEL target: message
+fn log_format(
+    w: &mut dyn std::io::Write,
+    now: &mut DeferredNow,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    // Writer-routing targets like "{_Default,error}" are not useful in the log
+    let target = if record.target().starts_with('{') {
+        record.module_path().unwrap_or_default()
+    } else {
+        record.target()
+    };
+    writeln!(
+        w,
+        "{} [{}] {}: {}",
+        now.now().format("%d/%b/%Y:%H:%M:%S %z"),
+        record.level(),
+        target,
+        record.args()
+    )
 }
-// Load WASM plugin at startup
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::Mutex;
-use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("wigspace: {}\n{}", e, cli::USAGE);
+            std::process::exit(1);
+        }
+    };
+    match options.action {
+        Action::Run => {}
+        Action::Help => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Action::Test | Action::Dump => {
+            std::process::exit(test_config(&options.config, options.action == Action::Dump))
+        }
+        Action::Signal(command) => {
+            // A config that fails validation still names the pid file.
+            let pid_path = parse_config(&options.config)
+                .ok()
+                .and_then(|config| config.pid_file)
+                .unwrap_or_else(|| shutdown::DEFAULT_PID_FILE.to_string());
+            if let Err(e) = cli::send_signal(Path::new(&pid_path), command) {
+                eprintln!("wigspace: {} via {}: {}", command, pid_path, e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    }
+
     // --- LOGGING INIT FIRST ---
     let today = chrono::Local::now().format("%Y-%m-%d").to_string();
     // Ensure log directory exists
     std::fs::create_dir_all("log").expect("Failed to create log directory");
-    let temp_config = load_config("config.yaml");
+    let temp_config = match load_config(&options.config) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("wigspace: {}", e);
+            std::process::exit(1);
+        }
+    };
     let access_log_spec = FileSpec::default()
         .directory("log")
         .basename(format!("access_r{}", today));
-    let _error_log_path = temp_config
+    let error_log_path = temp_config
         .error_log
         .clone()
         .unwrap_or_else(|| format!("log/error_r{}.log", today));
 
-    Logger::try_with_str("info")?
+    let logger = Logger::try_with_str("info")?
         .log_to_file(access_log_spec)
         .write_mode(WriteMode::BufferAndFlush)
         .rotate(
@@ -60,21 +642,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             Naming::Numbers,
             Cleanup::KeepLogFiles(30),
         )
-        .format(|w, now, record| {
-            // Format mirip Nginx: [time] LEVEL target: message
-            write!(
-                w,
-                "{} [{}] {}: {}\n",
-                now.now().format("%d/%b/%Y:%H:%M:%S %z"),
-                record.level(),
-                record.target(),
-                record.args()
-            )
-        })
+        .format(log_format)
+        .add_writer(
+            "error",
+            Box::new(
+                FileLogWriter::builder(FileSpec::try_from(&error_log_path)?)
+                    .append()
+                    .format(log_format)
+                    .try_build()?,
+            ),
+        )
         .start()?;
     // --- END LOGGING INIT ---
 
     let config = Arc::new(RwLock::new(temp_config));
+    let pid_path = config
+        .read()
+        .unwrap()
+        .pid_file
+        .clone()
+        .unwrap_or_else(|| shutdown::DEFAULT_PID_FILE.to_string());
 
     {
         let config_read = config.read().unwrap();
@@ -90,113 +677,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     }
 
-    // --- HOT-RELOAD CONFIG ---
-    let config_watcher = config.clone();
-    std::thread::spawn(move || {
-        log::info!("[hot-reload] watcher thread started");
-        let mut watcher = RecommendedWatcher::new(
-            move |res: Result<notify::Event, notify::Error>| {
-                match res {
-                    Ok(event) => {
-                        log::info!(
-                            "[hot-reload] notify event: kind={:?} paths={:?}",
-                            event.kind,
-                            event.paths
-                        );
-                        // Reload config on any event for now
-                        let new_config = load_config("config.yaml");
-                        let mut w = config_watcher.write().unwrap();
-                        *w = new_config;
-                        log::info!("[hot-reload] config.yaml reloaded");
-                    }
-                    Err(e) => {
-                        log::error!("[hot-reload] Watch error: {:?}", e);
-                    }
-                }
-            },
-            notify::Config::default(),
-        )
-        .unwrap();
-        watcher
-            .watch(
-                std::path::Path::new("config.yaml"),
-                RecursiveMode::Recursive,
-            )
-            .unwrap();
-        // Keep thread alive
-        loop {
-            std::thread::sleep(std::time::Duration::from_secs(3600));
-        }
-    });
-
-    let config_read = config.read().unwrap();
-    let addr = SocketAddr::new(config_read.address.parse()?, config_read.port);
-    let listener = TcpListener::bind(addr).await?;
-    info!("Server running on http://{}", addr);
-
-    // Build handler and middleware chain using builder
-    let handler: Arc<dyn Handler> = Arc::new(SimpleHandler);
-    let logging_middleware = Arc::new(LoggingMiddleware::new());
-    let chain = middleware_chain::MiddlewareChainBuilder::new()
-        .add_middleware(logging_middleware)
-        .build(handler);
-
-    // --- DYNAMIC PLUGIN LOADER & ENDPOINT MAPPING ---
-    use std::collections::HashMap;
-    let plugins_dir = config_read.plugins_dir.clone().unwrap_or_else(|| "./plugins".to_string());
-    let mut endpoint_plugins: HashMap<String, PluginInstance> = HashMap::new();
-    let mut loaded_plugins_log = Vec::new();
-    if let Some(ref mapping) = config_read.plugin_endpoints {
-        for (endpoint, filename) in mapping.iter() {
-            let path = PathBuf::from(&plugins_dir).join(filename);
-            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
-            match ext {
-                "so" => {
-                    match unsafe { CAbiModule::load(&path) } {
-                        Ok(m) => {
-                            endpoint_plugins.insert(endpoint.clone(), PluginInstance::CAbi(Arc::new(m)));
-                            loaded_plugins_log.push(format!("{} -> {} [CAbi]", endpoint, filename));
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to load C ABI plugin {}: {}", path.display(), e);
-                        }
-                    }
-                },
-                "lua" => {
-                    match ScriptingModule::load(&path) {
-                        Ok(m) => {
-                            endpoint_plugins.insert(endpoint.clone(), PluginInstance::Lua(Arc::new(m)));
-                            loaded_plugins_log.push(format!("{} -> {} [Lua]", endpoint, filename));
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to load Lua plugin {}: {}", path.display(), e);
-                        }
-                    }
-                },
-                "wasm" => {
-                    match WasmModule::load(&path) {
-                        Ok(m) => {
-                            endpoint_plugins.insert(endpoint.clone(), PluginInstance::Wasm(Arc::new(m)));
-                            loaded_plugins_log.push(format!("{} -> {} [WASM]", endpoint, filename));
-                        },
-                        Err(e) => {
-                            eprintln!("Failed to load WASM plugin {}: {}", path.display(), e);
-                        }
-                    }
-                },
-                _ => {
-                    eprintln!("Unknown plugin extension for {}: {}", endpoint, filename);
-                }
-            }
-        }
-    }
-    if !loaded_plugins_log.is_empty() {
-        info!("Loaded plugins: {:?}", loaded_plugins_log);
-    } else {
-        info!("No plugins loaded from mapping");
-    }
-    let endpoint_plugins = Arc::new(endpoint_plugins);
-
     // Load Rust dylib plugin at startup
     let mut rust_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     rust_path.push("src/modules/rust_plugin_example/target/release/librust_plugin_example.so");
@@ -215,104 +695,311 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     }));
 
+    let status = Arc::new(Mutex::new(ReloadStatus::default()));
+    let control = control_endpoints(&rust_plugin, rust_path, status.clone());
+
+    let proxy = Arc::new(ProxyHandler::from_config(&config.read().unwrap()));
+    health_check::spawn(proxy.clone());
+    let mut plugins = PluginCache::new();
+    let (unloader, unloading) = Unloader::spawn(config.clone());
+    let (sites, listens) = build_sites(
+        &config.read().unwrap(),
+        &proxy,
+        &mut plugins,
+        &unloader,
+        &control,
+    );
+    plugins.sweep();
+    let sites = Arc::new(ArcSwap::from_pointee(sites));
+
+    let tls_resolver = match config.read().unwrap().tls {
+        Some(ref tls) => Some(Arc::new(CertResolver::from_config(tls)?)),
+        None => None,
+    };
+    let tls_watcher = tls_resolver.as_ref().map(tls::watch).transpose()?;
+
+    // --- HOT-RELOAD CONFIG ---
+    // The watcher asks the main loop for reloads, which applies them one at
+    // a time.
+    let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+    let mut reloader = Reloader {
+        path: options.config.clone(),
+        config: config.clone(),
+        sites: sites.clone(),
+        proxy: proxy.clone(),
+        plugins,
+        unloader,
+        control,
+        tls: tls_resolver,
+        tls_watcher,
+        watcher: ConfigWatcher::new(config_sources(&options.config), DEBOUNCE, move || {
+            info!("[hot-reload] config changed");
+            let _ = reload_tx.send(());
+        })?,
+        status,
+    };
+    let state = AppState {
+        config: config.clone(),
+        sites,
+    };
+    let mut listeners = Listeners {
+        state: state.clone(),
+        graceful: Arc::new(GracefulShutdown::new()),
+        running: Vec::new(),
+    };
+    let mut signals = Signals::new()?;
+    // Sockets passed down by the binary we are replacing, if any.
+    let mut inherited = upgrade::inherited_listeners();
+    for listen in listens {
+        listeners
+            .bind(listen, reloader.tls.as_ref(), &mut inherited)
+            .await?;
+    }
+    // Inherited sockets no longer in the config are closed here.
+    drop(inherited);
+    // The pid file doubles as the ready signal for an upgrading parent.
+    let mut pid_file = PidFile::create(&pid_path)?;
+
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
-        let config = config.clone();
-        let chain = chain.clone();
-        let endpoint_plugins = endpoint_plugins.clone();
-        let rust_plugin_outer = rust_plugin.clone();
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
-                        let config = config.clone();
-                        let chain = chain.clone();
-                        let endpoint_plugins = endpoint_plugins.clone();
-                        let rust_plugin = rust_plugin_outer.clone();
-                        async move {
-                            let path = req.uri().path();
-                            if let Some(plugin) = endpoint_plugins.get(path) {
-                                let input = format!("{} {}", req.method(), req.uri());
-                                let resp = match plugin {
-                                    PluginInstance::CAbi(p) => {
-                                        let output = p.handle(&input);
-                                        hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from(output)))
-                                    },
-                                    PluginInstance::Lua(p) => {
-                                        let output = p.handle(&input);
-                                        hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from(output)))
-                                    },
-                                    PluginInstance::Wasm(p) => {
-                                        let output = p.handle(&input);
-                                        hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from(output)))
-                                    },
-                                };
-                                Ok::<_, std::convert::Infallible>(resp)
-                            } else if path == "/reload-rust-plugin" {
-                                let mut guard = rust_plugin.lock().unwrap();
-                                if let Some(rust_plugin) = guard.as_mut() {
-                                    let msg = rust_plugin.reload();
-                                    let resp = hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from(msg)));
-                                    Ok::<_, std::convert::Infallible>(resp)
-                                } else {
-                                    // Try load if None
-                                    let mut rust_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-                                    rust_path.push("src/modules/rust_plugin_example/target/release/librust_plugin_example.so");
-                                    match unsafe { RustDylibModule::load(&rust_path) } {
-                                        Ok(new_mod) => {
-                                            *guard = Some(new_mod);
-                                            let resp = hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from("[rust_plugin] loaded")));
-                                            Ok::<_, std::convert::Infallible>(resp)
-                                        },
-                                        Err(e) => {
-                                            let resp = hyper::Response::builder()
-                                                .status(500)
-                                                .body(http_body_util::Full::new(hyper::body::Bytes::from(format!("[rust_plugin] reload error: {}", e))))
-                                                .unwrap();
-                                            Ok::<_, std::convert::Infallible>(resp)
-                                        }
-                                    }
-                                }
-                            } else if path == "/init-rust-plugin" {
-                                let mut guard = rust_plugin.lock().unwrap();
-                                if let Some(rust_plugin) = guard.as_mut() {
-                                    let msg = rust_plugin.init();
-                                    let resp = hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from(msg)));
-                                    Ok::<_, std::convert::Infallible>(resp)
-                                } else {
-                                    let resp = hyper::Response::builder()
-                                        .status(500)
-                                        .body(http_body_util::Full::new(hyper::body::Bytes::from("Rust dylib plugin not loaded")))
-                                        .unwrap();
-                                    Ok::<_, std::convert::Infallible>(resp)
-                                }
-                            } else if path == "/shutdown-rust-plugin" {
-                                let mut guard = rust_plugin.lock().unwrap();
-                                if let Some(rust_plugin) = guard.as_mut() {
-                                    let msg = rust_plugin.shutdown();
-                                    let resp = hyper::Response::new(http_body_util::Full::new(hyper::body::Bytes::from(msg)));
-                                    Ok::<_, std::convert::Infallible>(resp)
-                                } else {
-                                    let resp = hyper::Response::builder()
-                                        .status(500)
-                                        .body(http_body_util::Full::new(hyper::body::Bytes::from("Rust dylib plugin not loaded")))
-                                        .unwrap();
-                                    Ok::<_, std::convert::Infallible>(resp)
-                                }
-                            } else {
-                                // Always read latest config
-                                let config_arc = config.clone();
-                                chain.handle(req, config_arc).await
-                            }
-                        }
-                    }),
-                )
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
+        let signal = tokio::select! {
+            signal = signals.recv() => signal,
+            Some(()) = reload_rx.recv() => {
+                reloader.reload(&mut listeners).await;
+                continue;
+            }
+        };
+        match signal {
+            Signal::Terminate(name) => {
+                info!(
+                    "[shutdown] {} received, no longer accepting connections",
+                    name
+                );
+                break;
+            }
+            Signal::Reload => {
+                info!("[hot-reload] SIGHUP received");
+                reloader.reload(&mut listeners).await;
+            }
+            Signal::Reopen => {
+                info!("[logging] SIGUSR1 received, reopening log files");
+                if let Err(e) = logger.reopen_output() {
+                    log::error!("[logging] reopening log files: {}", e);
+                }
+            }
+            Signal::Upgrade => match upgrade_binary(&listeners.fds(), &mut pid_file).await {
+                Ok(pid) => {
+                    info!("[upgrade] new binary (pid {}) is serving, draining", pid);
+                    break;
+                }
+                Err(e) => log::error!("[upgrade] {}, keeping the current binary", e),
+            },
+        }
+    }
+    let graceful = listeners.close_all().await;
+    let timeout = Duration::from_secs(
+        config
+            .read()
+            .unwrap()
+            .shutdown_timeout
+            .unwrap_or(shutdown::DEFAULT_TIMEOUT_SECS),
+    );
+    if shutdown::drain(graceful, timeout).await {
+        info!("[shutdown] all connections closed");
+    } else {
+        log::warn!(
+            "[shutdown] connections still open after {}s, closing them",
+            timeout.as_secs()
+        );
+    }
+
+    // Drop every handler so the plugins are shut down, waiting for calls
+    // still running no longer than a plugin call may take.
+    reloader.sites.store(Arc::new(Sites::new()));
+    drop(reloader);
+    let plugin_timeout = config
+        .read()
+        .unwrap()
+        .plugin_timeout
+        .unwrap_or(plugin_handler::DEFAULT_TIMEOUT_SECS);
+    if tokio::time::timeout(Duration::from_secs(plugin_timeout), unloading)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "[shutdown] plugins still busy after {}s, exiting without shutting them down",
+            plugin_timeout
+        );
+    }
+    if let Some(rust_plugin) = rust_plugin.lock().unwrap().as_mut() {
+        info!("{}", rust_plugin.shutdown());
+    }
+
+    pid_file.remove();
+    info!("[shutdown] bye");
+    logger.flush();
+    logger.shutdown();
+    Ok(())
+}
+
+/// Start a new binary on `listen_fds` and wait for it to take over, with the
+/// pid file rotated to `.oldbin` meanwhile. Returns the new process id.
+async fn upgrade_binary(
+    listen_fds: &[(SocketAddr, RawFd)],
+    pid_file: &mut PidFile,
+) -> std::io::Result<u32> {
+    info!("[upgrade] SIGUSR2 received, starting a new binary");
+    let original = pid_file.path().to_path_buf();
+    pid_file.rotate()?;
+    let result = match upgrade::spawn(listen_fds) {
+        Ok(mut child) => {
+            let ready = upgrade::wait_ready(&mut child, &original, upgrade::READY_TIMEOUT).await;
+            if ready.is_err() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            ready.map(|_| child.id())
+        }
+        Err(e) => Err(e),
+    };
+    if result.is_err() {
+        pid_file.restore()?;
+    }
+    result
+}
+
+/// Accept connections on one listen address until `stop` is set.
+async fn accept_loop(
+    listener: TcpListener,
+    listen_addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    state: AppState,
+    graceful: Arc<GracefulShutdown>,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stop.wait_for(|stop| *stop) => return,
+        };
+        let (stream, remote_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::error!("[listen] accept error on {}: {}", listen_addr, e);
+                continue;
+            }
+        };
+        let conn = Conn {
+            remote_addr,
+            listen_addr,
+            tls: false,
+            server_name: None,
+        };
+        let state = state.clone();
+        let watcher = graceful.watcher();
+        let Some(acceptor) = acceptor.clone() else {
+            tokio::spawn(serve_connection(stream, conn, state, watcher));
+            continue;
+        };
+        tokio::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let server_name = stream.get_ref().1.server_name().map(str::to_string);
+                    let conn = Conn {
+                        tls: true,
+                        server_name,
+                        ..conn
+                    };
+                    serve_connection(stream, conn, state, watcher).await
+                }
+                Err(e) => log::warn!("[tls] handshake with {} failed: {}", remote_addr, e),
             }
         });
     }
 }
+
+/// Where a connection came from and which listener accepted it.
+#[derive(Clone)]
+struct Conn {
+    remote_addr: SocketAddr,
+    listen_addr: SocketAddr,
+    tls: bool,
+    /// TLS SNI name, used when a request has no host.
+    server_name: Option<String>,
+}
+
+/// Shared state handed to every connection.
+#[derive(Clone)]
+struct AppState {
+    config: Arc<RwLock<Config>>,
+    sites: Arc<ArcSwap<Sites>>,
+}
+
+/// Serve HTTP/1.1 or HTTP/2 on an accepted connection, plaintext or TLS.
+async fn serve_connection<S>(stream: S, conn: Conn, state: AppState, watcher: graceful::Watcher)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let builder = connection::builder(state.config.read().unwrap().http2.as_ref());
+    let service = service_fn(move |req| handle_request(req, conn.clone(), state.clone()));
+    let connection = builder.serve_connection(TokioIo::new(stream), service);
+    if let Err(err) = watcher.watch(connection).await {
+        eprintln!("Error serving connection: {:?}", err);
+    }
+}
+
+async fn handle_request(
+    req: hyper::Request<hyper::body::Incoming>,
+    conn: Conn,
+    state: AppState,
+) -> Result<hyper::Response<ResponseBody>, std::convert::Infallible> {
+    let mut req = req.map(request_body);
+    req.extensions_mut().insert(ConnectionInfo {
+        remote_addr: conn.remote_addr,
+        tls: conn.tls,
+    });
+    // Snapshot the current servers and pick the one for this request
+    let sites = state.sites.load_full();
+    let host = request_host(&req).or(conn.server_name);
+    let Some(site) = sites
+        .get(&conn.listen_addr)
+        .and_then(|hosts| hosts.find(host.as_deref()))
+    else {
+        // The listener's servers were removed by a reload.
+        return Ok(hyper::Response::builder()
+            .status(421)
+            .body(full("Misdirected Request"))
+            .unwrap());
+    };
+    let path = req.uri().path().to_string();
+    match site.router.find(req.method(), &path) {
+        RouteLookup::Found(m) => {
+            req.extensions_mut().insert(m.params);
+            return m.target.handle(req, site.config.clone()).await;
+        }
+        RouteLookup::MethodNotAllowed(allowed) => {
+            let allow = allowed
+                .iter()
+                .map(|m| m.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let resp = hyper::Response::builder()
+                .status(405)
+                .header(hyper::header::ALLOW, allow)
+                .body(full("Method Not Allowed"))
+                .unwrap();
+            return Ok::<_, std::convert::Infallible>(resp);
+        }
+        RouteLookup::NotFound => {}
+    }
+    if let RouteLookup::Found(m) = site.control.find(req.method(), &path) {
+        return m.target.handle(req, site.config.clone()).await;
+    }
+    // Always read latest config
+    let config_arc = site.config.clone();
+    let proxied = config_arc.read().unwrap().proxy_pass.is_some();
+    if proxied {
+        site.proxy_chain.handle(req, config_arc).await
+    } else {
+        site.chain.handle(req, config_arc).await
+    }
+}
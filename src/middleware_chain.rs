@@ -1,11 +1,17 @@
-use crate::middleware_trait::Middleware;
-use crate::handler_trait::Handler;
+use crate::handler_trait::{Context, Handler, HandlerFuture, RequestBody};
+use crate::middleware_trait::{Flow, Middleware, RequestHead};
 use std::sync::Arc;
 
 pub struct MiddlewareChainBuilder {
     middlewares: Vec<Arc<dyn Middleware>>,
 }
 
+impl Default for MiddlewareChainBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MiddlewareChainBuilder {
     pub fn new() -> Self {
         MiddlewareChainBuilder {
@@ -30,13 +36,20 @@ struct MiddlewareHandlerWrapper {
     next: Arc<dyn Handler>,
 }
 
-use std::sync::RwLock;
 impl Handler for MiddlewareHandlerWrapper {
     fn handle<'a>(
         &'a self,
-        req: hyper::Request<hyper::body::Incoming>,
+        mut req: hyper::Request<RequestBody>,
         config: std::sync::Arc<std::sync::RwLock<crate::config::Config>>,
-    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<hyper::Response<http_body_util::Full<hyper::body::Bytes>>, std::convert::Infallible>> + Send + 'a>> {
-        self.mw.handle(req, config, self.next.clone())
+    ) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let ctx = Context::of(&mut req);
+            let head = RequestHead::of(&req);
+            let resp = match self.mw.before(req, &ctx).await {
+                Flow::Continue(req) => self.next.handle(req, config).await?,
+                Flow::Respond(resp) => resp,
+            };
+            Ok(self.mw.after(&head, &ctx, resp).await)
+        })
     }
 }
@@ -0,0 +1,128 @@
+//! Middleware by name, for the `middleware:` lists of servers and routes.
+//!
+//! ```yaml
+//! middleware:
+//!   - logging
+//!   - name: headers
+//!     set: { X-Frame-Options: DENY }
+//! routes:
+//!   - prefix: /api
+//!     handler: proxy
+//!     middleware:
+//!       - name: auth
+//!         api_keys: { ci: "${CI_API_KEY}" }
+//!       - name: rate_limit
+//!         requests_per_second: 10
+//! ```
+use crate::auth_middleware::{AuthMiddleware, AuthOptions};
+use crate::compression_middleware::{CompressionMiddleware, CompressionOptions};
+use crate::config::MiddlewareConfig;
+use crate::handler_trait::Handler;
+use crate::headers_middleware::{HeadersMiddleware, HeadersOptions};
+use crate::logging_middleware::LoggingMiddleware;
+use crate::middleware_chain::MiddlewareChainBuilder;
+use crate::middleware_trait::Middleware;
+use crate::rate_limit_middleware::{RateLimitMiddleware, RateLimitOptions};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Builds a middleware from its options (an empty mapping for a bare name).
+pub type MiddlewareFactory =
+    Arc<dyn Fn(&serde_yaml::Value) -> Result<Arc<dyn Middleware>, String> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct MiddlewareRegistry {
+    factories: BTreeMap<String, MiddlewareFactory>,
+}
+
+/// The built-in middleware: `logging`, `auth`, `rate_limit`, `headers` and
+/// `compression`.
+impl Default for MiddlewareRegistry {
+    fn default() -> Self {
+        let mut registry = MiddlewareRegistry::new();
+        registry.register("logging", |options| {
+            parse::<Empty>(options)?;
+            Ok(Arc::new(LoggingMiddleware::new()))
+        });
+        registry.register("auth", |options| {
+            let options = parse::<AuthOptions>(options)?;
+            Ok(Arc::new(AuthMiddleware::new(options)?))
+        });
+        registry.register("rate_limit", |options| {
+            let options = parse::<RateLimitOptions>(options)?;
+            Ok(Arc::new(RateLimitMiddleware::new(options)?))
+        });
+        registry.register("headers", |options| {
+            let options = parse::<HeadersOptions>(options)?;
+            Ok(Arc::new(HeadersMiddleware::new(options)?))
+        });
+        registry.register("compression", |options| {
+            let options = parse::<CompressionOptions>(options)?;
+            Ok(Arc::new(CompressionMiddleware::new(options)?))
+        });
+        registry
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Empty {}
+
+/// Deserialize a middleware's options, keeping the message short.
+fn parse<T: DeserializeOwned>(options: &serde_yaml::Value) -> Result<T, String> {
+    serde_yaml::from_value(options.clone()).map_err(|e| e.to_string())
+}
+
+impl MiddlewareRegistry {
+    /// A registry without any middleware.
+    pub fn new() -> Self {
+        MiddlewareRegistry {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Add or replace the middleware called `name`.
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&serde_yaml::Value) -> Result<Arc<dyn Middleware>, String> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Arc::new(factory));
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// A new instance of the middleware `config` names.
+    pub fn build(&self, config: &MiddlewareConfig) -> Result<Arc<dyn Middleware>, String> {
+        let factory = self.factories.get(config.name()).ok_or_else(|| {
+            let known = self.names().collect::<Vec<_>>().join(", ");
+            format!("unknown middleware {:?} (known: {})", config.name(), known)
+        })?;
+        factory(&config.options()).map_err(|e| format!("{}: {}", config.name(), e))
+    }
+
+    /// Build every entry of `configs`, logging and skipping the ones that
+    /// fail (`Config::validate` reports those before a config is used).
+    pub fn build_all(&self, configs: &[MiddlewareConfig]) -> Vec<Arc<dyn Middleware>> {
+        configs
+            .iter()
+            .filter_map(|config| {
+                self.build(config)
+                    .map_err(|e| log::error!("[middleware] {}, skipped", e))
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/// Wrap `handler` in `middleware`, outermost first.
+pub fn chain(middleware: &[Arc<dyn Middleware>], handler: Arc<dyn Handler>) -> Arc<dyn Handler> {
+    middleware
+        .iter()
+        .fold(MiddlewareChainBuilder::new(), |builder, mw| {
+            builder.add_middleware(mw.clone())
+        })
+        .build(handler)
+}
@@ -1,21 +1,58 @@
-use crate::config::Config;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::{Request, Response};
-use std::convert::Infallible;
+use crate::handler_trait::{Context, RequestBody, ResponseBody};
+use hyper::{Method, Request, Response, Uri, Version};
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::sync::RwLock;
 
+/// Boxed future returned by middleware hooks.
+pub type MiddlewareFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// What a `before` hook decided.
+pub enum Flow {
+    /// Pass the request, possibly changed, to the rest of the chain.
+    Continue(Request<RequestBody>),
+    /// Skip the rest of the chain and answer with this response.
+    Respond(Response<ResponseBody>),
+}
+
+/// The request line an `after` hook runs for; the request itself has been
+/// handed down the chain by then.
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+}
+
+impl RequestHead {
+    pub fn of<B>(req: &Request<B>) -> Self {
+        RequestHead {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+        }
+    }
+}
+
+/// Runs `before` on the way in and `after` on the way out; both pass things
+/// through unchanged unless overridden. `after` sees every response leaving
+/// the middleware, including one its own `before` answered with.
 pub trait Middleware: Send + Sync {
-    fn handle<'a>(
+    fn before<'a>(
         &'a self,
-        req: Request<Incoming>,
-        config: Arc<RwLock<Config>>,
-        next: Arc<dyn Handler + Send + Sync>,
-    ) -> Pin<Box<dyn Future<Output = Result<Response<Full<Bytes>>, Infallible>> + Send + 'a>>;
-}
+        req: Request<RequestBody>,
+        ctx: &'a Context,
+    ) -> MiddlewareFuture<'a, Flow> {
+        let _ = ctx;
+        Box::pin(async move { Flow::Continue(req) })
+    }
 
-// Import Handler trait for the next parameter
-use crate::handler_trait::Handler;
+    fn after<'a>(
+        &'a self,
+        head: &'a RequestHead,
+        ctx: &'a Context,
+        resp: Response<ResponseBody>,
+    ) -> MiddlewareFuture<'a, Response<ResponseBody>> {
+        let _ = (head, ctx);
+        Box::pin(async move { resp })
+    }
+}
@@ -1,5 +1,15 @@
+use crate::modules::envelope::{PluginRequest, PluginResponse};
+use crate::router::RouteParams;
+use std::fmt;
+use std::future::Future;
 use std::panic::{AssertUnwindSafe, catch_unwind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+use tokio::time::Instant;
+pub use wigspace_plugin_api::PluginVTable;
+use wigspace_plugin_api::{ABI_VERSION, CAP_ENVELOPE, CAP_LIFECYCLE, PluginInfo, PluginInfoFn};
 
 /// Trait untuk lifecycle management plugin
 pub trait PluginLifecycle {
@@ -19,124 +29,439 @@ use std::ffi::c_void;
 /// Trait for all dynamic modules (C ABI, Rust dylib, WASM, scripting)
 pub trait DynamicModule: Send + Sync {
     fn handle(&self, input: &str) -> String;
+
+    /// Handle a request together with the params captured by the router.
+    /// Modules without a structured way to receive them get the params
+    /// appended to the input as `name=value` pairs.
+    fn handle_with_params(&self, input: &str, params: &RouteParams) -> String {
+        self.handle(&with_params(input, params))
+    }
+
+    /// Handle a request given as an envelope. Plugins without an envelope
+    /// entry point get the request line and params, and what they return
+    /// becomes a 200 response.
+    fn call(&self, req: &PluginRequest) -> PluginResponse {
+        PluginResponse::text(self.handle_with_params(&req.request_line(), &req.params))
+    }
+
+    /// `call` on a blocking thread of the runtime `rt`, giving up at
+    /// `deadline`. WASM and Lua plugins run as futures on `rt` there, so
+    /// their host calls are async and they are stopped at the deadline;
+    /// others run to completion.
+    fn call_blocking(
+        &self,
+        req: &PluginRequest,
+        _rt: &Handle,
+        _deadline: Instant,
+    ) -> Result<PluginResponse, TimedOut> {
+        Ok(self.call(req))
+    }
+}
+
+/// A plugin call stopped at its deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "plugin call timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Run the future `call` makes from synchronous code, on a thread with a
+/// runtime of its own so it works both inside and outside of tokio.
+fn block_on<F, Fut>(call: F) -> Fut::Output
+where
+    F: FnOnce() -> Fut + Send,
+    Fut: Future,
+    Fut::Output: Send,
+{
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .expect("plugin runtime")
+                .block_on(call())
+        })
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// The `sleep` host call: waits `ms` milliseconds without holding a tokio
+/// worker.
+async fn host_sleep(ms: i64) {
+    tokio::time::sleep(Duration::from_millis(ms.max(0) as u64)).await;
+}
+
+/// `input` followed by the route params as `name=value` pairs.
+fn with_params(input: &str, params: &RouteParams) -> String {
+    if params.is_empty() {
+        input.to_string()
+    } else {
+        format!("{} {}", input, params)
+    }
+}
+
+/// Parse the JSON envelope a native or WASM plugin answered with.
+fn parse_response(kind: &str, output: &str) -> Result<PluginResponse, String> {
+    PluginResponse::from_json(output).map_err(|e| format!("[{}] {}", kind, e))
+}
+
+/// Why a native plugin could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The library could not be opened or lacks a required export.
+    Library(libloading::Error),
+    /// `plugin_info` is missing or describes a plugin this server cannot
+    /// run.
+    Incompatible(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Library(e) => write!(f, "{}", e),
+            LoadError::Incompatible(reason) => write!(f, "incompatible plugin: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<libloading::Error> for LoadError {
+    fn from(e: libloading::Error) -> Self {
+        LoadError::Library(e)
+    }
+}
+
+/// A native plugin's `plugin_info`, checked and copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginManifest {
+    pub name: String,
+    pub version: String,
+    /// `CAP_*` flags from `wigspace_plugin_api`.
+    pub capabilities: u32,
+}
+
+impl PluginManifest {
+    pub fn has(&self, capability: u32) -> bool {
+        self.capabilities & capability != 0
+    }
+}
+
+/// Call `plugin_info` and check the plugin was built for this ABI.
+///
+/// # Safety
+/// `lib` must be a trusted plugin; `plugin_info` must return NULL or a
+/// `PluginInfo` living as long as the library.
+unsafe fn read_manifest(lib: &Library) -> Result<PluginManifest, LoadError> {
+    let info_fn = unsafe { lib.get::<PluginInfoFn>(b"plugin_info") }.map_err(|_| {
+        LoadError::Incompatible(
+            "no plugin_info export; rebuild it against wigspace-plugin-api".to_string(),
+        )
+    })?;
+    let info = info_fn();
+    if info.is_null() {
+        return Err(LoadError::Incompatible(
+            "plugin_info returned NULL".to_string(),
+        ));
+    }
+    // Only the version is read before it is known the layout matches.
+    let abi_version = unsafe { std::ptr::addr_of!((*info).abi_version).read() };
+    if abi_version != ABI_VERSION {
+        return Err(LoadError::Incompatible(format!(
+            "built for plugin ABI {}, this server supports {}",
+            abi_version, ABI_VERSION
+        )));
+    }
+    let info: &PluginInfo = unsafe { &*info };
+    let text = |ptr: *const c_char, field: &str| {
+        if ptr.is_null() {
+            return Err(LoadError::Incompatible(format!(
+                "plugin_info {} is NULL",
+                field
+            )));
+        }
+        unsafe { CStr::from_ptr(ptr) }
+            .to_str()
+            .map(str::to_owned)
+            .map_err(|_| LoadError::Incompatible(format!("plugin_info {} is not UTF-8", field)))
+    };
+    Ok(PluginManifest {
+        name: text(info.name, "name")?,
+        version: text(info.version, "version")?,
+        capabilities: info.capabilities,
+    })
+}
+
+/// The `plugin_init`/`plugin_shutdown` pair of a plugin with
+/// `CAP_LIFECYCLE`.
+type Hooks = (
+    Option<unsafe extern "C" fn() -> i32>,
+    Option<unsafe extern "C" fn() -> i32>,
+);
+
+/// # Safety
+/// As for `read_manifest`.
+unsafe fn lifecycle_hooks(lib: &Library, manifest: &PluginManifest) -> Result<Hooks, LoadError> {
+    if !manifest.has(CAP_LIFECYCLE) {
+        return Ok((None, None));
+    }
+    unsafe {
+        let init = *lib.get::<unsafe extern "C" fn() -> i32>(b"plugin_init")?;
+        let shutdown = *lib.get::<unsafe extern "C" fn() -> i32>(b"plugin_shutdown")?;
+        Ok((Some(init), Some(shutdown)))
+    }
+}
+
+/// `free_string`, which native plugins must export to release the strings
+/// they return.
+type FreeString = unsafe extern "C" fn(*mut c_char);
+
+/// Copy a string a native plugin returned and hand it back to the plugin's
+/// `free_string`. NULL and invalid UTF-8 are errors.
+///
+/// # Safety
+/// `ptr` must be NULL or a null-terminated string `free` accepts.
+unsafe fn take_string(kind: &str, ptr: *mut c_char, free: FreeString) -> Result<String, String> {
+    if ptr.is_null() {
+        return Err(format!("[{}] plugin returned NULL", kind));
+    }
+    let result = unsafe { CStr::from_ptr(ptr) }
+        .to_str()
+        .map(str::to_owned)
+        .map_err(|e| format!("[{}] plugin returned invalid UTF-8: {}", kind, e));
+    unsafe { free(ptr) };
+    result
 }
 
 /// C ABI module loader (legacy, ecosystem-wide)
 pub struct CAbiModule {
+    path: PathBuf,
     _lib: Library,
+    manifest: PluginManifest,
     handler: Symbol<'static, unsafe extern "C" fn(*const u8, usize) -> *mut c_void>,
+    /// `handle_envelope` of plugins with `CAP_ENVELOPE`, same signature as
+    /// `handle_request`, taking and returning JSON envelopes.
+    envelope_fn: Option<unsafe extern "C" fn(*const u8, usize) -> *mut c_void>,
+    free_string: FreeString,
+    init_fn: Option<unsafe extern "C" fn() -> i32>,
+    shutdown_fn: Option<unsafe extern "C" fn() -> i32>,
 }
 
 impl CAbiModule {
-    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, libloading::Error> {
-        let lib = unsafe { Library::new(path)? };
+    /// # Safety
+    /// Loading runs the library's initialisers; `path` must be a trusted
+    /// plugin exporting `plugin_info`, `handle_request` with the C ABI
+    /// signature above and a `free_string` releasing what it returns.
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, LoadError> {
+        let pathbuf = PathBuf::from(path.as_ref());
+        let lib = unsafe { Library::new(&pathbuf)? };
+        let manifest = unsafe { read_manifest(&lib)? };
         let handler: Symbol<unsafe extern "C" fn(*const u8, usize) -> *mut c_void> =
             unsafe { lib.get(b"handle_request")? };
         // Extend lifetime for trait object safety
         let handler: Symbol<'static, unsafe extern "C" fn(*const u8, usize) -> *mut c_void> =
             unsafe { std::mem::transmute(handler) };
-        Ok(CAbiModule { _lib: lib, handler })
+        let envelope_fn = if manifest.has(CAP_ENVELOPE) {
+            Some(unsafe {
+                *lib.get::<unsafe extern "C" fn(*const u8, usize) -> *mut c_void>(
+                    b"handle_envelope",
+                )?
+            })
+        } else {
+            None
+        };
+        let free_string = unsafe { *lib.get::<FreeString>(b"free_string")? };
+        let (init_fn, shutdown_fn) = unsafe { lifecycle_hooks(&lib, &manifest)? };
+        Ok(CAbiModule {
+            path: pathbuf,
+            _lib: lib,
+            manifest,
+            handler,
+            envelope_fn,
+            free_string,
+            init_fn,
+            shutdown_fn,
+        })
     }
 }
 
-impl DynamicModule for CAbiModule {
-    fn handle(&self, input: &str) -> String {
+impl PluginLifecycle for CAbiModule {
+    fn init(&mut self) -> String {
+        call_hook("c_plugin", "init", self.init_fn)
+    }
+    fn shutdown(&mut self) -> String {
+        call_hook("c_plugin", "shutdown", self.shutdown_fn)
+    }
+    fn reload(&mut self) -> String {
+        let path = self.path.clone();
+        // Safety: same contract as the original load
+        match unsafe { CAbiModule::load(&path) } {
+            Ok(new_mod) => {
+                *self = new_mod;
+                "[c_plugin] reload: success".to_string()
+            }
+            Err(e) => format!("[c_plugin] reload error: {}", e),
+        }
+    }
+}
+
+/// Run an optional native `plugin_init`/`plugin_shutdown` hook.
+fn call_hook(kind: &str, name: &str, hook: Option<unsafe extern "C" fn() -> i32>) -> String {
+    let Some(f) = hook else {
+        return format!("[{}] no {} fn", kind, name);
+    };
+    match catch_unwind(AssertUnwindSafe(|| unsafe { f() })) {
+        Ok(code) => format!("[{}] {}: {}", kind, name, code),
+        Err(_) => format!("[{}] panic in {}", kind, name),
+    }
+}
+
+impl CAbiModule {
+    pub fn manifest(&self) -> &PluginManifest {
+        &self.manifest
+    }
+
+    /// Call a C ABI entry point with `input`.
+    fn call_c(
+        &self,
+        f: unsafe extern "C" fn(*const u8, usize) -> *mut c_void,
+        input: &str,
+    ) -> Result<String, String> {
         let bytes = input.as_bytes();
         unsafe {
-            let ptr = (self.handler)(bytes.as_ptr(), bytes.len());
-            // Assume returned pointer is a null-terminated C string
-            let cstr = std::ffi::CStr::from_ptr(ptr as *const i8);
-            let result = cstr.to_string_lossy().into_owned();
-            // Free the string if the module provides a free function (not shown here)
-            result
+            let ptr = f(bytes.as_ptr(), bytes.len());
+            take_string("c_plugin", ptr as *mut c_char, self.free_string)
         }
     }
 }
 
-/// Real Rust dylib loader (vtable pattern)
-use std::os::raw::c_char;
+impl DynamicModule for CAbiModule {
+    fn handle(&self, input: &str) -> String {
+        self.call_c(*self.handler, input).unwrap_or_else(|e| e)
+    }
 
-#[repr(C)]
-pub struct PluginVTable {
-    pub handle: extern "C" fn(*const c_char) -> *mut c_char,
+    fn call(&self, req: &PluginRequest) -> PluginResponse {
+        let result = match self.envelope_fn {
+            Some(f) => self
+                .call_c(f, &req.to_json())
+                .and_then(|output| parse_response("c_plugin", &output)),
+            None => self
+                .call_c(
+                    *self.handler,
+                    &with_params(&req.request_line(), &req.params),
+                )
+                .map(PluginResponse::text),
+        };
+        result.unwrap_or_else(PluginResponse::error)
+    }
 }
 
+/// Real Rust dylib loader (vtable pattern)
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
 pub struct RustDylibModule {
     path: PathBuf,
     _lib: Library,
+    manifest: PluginManifest,
     vtable: &'static PluginVTable,
+    /// `handle_envelope` of plugins with `CAP_ENVELOPE`, like
+    /// `PluginVTable::handle` but taking and returning JSON envelopes.
+    envelope_fn: Option<extern "C" fn(*const c_char) -> *mut c_char>,
+    free_string: FreeString,
     init_fn: Option<unsafe extern "C" fn() -> i32>,
     shutdown_fn: Option<unsafe extern "C" fn() -> i32>,
 }
 
 impl RustDylibModule {
-    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, libloading::Error> {
+    /// # Safety
+    /// Loading runs the library's initialisers; `path` must be a trusted
+    /// plugin exporting `plugin_info`, `get_plugin_vtable` with the layout
+    /// of `PluginVTable` and a `free_string` releasing what its functions
+    /// return.
+    pub unsafe fn load<P: AsRef<OsStr>>(path: P) -> Result<Self, LoadError> {
         let pathbuf = PathBuf::from(path.as_ref());
         let lib = unsafe { Library::new(&pathbuf)? };
+        let manifest = unsafe { read_manifest(&lib)? };
         let vtable_sym: Symbol<unsafe extern "C" fn() -> *const PluginVTable> =
             unsafe { lib.get(b"get_plugin_vtable")? };
         let vtable = unsafe { vtable_sym() };
-        let vtable: &'static PluginVTable = unsafe { std::mem::transmute(vtable) };
-        // Optional: init/shutdown
-        let init_fn =
-            lib.get(b"plugin_init")
-                .ok()
-                .map(|sym: Symbol<unsafe extern "C" fn() -> i32>| unsafe {
-                    std::mem::transmute::<_, unsafe extern "C" fn() -> i32>(sym)
-                });
-        let shutdown_fn = lib.get(b"plugin_shutdown").ok().map(
-            |sym: Symbol<unsafe extern "C" fn() -> i32>| unsafe {
-                std::mem::transmute::<_, unsafe extern "C" fn() -> i32>(sym)
-            },
-        );
+        let vtable: &'static PluginVTable = unsafe { &*vtable };
+        let envelope_fn = if manifest.has(CAP_ENVELOPE) {
+            Some(unsafe {
+                *lib.get::<extern "C" fn(*const c_char) -> *mut c_char>(b"handle_envelope")?
+            })
+        } else {
+            None
+        };
+        let free_string = unsafe { *lib.get::<FreeString>(b"free_string")? };
+        let (init_fn, shutdown_fn) = unsafe { lifecycle_hooks(&lib, &manifest)? };
         Ok(RustDylibModule {
             path: pathbuf,
             _lib: lib,
+            manifest,
             vtable,
+            envelope_fn,
+            free_string,
             init_fn,
             shutdown_fn,
         })
     }
 }
 
+impl RustDylibModule {
+    pub fn manifest(&self) -> &PluginManifest {
+        &self.manifest
+    }
+
+    /// Call a Rust dylib entry point with `input`, catching panics.
+    fn call_rust(
+        &self,
+        f: extern "C" fn(*const c_char) -> *mut c_char,
+        input: &str,
+    ) -> Result<String, String> {
+        let c_input = std::ffi::CString::new(input)
+            .map_err(|_| "[rust_plugin] input contains a NUL byte".to_string())?;
+        let ptr = catch_unwind(AssertUnwindSafe(|| f(c_input.as_ptr())))
+            .map_err(|_| "[rust_plugin] panic in plugin".to_string())?;
+        unsafe { take_string("rust_plugin", ptr, self.free_string) }
+    }
+}
+
 impl DynamicModule for RustDylibModule {
     fn handle(&self, input: &str) -> String {
-        let c_input = std::ffi::CString::new(input).unwrap();
-        let result = catch_unwind(AssertUnwindSafe(|| unsafe {
-            let ptr = (self.vtable.handle)(c_input.as_ptr());
-            let cstr = std::ffi::CStr::from_ptr(ptr);
-            let result = cstr.to_string_lossy().into_owned();
-            result
-        }));
-        match result {
-            Ok(s) => s,
-            Err(_) => "[rust_plugin] panic in plugin".to_string(),
-        }
+        self.call_rust(self.vtable.handle, input)
+            .unwrap_or_else(|e| e)
+    }
+
+    fn call(&self, req: &PluginRequest) -> PluginResponse {
+        let result = match self.envelope_fn {
+            Some(f) => self
+                .call_rust(f, &req.to_json())
+                .and_then(|output| parse_response("rust_plugin", &output)),
+            None => self
+                .call_rust(
+                    self.vtable.handle,
+                    &with_params(&req.request_line(), &req.params),
+                )
+                .map(PluginResponse::text),
+        };
+        result.unwrap_or_else(PluginResponse::error)
     }
 }
 
 impl PluginLifecycle for RustDylibModule {
     fn init(&mut self) -> String {
-        if let Some(f) = self.init_fn {
-            let res = catch_unwind(AssertUnwindSafe(|| unsafe { f() }));
-            match res {
-                Ok(code) => format!("[rust_plugin] init: {}", code),
-                Err(_) => "[rust_plugin] panic in init".to_string(),
-            }
-        } else {
-            "[rust_plugin] no init fn".to_string()
-        }
+        call_hook("rust_plugin", "init", self.init_fn)
     }
     fn shutdown(&mut self) -> String {
-        if let Some(f) = self.shutdown_fn {
-            let res = catch_unwind(AssertUnwindSafe(|| unsafe { f() }));
-            match res {
-                Ok(code) => format!("[rust_plugin] shutdown: {}", code),
-                Err(_) => "[rust_plugin] panic in shutdown".to_string(),
-            }
-        } else {
-            "[rust_plugin] no shutdown fn".to_string()
-        }
+        call_hook("rust_plugin", "shutdown", self.shutdown_fn)
     }
     fn reload(&mut self) -> String {
         // Drop current lib, reload from path
@@ -153,20 +478,37 @@ impl PluginLifecycle for RustDylibModule {
 }
 
 /// WASM module loader (wasmtime skeleton)
+///
+/// Calls run as futures: the host calls a module imports from `env` are
+/// async, and a busy module yields every `FUEL_YIELD` units of fuel, so a
+/// deadline stops it even without host calls. Imports available:
+/// - `sleep_ms(ms: i32)`: the `sleep` host call.
 pub struct WasmModule {
+    path: PathBuf,
     engine: wasmtime::Engine,
     module: wasmtime::Module,
     linker: wasmtime::Linker<()>,
     memory_ty: wasmtime::MemoryType,
 }
 
+/// Fuel, roughly instructions, a WASM call runs between yields.
+const FUEL_YIELD: u64 = 10_000;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
 impl WasmModule {
     pub fn load<P: AsRef<std::path::Path>>(path: P) -> anyhow::Result<Self> {
-        let engine = wasmtime::Engine::default();
-        let module = wasmtime::Module::from_file(&engine, path)?;
-        let linker = wasmtime::Linker::new(&engine);
+        let mut config = wasmtime::Config::new();
+        config.async_support(true).consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let module = wasmtime::Module::from_file(&engine, &path)?;
+        let mut linker = wasmtime::Linker::new(&engine);
+        linker.func_wrap_async("env", "sleep_ms", |_caller, (ms,): (i32,)| {
+            Box::new(host_sleep(ms.into()))
+        })?;
         let memory_ty = wasmtime::MemoryType::new(1, None);
         Ok(WasmModule {
+            path: path.as_ref().to_path_buf(),
             engine,
             module,
             linker,
@@ -175,90 +517,534 @@ impl WasmModule {
     }
 }
 
-impl DynamicModule for WasmModule {
-    fn handle(&self, input: &str) -> String {
-        use wasmtime::{Memory, Store, Val};
+impl WasmModule {
+    /// A fresh store and instance with the host memory linked in as
+    /// `env.memory`.
+    async fn instantiate(
+        &self,
+    ) -> Result<(wasmtime::Store<()>, wasmtime::Instance, wasmtime::Memory), String> {
+        use wasmtime::{Memory, Store};
         let mut store = Store::new(&self.engine, ());
-        let memory = Memory::new(&mut store, self.memory_ty.clone()).unwrap();
+        store
+            .set_fuel(u64::MAX)
+            .and_then(|()| store.fuel_async_yield_interval(Some(FUEL_YIELD)))
+            .map_err(|e| format!("[WASM error] store: {}", e))?;
+        let memory = Memory::new(&mut store, self.memory_ty.clone())
+            .map_err(|e| format!("[WASM error] memory: {}", e))?;
         let mut linker = self.linker.clone();
         linker
-            .define(&mut store, "env", "memory", memory.clone())
-            .unwrap();
-        let instance = match linker.instantiate(&mut store, &self.module) {
-            Ok(i) => i,
-            Err(e) => return format!("[WASM error] instantiation failed: {}", e),
+            .define(&mut store, "env", "memory", memory)
+            .map_err(|e| format!("[WASM error] link: {}", e))?;
+        let instance = linker
+            .instantiate_async(&mut store, &self.module)
+            .await
+            .map_err(|e| format!("[WASM error] instantiation failed: {}", e))?;
+        Ok((store, instance, memory))
+    }
+
+    /// Call an optional exported `init`/`shutdown` taking no arguments.
+    async fn call_export(&self, name: &str) -> String {
+        let (mut store, instance, _) = match self.instantiate().await {
+            Ok(parts) => parts,
+            Err(e) => return e,
         };
-        // Find exported function
-        let func = match instance.get_func(&mut store, "handle") {
-            Some(f) => f,
-            None => return "[WASM error] no exported 'handle' function".to_string(),
+        let Some(func) = instance.get_func(&mut store, name) else {
+            return format!("[wasm_plugin] no {} fn", name);
         };
+        let mut results = vec![wasmtime::Val::I32(0); func.ty(&store).results().len()];
+        match func.call_async(&mut store, &[], &mut results).await {
+            Ok(()) => match results.first() {
+                Some(wasmtime::Val::I32(code)) => format!("[wasm_plugin] {}: {}", name, code),
+                _ => format!("[wasm_plugin] {}: ok", name),
+            },
+            Err(e) => format!("[WASM error] {} failed: {}", name, e),
+        }
+    }
+}
+
+impl PluginLifecycle for WasmModule {
+    fn init(&mut self) -> String {
+        block_on(|| self.call_export("init"))
+    }
+    fn shutdown(&mut self) -> String {
+        block_on(|| self.call_export("shutdown"))
+    }
+    fn reload(&mut self) -> String {
+        match WasmModule::load(&self.path) {
+            Ok(new_mod) => {
+                *self = new_mod;
+                "[wasm_plugin] reload: success".to_string()
+            }
+            Err(e) => format!("[wasm_plugin] reload error: {}", e),
+        }
+    }
+}
+
+impl WasmModule {
+    /// Call the exported `name(ptr, len) -> ptr` with `input` written at
+    /// offset 100, reading the null-terminated string it points to. The
+    /// memory grows to fit inputs larger than its first page.
+    async fn call_str(&self, name: &str, input: &str) -> Result<String, String> {
+        use wasmtime::Val;
+        let (mut store, instance, memory) = self.instantiate().await?;
+        // Find exported function
+        let func = instance
+            .get_func(&mut store, name)
+            .ok_or_else(|| format!("[WASM error] no exported '{}' function", name))?;
         // Write input string to memory (at offset 100)
         let input_bytes = input.as_bytes();
         let offset = 100u32;
-        if let Err(e) = memory.write(&mut store, offset as usize, input_bytes) {
-            return format!("[WASM error] memory write: {}", e);
+        let needed = offset as u64 + input_bytes.len() as u64;
+        let size = memory.data_size(&store) as u64;
+        if needed > size {
+            let pages = (needed - size).div_ceil(WASM_PAGE_SIZE);
+            memory
+                .grow(&mut store, pages)
+                .map_err(|e| format!("[WASM error] memory grow: {}", e))?;
         }
-        // Call handle(ptr, len)
+        memory
+            .write(&mut store, offset as usize, input_bytes)
+            .map_err(|e| format!("[WASM error] memory write: {}", e))?;
+        // Call name(ptr, len)
         let mut results = [Val::I32(0)];
-        let result = func.call(
+        func.call_async(
             &mut store,
             &[Val::I32(offset as i32), Val::I32(input_bytes.len() as i32)],
             &mut results,
-        );
-        let out_ptr = match result {
-            Ok(()) => match results[0] {
-                Val::I32(ptr) => ptr as u32,
-                _ => return "[WASM error] unexpected return type".to_string(),
-            },
-            Err(e) => return format!("[WASM error] call failed: {}", e),
+        )
+        .await
+        .map_err(|e| format!("[WASM error] call failed: {}", e))?;
+        let out_ptr = match results[0] {
+            Val::I32(ptr) => ptr as u32,
+            _ => return Err("[WASM error] unexpected return type".to_string()),
         };
         // Read null-terminated string from memory at out_ptr
         let mut buf = Vec::new();
         let mut cur = out_ptr as usize;
-        loop {
-            let byte = match memory.data(&store).get(cur) {
-                Some(b) => *b,
-                None => break,
-            };
+        while let Some(&byte) = memory.data(&store).get(cur) {
             if byte == 0 {
                 break;
             }
             buf.push(byte);
             cur += 1;
         }
-        String::from_utf8_lossy(&buf).into_owned()
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    /// Modules exporting `handle_envelope` get the JSON envelope.
+    async fn respond(&self, req: &PluginRequest) -> PluginResponse {
+        if self.module.get_export("handle_envelope").is_none() {
+            let input = with_params(&req.request_line(), &req.params);
+            return PluginResponse::text(
+                self.call_str("handle", &input).await.unwrap_or_else(|e| e),
+            );
+        }
+        match self.call_str("handle_envelope", &req.to_json()).await {
+            Ok(output) => {
+                parse_response("wasm_plugin", &output).unwrap_or_else(PluginResponse::error)
+            }
+            Err(e) => PluginResponse::error(e),
+        }
+    }
+}
+
+impl DynamicModule for WasmModule {
+    fn handle(&self, input: &str) -> String {
+        block_on(|| self.call_str("handle", input)).unwrap_or_else(|e| e)
+    }
+
+    fn call(&self, req: &PluginRequest) -> PluginResponse {
+        block_on(|| self.respond(req))
+    }
+
+    fn call_blocking(
+        &self,
+        req: &PluginRequest,
+        rt: &Handle,
+        deadline: Instant,
+    ) -> Result<PluginResponse, TimedOut> {
+        rt.block_on(tokio::time::timeout_at(deadline, self.respond(req)))
+            .map_err(|_| TimedOut)
     }
 }
 
 /// Lua scripting module loader (rlua skeleton)
+///
+/// The script is compiled once and run in a pool of Lua states, each set up
+/// by running the script before it serves a request. States are reused, so
+/// globals one request sets may be seen by a later one. A script setting the
+/// global `shared_state = true` gets a single state instead, used by one
+/// request at a time, for counters and caches. The states are rebuilt when
+/// the script file changes.
+///
+/// Script functions run as coroutines, so the host calls below are async
+/// and a script waiting on one yields. Globals available:
+/// - `sleep(ms)`: the `sleep` host call.
 pub struct ScriptingModule {
-    script: String,
+    path: PathBuf,
+    current: RwLock<CurrentScript>,
+}
+
+/// The loaded version of a script file.
+struct CurrentScript {
+    /// Modification time of the file when last looked at, even if that
+    /// version failed to load.
+    modified: Option<SystemTime>,
+    states: Arc<LuaStates>,
+}
+
+/// The compiled script and the Lua states running it.
+struct LuaStates {
+    bytecode: Vec<u8>,
+    pool: LuaPool,
+}
+
+enum LuaPool {
+    /// States not serving a request.
+    Idle(Mutex<Vec<rlua::Lua>>),
+    Shared(tokio::sync::Mutex<rlua::Lua>),
+}
+
+/// Idle Lua states kept per script; more are dropped when their call ends.
+const LUA_POOL_SIZE: usize = 16;
+
+/// Instructions a Lua call runs between checks of its deadline.
+const LUA_DEADLINE_CHECK: u32 = 10_000;
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl LuaStates {
+    /// Compile the script at `path` and set up its first state.
+    fn load(path: &Path) -> std::io::Result<Self> {
+        let script = std::fs::read_to_string(path)?;
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+        let bytecode = rlua::Lua::new()
+            .load(&script)
+            .set_name(format!("@{}", path.display()))
+            .into_function()
+            .map_err(|e| invalid(format!("[Lua error] script load: {}", e)))?
+            .dump(false);
+        let mut states = LuaStates {
+            bytecode,
+            pool: LuaPool::Idle(Mutex::new(Vec::new())),
+        };
+        let first = states.new_state().map_err(invalid)?;
+        let shared = first
+            .globals()
+            .get::<_, Option<bool>>("shared_state")
+            .ok()
+            .flatten()
+            .unwrap_or(false);
+        states.pool = if shared {
+            LuaPool::Shared(tokio::sync::Mutex::new(first))
+        } else {
+            LuaPool::Idle(Mutex::new(vec![first]))
+        };
+        Ok(states)
+    }
+
+    /// A Lua state with the host calls and the script run in it.
+    fn new_state(&self) -> Result<rlua::Lua, String> {
+        let lua = rlua::Lua::new();
+        let sleep = lua
+            .create_async_function(|_, ms: i64| async move {
+                host_sleep(ms).await;
+                Ok(())
+            })
+            .map_err(|e| format!("[Lua error] host calls: {}", e))?;
+        lua.globals()
+            .set("sleep", sleep)
+            .map_err(|e| format!("[Lua error] host calls: {}", e))?;
+        lua.load(&self.bytecode)
+            .exec()
+            .map_err(|e| format!("[Lua error] script load: {}", e))?;
+        Ok(lua)
+    }
 }
 
 impl ScriptingModule {
     pub fn load<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
-        let script = std::fs::read_to_string(path)?;
-        Ok(ScriptingModule { script })
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let states = Arc::new(LuaStates::load(&path)?);
+        Ok(ScriptingModule {
+            path,
+            current: RwLock::new(CurrentScript { modified, states }),
+        })
+    }
+
+    /// The states of the script, rebuilt first if the file changed. When
+    /// the new version fails to load the old one is kept.
+    fn states(&self) -> Arc<LuaStates> {
+        let modified = modified(&self.path);
+        {
+            let current = self.current.read().unwrap();
+            if current.modified == modified {
+                return current.states.clone();
+            }
+        }
+        let mut current = self.current.write().unwrap();
+        if current.modified != modified {
+            current.modified = modified;
+            match LuaStates::load(&self.path) {
+                Ok(states) => {
+                    log::info!("[lua_plugin] {} changed, reloaded", self.path.display());
+                    current.states = Arc::new(states);
+                }
+                Err(e) => log::error!(
+                    "[lua_plugin] keeping the loaded version of {}: {}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+        current.states.clone()
+    }
+
+    /// Run `f` in a state of the script: an idle one, or the shared one
+    /// once it is free. A state whose call ran past `deadline` is dropped.
+    async fn with_state<T>(
+        &self,
+        deadline: Option<Instant>,
+        f: impl AsyncFnOnce(&rlua::Lua) -> T,
+    ) -> Result<T, String> {
+        let states = self.states();
+        match &states.pool {
+            LuaPool::Shared(lua) => Ok(f(&*lua.lock().await).await),
+            LuaPool::Idle(idle) => {
+                let reused = idle.lock().unwrap().pop();
+                let lua = match reused {
+                    Some(lua) => lua,
+                    None => states.new_state()?,
+                };
+                let out = f(&lua).await;
+                let mut idle = idle.lock().unwrap();
+                if deadline.is_none_or(|d| Instant::now() < d) && idle.len() < LUA_POOL_SIZE {
+                    idle.push(lua);
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    /// Run the script's optional global `init`/`shutdown` function.
+    async fn call_hook(&self, name: &str) -> String {
+        let hook =
+            async |lua: &rlua::Lua| match lua.globals().get::<_, Option<rlua::Function>>(name) {
+                Ok(Some(func)) => match call_lua::<_, rlua::Value>(lua, func, (), None).await {
+                    Ok(rlua::Value::String(s)) => {
+                        format!("[lua_plugin] {}: {}", name, s.to_str().unwrap_or(""))
+                    }
+                    Ok(_) => format!("[lua_plugin] {}: ok", name),
+                    Err(e) => format!("[Lua error] {}: {}", name, e),
+                },
+                Ok(None) => format!("[lua_plugin] no {} fn", name),
+                Err(e) => format!("[Lua error] {}: {}", name, e),
+            };
+        self.with_state(None, hook).await.unwrap_or_else(|e| e)
     }
 }
 
-impl DynamicModule for ScriptingModule {
-    fn handle(&self, input: &str) -> String {
-        use rlua::Lua;
-        let lua = Lua::new();
-        let script = &self.script;
-        if let Err(e) = lua.load(script).exec() {
-            return format!("[Lua error] script load: {}", e);
+impl PluginLifecycle for ScriptingModule {
+    fn init(&mut self) -> String {
+        block_on(|| self.call_hook("init"))
+    }
+    fn shutdown(&mut self) -> String {
+        block_on(|| self.call_hook("shutdown"))
+    }
+    fn reload(&mut self) -> String {
+        match ScriptingModule::load(&self.path) {
+            Ok(new_mod) => {
+                *self = new_mod;
+                "[lua_plugin] reload: success".to_string()
+            }
+            Err(e) => format!("[lua_plugin] reload error: {}", e),
         }
+    }
+}
+
+/// Call `func` as a coroutine, so it can await host calls, stopping it with
+/// an error once `deadline` has passed.
+async fn call_lua<'lua, A, R>(
+    lua: &'lua rlua::Lua,
+    func: rlua::Function<'lua>,
+    args: A,
+    deadline: Option<Instant>,
+) -> rlua::Result<R>
+where
+    A: rlua::IntoLuaMulti<'lua>,
+    R: rlua::FromLuaMulti<'lua> + 'lua,
+{
+    let thread = lua.create_thread(func)?;
+    if let Some(deadline) = deadline {
+        let triggers = rlua::HookTriggers::new().every_nth_instruction(LUA_DEADLINE_CHECK);
+        thread.set_hook(triggers, move |_, _| {
+            if Instant::now() >= deadline {
+                Err(rlua::Error::RuntimeError(TimedOut.to_string()))
+            } else {
+                Ok(())
+            }
+        });
+    }
+    thread.into_async(args).await
+}
+
+impl ScriptingModule {
+    /// Run the script's `handle(input, params)`; `params` is a Lua table of
+    /// the captured route params, or nil when there are none.
+    async fn call_handle(&self, input: &str, params: Option<&RouteParams>) -> String {
+        self.with_state(None, async |lua| {
+            Self::handle_in(lua, input, params, None).await
+        })
+        .await
+        .unwrap_or_else(|e| e)
+    }
+
+    async fn handle_in(
+        lua: &rlua::Lua,
+        input: &str,
+        params: Option<&RouteParams>,
+        deadline: Option<Instant>,
+    ) -> String {
         let func = match lua.globals().get::<_, rlua::Function>("handle") {
             Ok(f) => f,
             Err(e) => return format!("[Lua error] no 'handle' function: {}", e),
         };
-        match func.call::<_, rlua::Value>(input) {
+        let params = match params {
+            Some(params) => {
+                let table = match lua.create_table() {
+                    Ok(t) => t,
+                    Err(e) => return format!("[Lua error] params table: {}", e),
+                };
+                for (k, v) in params.iter() {
+                    if let Err(e) = table.set(k, v) {
+                        return format!("[Lua error] params table: {}", e);
+                    }
+                }
+                rlua::Value::Table(table)
+            }
+            None => rlua::Value::Nil,
+        };
+        match call_lua::<_, rlua::Value>(lua, func, (input, params), deadline).await {
             Ok(rlua::Value::String(s)) => s.to_str().unwrap_or("").to_string(),
             Ok(v) => format!("[Lua] Non-string return: {:?}", v),
             Err(e) => format!("[Lua error] call: {}", e),
         }
     }
+
+    /// Scripts defining `handle_request` get the envelope; others `handle`.
+    async fn respond(&self, req: &PluginRequest, deadline: Option<Instant>) -> PluginResponse {
+        let respond = async |lua: &rlua::Lua| match lua
+            .globals()
+            .get::<_, Option<rlua::Function>>("handle_request")
+        {
+            Ok(Some(func)) => call_lua_request(lua, func, req, deadline)
+                .await
+                .unwrap_or_else(|e| {
+                    PluginResponse::error(format!("[Lua error] handle_request: {}", e))
+                }),
+            Ok(None) => PluginResponse::text(
+                Self::handle_in(lua, &req.request_line(), Some(&req.params), deadline).await,
+            ),
+            Err(e) => PluginResponse::error(format!("[Lua error] handle_request: {}", e)),
+        };
+        self.with_state(deadline, respond)
+            .await
+            .unwrap_or_else(PluginResponse::error)
+    }
+}
+
+/// `handle_request(req)`: the envelope as a table with `headers` and
+/// `params` as name → value tables. The script returns a string for a 200
+/// response or a table with optional `status`, `headers` and `body`.
+async fn call_lua_request(
+    lua: &rlua::Lua,
+    func: rlua::Function<'_>,
+    req: &PluginRequest,
+    deadline: Option<Instant>,
+) -> rlua::Result<PluginResponse> {
+    let table = lua.create_table()?;
+    table.set("version", req.version)?;
+    table.set("method", req.method.as_str())?;
+    table.set("uri", req.uri.as_str())?;
+    let headers = lua.create_table()?;
+    for (name, value) in &req.headers {
+        // Repeated headers are joined, as HTTP allows for most of them.
+        let value = match headers.get::<_, Option<String>>(name.as_str())? {
+            Some(previous) => format!("{}, {}", previous, value),
+            None => value.clone(),
+        };
+        headers.set(name.as_str(), value)?;
+    }
+    table.set("headers", headers)?;
+    table.set("body", lua.create_string(&req.body)?)?;
+    table.set("client", req.client.as_deref())?;
+    let params = lua.create_table()?;
+    for (name, value) in req.params.iter() {
+        params.set(name, value)?;
+    }
+    table.set("params", params)?;
+
+    match call_lua::<_, rlua::Value>(lua, func, table, deadline).await? {
+        rlua::Value::String(s) => Ok(PluginResponse::text(s.to_str()?)),
+        rlua::Value::Table(t) => {
+            let mut resp = PluginResponse::text("");
+            if let Some(version) = t.get::<_, Option<u32>>("version")? {
+                resp.version = version;
+                resp.check_version().map_err(rlua::Error::RuntimeError)?;
+            }
+            if let Some(status) = t.get::<_, Option<u16>>("status")? {
+                resp.status = status;
+            }
+            if let Some(headers) = t.get::<_, Option<rlua::Table>>("headers")? {
+                for pair in headers.pairs::<String, String>() {
+                    resp.headers.push(pair?);
+                }
+            }
+            if let Some(body) = t.get::<_, Option<rlua::String>>("body")? {
+                resp.body = body.as_bytes().to_vec();
+            }
+            Ok(resp)
+        }
+        v => Err(rlua::Error::RuntimeError(format!(
+            "handle_request returned {}, expected a string or table",
+            v.type_name()
+        ))),
+    }
+}
+
+impl DynamicModule for ScriptingModule {
+    fn handle(&self, input: &str) -> String {
+        block_on(|| self.call_handle(input, None))
+    }
+
+    fn handle_with_params(&self, input: &str, params: &RouteParams) -> String {
+        block_on(|| self.call_handle(input, Some(params)))
+    }
+
+    fn call(&self, req: &PluginRequest) -> PluginResponse {
+        block_on(|| self.respond(req, None))
+    }
+
+    /// A script still running at `deadline` is stopped by its next host
+    /// call or instruction count check.
+    fn call_blocking(
+        &self,
+        req: &PluginRequest,
+        rt: &Handle,
+        deadline: Instant,
+    ) -> Result<PluginResponse, TimedOut> {
+        let resp = rt
+            .block_on(tokio::time::timeout_at(
+                deadline,
+                self.respond(req, Some(deadline)),
+            ))
+            .map_err(|_| TimedOut)?;
+        if Instant::now() >= deadline {
+            return Err(TimedOut);
+        }
+        Ok(resp)
+    }
 }
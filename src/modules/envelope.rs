@@ -0,0 +1,172 @@
+//! The request/response envelope exchanged with plugins.
+//!
+//! Native and WASM plugins get it as JSON; the body is base64 and headers
+//! are `[name, value]` pairs in request order:
+//!
+//! ```json
+//! {"version": 1, "method": "POST", "uri": "/users/7?full=1",
+//!  "headers": [["content-type", "text/plain"]], "body": "aGk=",
+//!  "client": "127.0.0.1:50412", "params": {"id": "7"}}
+//! ```
+//!
+//! and answer with `{"version": 1, "status": 201, "headers": [...],
+//! "body": "..."}`, where everything but `version` is optional. Lua plugins
+//! get and return the same fields as tables.
+use crate::router::RouteParams;
+use serde::{Deserialize, Serialize};
+pub use wigspace_plugin_api::ENVELOPE_VERSION;
+
+/// An HTTP request as a plugin sees it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginRequest {
+    pub version: u32,
+    pub method: String,
+    pub uri: String,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+    /// Client address, `ip:port`.
+    pub client: Option<String>,
+    /// Params the router captured, in pattern order.
+    #[serde(with = "params_map")]
+    pub params: RouteParams,
+}
+
+impl PluginRequest {
+    /// A request without headers, body or client.
+    pub fn new(method: &str, uri: &str) -> Self {
+        PluginRequest {
+            version: ENVELOPE_VERSION,
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            client: None,
+            params: RouteParams::new(),
+        }
+    }
+
+    /// `METHOD URI`, the input of plugins without envelope support.
+    pub fn request_line(&self) -> String {
+        format!("{} {}", self.method, self.uri)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a request always serialises")
+    }
+}
+
+/// What a plugin answers with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PluginResponse {
+    pub version: u32,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default, with = "base64_body")]
+    pub body: Vec<u8>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+impl PluginResponse {
+    /// A 200 response with `body` and no headers.
+    pub fn text(body: impl Into<String>) -> Self {
+        PluginResponse {
+            version: ENVELOPE_VERSION,
+            status: 200,
+            headers: Vec::new(),
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// A 500 response carrying `message`, for plugins that failed.
+    pub fn error(message: impl Into<String>) -> Self {
+        PluginResponse {
+            status: 500,
+            ..PluginResponse::text(message)
+        }
+    }
+
+    /// Parse a plugin's JSON answer, rejecting other envelope versions.
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let resp: PluginResponse =
+            serde_json::from_str(json).map_err(|e| format!("invalid response envelope: {}", e))?;
+        resp.check_version()?;
+        Ok(resp)
+    }
+
+    pub fn check_version(&self) -> Result<(), String> {
+        if self.version != ENVELOPE_VERSION {
+            return Err(format!(
+                "response envelope version {} is not supported (expected {})",
+                self.version, ENVELOPE_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
+mod base64_body {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(encoded)
+            .map_err(|e| serde::de::Error::custom(format!("body is not base64: {}", e)))
+    }
+}
+
+/// Route params as a JSON object, keeping their order.
+mod params_map {
+    use crate::router::RouteParams;
+    use serde::de::{MapAccess, Visitor};
+    use serde::ser::SerializeMap;
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(
+        params: &RouteParams,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(params.len()))?;
+        for (name, value) in params.iter() {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<RouteParams, D::Error> {
+        struct ParamsVisitor;
+
+        impl<'de> Visitor<'de> for ParamsVisitor {
+            type Value = RouteParams;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of route params")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<RouteParams, A::Error> {
+                let mut params = RouteParams::new();
+                while let Some((name, value)) = access.next_entry::<String, String>()? {
+                    params.push(name, value);
+                }
+                Ok(params)
+            }
+        }
+
+        deserializer.deserialize_map(ParamsVisitor)
+    }
+}
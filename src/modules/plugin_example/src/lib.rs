@@ -1,8 +1,17 @@
 //! Minimal C ABI plugin for dynamic_loader.rs
-//! Exports: handle_request(input_ptr, input_len) -> *mut c_char
+//! Exports: plugin_info, handle_request(input_ptr, input_len) -> *mut c_char
+//! and free_string
 
 use std::ffi::CString;
 use std::os::raw::{c_char, c_uchar};
+use wigspace_plugin_api::PluginInfo;
+
+static INFO: PluginInfo = PluginInfo::new(c"plugin_example", c"0.1.0", 0);
+
+#[no_mangle]
+pub extern "C" fn plugin_info() -> *const PluginInfo {
+    &INFO
+}
 
 // Note: If you expand the FFI interface, you may need to reintroduce CStr or c_void.
 
@@ -24,7 +33,7 @@ pub unsafe extern "C" fn handle_request(
     CString::new(response).unwrap().into_raw()
 }
 
-/// Optional: free string returned by handle_request
+/// Required: the server hands every string handle_request returns back here
 #[no_mangle]
 pub unsafe extern "C" fn free_string(ptr: *mut c_char) {
     if !ptr.is_null() {
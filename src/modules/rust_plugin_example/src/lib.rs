@@ -1,38 +1,19 @@
-#[no_mangle]
-pub extern "C" fn plugin_init() -> i32 {
-    // Inisialisasi resource jika perlu
-    42 // kode sukses dummy
-}
-
-#[no_mangle]
-pub extern "C" fn plugin_shutdown() -> i32 {
-    // Bersihkan resource jika perlu
-    24 // kode sukses dummy
-}
-/// Minimal Rust dylib plugin for DynamicModule FFI
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_void};
+//! Minimal Rust dylib plugin, written with wigspace-plugin-sdk
+use wigspace::{Request, Response};
+use wigspace_plugin_sdk as wigspace;
 
-#[repr(C)]
-pub struct PluginVTable {
-    pub handle: extern "C" fn(*const c_char) -> *mut c_char,
+#[wigspace::plugin(init = init, shutdown = shutdown)]
+fn handle(req: Request) -> Response {
+    Response::text(format!(
+        "[rust_plugin_example] got: {} {}",
+        req.method, req.uri
+    ))
 }
 
-extern "C" fn handle(_input: *const c_char) -> *mut c_char {
-    let response = "[rust_plugin] hello rust";
-    CString::new(response).unwrap().into_raw()
+fn init() -> i32 {
+    42
 }
 
-#[no_mangle]
-pub extern "C" fn get_plugin_vtable() -> *const PluginVTable {
-    &PLUGIN_VTABLE
-}
-
-static PLUGIN_VTABLE: PluginVTable = PluginVTable { handle };
-
-#[no_mangle]
-pub unsafe extern "C" fn free_string(ptr: *mut c_char) {
-    if !ptr.is_null() {
-        let _ = CString::from_raw(ptr);
-    }
+fn shutdown() -> i32 {
+    24
 }
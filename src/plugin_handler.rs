@@ -0,0 +1,333 @@
+//! Plugins as `Handler`s, so they are routed and wrapped in middleware like
+//! the built-in handlers.
+//!
+//! Calls run on tokio's blocking threads, at most `plugin_concurrency` at a
+//! time per plugin, and are answered 504 after `plugin_timeout`. A plugin
+//! is shut down by the `Unloader` once its handler and every call it started
+//! are gone, so a call still running after a reload never sees it shut down.
+use crate::config::Config;
+use crate::handler_trait::{
+    ConnectionInfo, Handler, HandlerFuture, RequestBody, ResponseBody, full,
+};
+use crate::modules::dynamic_loader::{DynamicModule, PluginLifecycle, TimedOut};
+use crate::modules::envelope::{PluginRequest, PluginResponse};
+use crate::router::RouteParams;
+use http_body_util::BodyExt;
+use hyper::{Request, Response, StatusCode};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{Semaphore, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// A loaded plugin of any kind.
+pub trait Plugin: DynamicModule + PluginLifecycle {}
+
+impl<T: DynamicModule + PluginLifecycle> Plugin for T {}
+
+/// A plugin shared between the routes using it and the reload coordinator.
+pub type SharedPlugin = Arc<RwLock<dyn Plugin>>;
+
+/// Requests with a larger body are answered 413 without calling the plugin.
+pub const MAX_BODY_SIZE: usize = 1 << 20;
+
+/// `plugin_timeout` when it is not set.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// `plugin_concurrency` when it is not set.
+pub const DEFAULT_CONCURRENCY: usize = 16;
+
+/// Calls a plugin with the request as a `PluginRequest` envelope and turns
+/// its `PluginResponse` into the HTTP response. Routes using the same plugin
+/// share one handler, and with it the plugin's concurrency limit.
+pub struct PluginHandler<M: ?Sized> {
+    module: Arc<RwLock<M>>,
+    slots: Arc<Semaphore>,
+    /// Shared with the calls in flight.
+    release: Option<Arc<Release>>,
+}
+
+/// A hook run when the last handler or call holding it is dropped.
+struct Release(Option<Box<dyn FnOnce() + Send + Sync>>);
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        if let Some(hook) = self.0.take() {
+            hook();
+        }
+    }
+}
+
+impl<M: ?Sized> PluginHandler<M> {
+    pub fn new(module: Arc<RwLock<M>>) -> Self {
+        PluginHandler {
+            module,
+            slots: Arc::new(Semaphore::new(DEFAULT_CONCURRENCY)),
+            release: None,
+        }
+    }
+
+    /// Run at most `limit` calls at once.
+    pub fn with_concurrency(mut self, limit: usize) -> Self {
+        self.slots = Arc::new(Semaphore::new(limit));
+        self
+    }
+
+    /// Run `hook` once this handler and every call it started are gone,
+    /// including calls still running after they timed out.
+    pub fn on_release(mut self, hook: impl FnOnce() + Send + Sync + 'static) -> Self {
+        self.release = Some(Arc::new(Release(Some(Box::new(hook)))));
+        self
+    }
+
+    pub fn module(&self) -> &Arc<RwLock<M>> {
+        &self.module
+    }
+}
+
+/// Shuts down the plugins handed to it, one at a time, each on a blocking
+/// thread and for at most `plugin_timeout`.
+#[derive(Clone)]
+pub struct Unloader {
+    tx: mpsc::UnboundedSender<SharedPlugin>,
+}
+
+impl Unloader {
+    /// Start the unloading task. It ends once every `Unloader` and every
+    /// `release` hook not run yet are dropped.
+    pub fn spawn(config: Arc<RwLock<Config>>) -> (Self, JoinHandle<()>) {
+        let (tx, mut rx) = mpsc::unbounded_channel::<SharedPlugin>();
+        let task = tokio::spawn(async move {
+            while let Some(plugin) = rx.recv().await {
+                let timeout = config
+                    .read()
+                    .unwrap()
+                    .plugin_timeout
+                    .unwrap_or(DEFAULT_TIMEOUT_SECS);
+                let shutdown =
+                    tokio::task::spawn_blocking(move || plugin.write().unwrap().shutdown());
+                match tokio::time::timeout(Duration::from_secs(timeout), shutdown).await {
+                    Ok(Ok(msg)) => log::info!("{}", msg),
+                    Ok(Err(e)) => log::error!("[plugin] shutdown failed: {}", e),
+                    Err(_) => log::error!("[plugin] shutdown timed out after {}s", timeout),
+                }
+            }
+        });
+        (Unloader { tx }, task)
+    }
+
+    /// A `PluginHandler::on_release` hook that shuts `plugin` down.
+    pub fn release(&self, plugin: SharedPlugin) -> impl FnOnce() + Send + Sync + 'static {
+        let tx = self.tx.clone();
+        move || {
+            // The task is gone only when the server is exiting.
+            let _ = tx.send(plugin);
+        }
+    }
+}
+
+/// The envelope for `req`, with its body read in full.
+pub async fn plugin_request(req: Request<RequestBody>) -> Result<PluginRequest, StatusCode> {
+    let (parts, mut body) = req.into_parts();
+    let mut bytes = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if let Ok(data) = frame.into_data() {
+            if bytes.len() + data.len() > MAX_BODY_SIZE {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            bytes.extend_from_slice(&data);
+        }
+    }
+    let mut envelope = PluginRequest::new(parts.method.as_str(), &parts.uri.to_string());
+    envelope.headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_string(), value)
+        })
+        .collect();
+    envelope.body = bytes;
+    envelope.client = parts
+        .extensions
+        .get::<ConnectionInfo>()
+        .map(|c| c.remote_addr.to_string());
+    if let Some(params) = parts.extensions.get::<RouteParams>() {
+        envelope.params = params.clone();
+    }
+    Ok(envelope)
+}
+
+/// The HTTP response for a plugin's answer; a status or header hyper
+/// rejects makes it a 500.
+pub fn plugin_response(resp: PluginResponse) -> Response<ResponseBody> {
+    let mut builder = Response::builder().status(resp.status);
+    for (name, value) in &resp.headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(full(resp.body)).unwrap_or_else(|e| {
+        log::error!("[plugin] invalid response: {}", e);
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(full("Invalid plugin response"))
+            .unwrap()
+    })
+}
+
+impl<M: DynamicModule + ?Sized + 'static> PluginHandler<M> {
+    /// Wait for a free slot and run the plugin on a blocking thread; `None`
+    /// when `deadline` passes first.
+    async fn run(&self, envelope: PluginRequest, deadline: Instant) -> Option<PluginResponse> {
+        let module = self.module.clone();
+        let slots = self.slots.clone();
+        let release = self.release.clone();
+        let call = async move {
+            let permit = slots.acquire_owned().await.expect("never closed");
+            let rt = Handle::current();
+            tokio::task::spawn_blocking(move || {
+                // Held until the plugin returns, even after a timeout.
+                let _release = release;
+                let _permit = permit;
+                module
+                    .read()
+                    .unwrap()
+                    .call_blocking(&envelope, &rt, deadline)
+            })
+            .await
+        };
+        match tokio::time::timeout_at(deadline, call).await {
+            Ok(Ok(Ok(resp))) => Some(resp),
+            Ok(Ok(Err(TimedOut))) | Err(_) => None,
+            Ok(Err(e)) => Some(PluginResponse::error(format!("plugin call failed: {}", e))),
+        }
+    }
+}
+
+impl<M: DynamicModule + ?Sized + 'static> Handler for PluginHandler<M> {
+    fn handle<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let envelope = match plugin_request(req).await {
+                Ok(envelope) => envelope,
+                Err(status) => {
+                    return Ok(Response::builder()
+                        .status(status)
+                        .body(full(status.canonical_reason().unwrap_or("")))
+                        .unwrap());
+                }
+            };
+            let timeout = config
+                .read()
+                .unwrap()
+                .plugin_timeout
+                .unwrap_or(DEFAULT_TIMEOUT_SECS);
+            let line = envelope.request_line();
+            let deadline = Instant::now() + Duration::from_secs(timeout);
+            let Some(resp) = self.run(envelope, deadline).await else {
+                log::error!("[plugin] {}: timed out after {}s", line, timeout);
+                return Ok(Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .body(full("Plugin timed out"))
+                    .unwrap());
+            };
+            if resp.status >= 500 {
+                log::error!("[plugin] {}: {}", line, String::from_utf8_lossy(&resp.body));
+            }
+            Ok(plugin_response(resp))
+        })
+    }
+}
+
+/// The lifecycle hook a `LifecycleHandler` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleAction {
+    Init,
+    Shutdown,
+    Reload,
+}
+
+/// Loads a plugin into an empty slot on `Reload`.
+pub type PluginLoader<M> = Arc<dyn Fn() -> Result<M, String> + Send + Sync>;
+
+/// Runs a lifecycle hook of the plugin in `slot` and answers with its
+/// message; 500 when the slot is empty, unless a `Reload` can load it.
+pub struct LifecycleHandler<M> {
+    name: &'static str,
+    slot: Arc<Mutex<Option<M>>>,
+    action: LifecycleAction,
+    loader: Option<PluginLoader<M>>,
+}
+
+impl<M> Clone for LifecycleHandler<M> {
+    fn clone(&self) -> Self {
+        LifecycleHandler {
+            name: self.name,
+            slot: self.slot.clone(),
+            action: self.action,
+            loader: self.loader.clone(),
+        }
+    }
+}
+
+impl<M> LifecycleHandler<M> {
+    /// `name` prefixes the messages, e.g. `[rust_plugin] loaded`.
+    pub fn new(name: &'static str, slot: Arc<Mutex<Option<M>>>, action: LifecycleAction) -> Self {
+        LifecycleHandler {
+            name,
+            slot,
+            action,
+            loader: None,
+        }
+    }
+
+    pub fn with_loader(mut self, loader: PluginLoader<M>) -> Self {
+        self.loader = Some(loader);
+        self
+    }
+}
+
+impl<M: PluginLifecycle> LifecycleHandler<M> {
+    fn run(&self) -> Result<String, String> {
+        let mut slot = self.slot.lock().unwrap();
+        match (slot.as_mut(), self.action, &self.loader) {
+            (Some(plugin), LifecycleAction::Init, _) => Ok(plugin.init()),
+            (Some(plugin), LifecycleAction::Shutdown, _) => Ok(plugin.shutdown()),
+            (Some(plugin), LifecycleAction::Reload, _) => Ok(plugin.reload()),
+            (None, LifecycleAction::Reload, Some(load)) => match load() {
+                Ok(plugin) => {
+                    *slot = Some(plugin);
+                    Ok(format!("[{}] loaded", self.name))
+                }
+                Err(e) => Err(format!("[{}] reload error: {}", self.name, e)),
+            },
+            (None, _, _) => Err(format!("[{}] not loaded", self.name)),
+        }
+    }
+}
+
+impl<M: PluginLifecycle + Send + 'static> Handler for LifecycleHandler<M> {
+    fn handle<'a>(
+        &'a self,
+        _req: Request<RequestBody>,
+        _config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        let this = self.clone();
+        Box::pin(async move {
+            // Loading and the hooks run plugin code, kept off the runtime.
+            let result = tokio::task::spawn_blocking(move || this.run())
+                .await
+                .unwrap_or_else(|e| Err(format!("[{}] {}", self.name, e)));
+            Ok(match result {
+                Ok(msg) => Response::new(full(msg)),
+                Err(msg) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(full(msg))
+                    .unwrap(),
+            })
+        })
+    }
+}
@@ -0,0 +1,398 @@
+//! Reverse proxy handler for `proxy_pass`.
+//!
+//! Request and response bodies are streamed through without buffering. The
+//! upstream URL and timeouts are read from the live config on every request,
+//! so hot-reloaded values apply to the next request. `proxy_pass` may name an
+//! upstream group (see `upstream`) instead of a single host.
+use crate::config::Config;
+use crate::handler_trait::{
+    BoxError, ConnectionInfo, Handler, HandlerFuture, RequestBody, ResponseBody, full,
+};
+use crate::upstream::{ActiveGuard, UpstreamPool, Upstreams};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use hyper::header::{HOST, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+pub const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_READ_TIMEOUT_SECS: u64 = 60;
+
+/// Hop-by-hop headers (RFC 9110 section 7.6.1) that must not be forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Body type sent upstream; boxed so retries can send an empty body.
+type ProxyBody = UnsyncBoxBody<Bytes, BoxError>;
+
+pub struct ProxyHandler {
+    client: Client<HttpConnector, ProxyBody>,
+    upstreams: RwLock<Arc<Upstreams>>,
+}
+
+impl ProxyHandler {
+    /// Build a proxy whose pooled client uses `connect_timeout` for new
+    /// upstream connections.
+    pub fn new(connect_timeout: Duration) -> Self {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(connect_timeout));
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+        ProxyHandler {
+            client,
+            upstreams: RwLock::new(Arc::new(Upstreams::default())),
+        }
+    }
+
+    /// Build a proxy using `proxy_connect_timeout` and `upstreams` from `config`.
+    pub fn from_config(config: &Config) -> Self {
+        let secs = config
+            .proxy_connect_timeout
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS);
+        let proxy = Self::new(Duration::from_secs(secs));
+        proxy.update_upstreams(config);
+        proxy
+    }
+
+    /// Swap in the upstream groups from a reloaded config. Requests already
+    /// in flight keep the groups they started with.
+    pub fn update_upstreams(&self, config: &Config) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let next = Upstreams::from_config(config, Some(&upstreams));
+        *upstreams = Arc::new(next);
+    }
+
+    pub fn upstreams(&self) -> Arc<Upstreams> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    /// Send one request, mapping failures to 502 or 504.
+    async fn send(
+        &self,
+        req: Request<ProxyBody>,
+        read_timeout: Duration,
+    ) -> Result<Response<Incoming>, StatusCode> {
+        let target = req.uri().clone();
+        match tokio::time::timeout(read_timeout, self.client.request(req)).await {
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(e)) => {
+                log::error!("[proxy] upstream {} failed: {:?}", target, e);
+                if is_timeout(&e) {
+                    Err(StatusCode::GATEWAY_TIMEOUT)
+                } else {
+                    Err(StatusCode::BAD_GATEWAY)
+                }
+            }
+            Err(_) => {
+                log::error!("[proxy] upstream {} timed out", target);
+                Err(StatusCode::GATEWAY_TIMEOUT)
+            }
+        }
+    }
+
+    /// Forward to a backend of `pool`. Idempotent requests without a body are
+    /// retried on the next backend when one fails; streamed bodies cannot be
+    /// replayed, so other requests get a single attempt.
+    async fn forward_to_pool(
+        &self,
+        req: Request<RequestBody>,
+        pool: Arc<UpstreamPool>,
+        read_timeout: Duration,
+    ) -> Response<ResponseBody> {
+        let (parts, body) = req.into_parts();
+        let retryable = parts.method.is_idempotent() && body.is_end_stream();
+        let client_ip = parts
+            .extensions
+            .get::<ConnectionInfo>()
+            .map(|info| info.remote_addr.ip());
+        let key = pool.hash_value(&parts.headers, client_ip);
+        let mut body = Some(body.boxed_unsync());
+        let mut tried = Vec::new();
+        loop {
+            let Some(index) = pool.pick(key.as_deref(), &tried) else {
+                log::error!("[proxy] no live upstreams in {}", pool.name);
+                return error_response(StatusCode::BAD_GATEWAY, "no live upstreams");
+            };
+            tried.push(index);
+            let backend = pool.backend(index).clone();
+            let mut uri = parts.uri.clone().into_parts();
+            uri.authority = match backend.address.parse() {
+                Ok(authority) => Some(authority),
+                Err(e) => {
+                    log::error!("[proxy] invalid backend address {}: {}", backend.address, e);
+                    return error_response(StatusCode::BAD_GATEWAY, "invalid upstream");
+                }
+            };
+            let body = body
+                .take()
+                .unwrap_or_else(|| Empty::new().map_err(|never| match never {}).boxed_unsync());
+            let mut attempt = Request::new(body);
+            *attempt.method_mut() = parts.method.clone();
+            *attempt.uri_mut() = Uri::from_parts(uri).expect("valid upstream uri");
+            *attempt.version_mut() = parts.version;
+            *attempt.headers_mut() = parts.headers.clone();
+
+            let guard = backend.track();
+            match self.send(attempt, read_timeout).await {
+                Ok(resp) => {
+                    backend.record_success();
+                    return proxied_response(resp, read_timeout, Some(guard));
+                }
+                Err(status) => {
+                    backend.record_failure(pool.max_fails, pool.fail_timeout);
+                    if !retryable || tried.len() >= pool.len() {
+                        return error_response(status, "upstream request failed");
+                    }
+                    log::info!(
+                        "[proxy] retrying {} on next backend of {}",
+                        parts.uri,
+                        pool.name
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Handler for ProxyHandler {
+    fn handle<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let (upstream, read_timeout) = {
+                let config_read = config.read().unwrap();
+                (
+                    config_read.proxy_pass.clone(),
+                    config_read
+                        .proxy_read_timeout
+                        .unwrap_or(DEFAULT_READ_TIMEOUT_SECS),
+                )
+            };
+            let Some(upstream) = upstream else {
+                return Ok(error_response(
+                    StatusCode::BAD_GATEWAY,
+                    "proxy_pass is not configured",
+                ));
+            };
+            let upstream: Uri = match upstream.parse() {
+                Ok(uri) => uri,
+                Err(e) => {
+                    log::error!("[proxy] invalid proxy_pass {}: {}", upstream, e);
+                    return Ok(error_response(StatusCode::BAD_GATEWAY, "invalid upstream"));
+                }
+            };
+            let read_timeout = Duration::from_secs(read_timeout);
+            let req = match rewrite_request(req, &upstream) {
+                Ok(req) => req,
+                Err(e) => {
+                    log::error!("[proxy] cannot build upstream request: {}", e);
+                    return Ok(error_response(StatusCode::BAD_GATEWAY, "invalid upstream"));
+                }
+            };
+            // `http://<name>` without a port names an upstream group.
+            let pool = match (upstream.host(), upstream.port()) {
+                (Some(host), None) => self.upstreams().get(host).cloned(),
+                _ => None,
+            };
+            if let Some(pool) = pool {
+                return Ok(self.forward_to_pool(req, pool, read_timeout).await);
+            }
+            let req = req.map(|body| body.boxed_unsync());
+            match self.send(req, read_timeout).await {
+                Ok(resp) => Ok(proxied_response(resp, read_timeout, None)),
+                Err(status) => Ok(error_response(status, "upstream request failed")),
+            }
+        })
+    }
+}
+
+fn proxied_response(
+    resp: Response<Incoming>,
+    read_timeout: Duration,
+    guard: Option<ActiveGuard>,
+) -> Response<ResponseBody> {
+    let (mut parts, body) = resp.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    let body = TimeoutBody::new(body, read_timeout, guard).boxed();
+    Response::from_parts(parts, body)
+}
+
+/// Point `req` at `upstream` and set the forwarding headers.
+///
+/// The upstream path (if any) is prepended to the request path, so
+/// `proxy_pass: http://backend/api` maps `/users?x=1` to `/api/users?x=1`.
+pub fn rewrite_request<B>(mut req: Request<B>, upstream: &Uri) -> Result<Request<B>, BoxError> {
+    let authority = upstream
+        .authority()
+        .ok_or("proxy_pass has no host")?
+        .clone();
+    if upstream.scheme_str().is_some_and(|s| s != "http") {
+        return Err(format!("unsupported upstream scheme in {}", upstream).into());
+    }
+    let base = upstream.path().trim_end_matches('/');
+    let path_and_query = match req.uri().path_and_query() {
+        Some(pq) => format!("{}{}", base, pq.as_str()),
+        None => format!("{}/", base),
+    };
+    let uri = Uri::builder()
+        .scheme("http")
+        .authority(authority.clone())
+        .path_and_query(path_and_query)
+        .build()?;
+
+    // HTTP/2 clients send `:authority` instead of `Host`.
+    let original_host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| req.uri().authority().map(|a| a.to_string()));
+    let info = req.extensions().get::<ConnectionInfo>().copied();
+    let client_ip = info.map(|info| info.remote_addr.ip());
+    let proto = if info.is_some_and(|info| info.tls) {
+        "https"
+    } else {
+        "http"
+    };
+
+    *req.uri_mut() = uri;
+    *req.version_mut() = hyper::Version::HTTP_11;
+    let headers = req.headers_mut();
+    strip_hop_by_hop(headers);
+    headers.insert(HOST, HeaderValue::from_str(authority.as_str())?);
+    if let Some(ip) = client_ip {
+        let xff = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(prev) => format!("{}, {}", prev, ip),
+            None => ip.to_string(),
+        };
+        headers.insert("x-forwarded-for", HeaderValue::from_str(&xff)?);
+    }
+    headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+
+    let mut forwarded = Vec::new();
+    if let Some(ip) = client_ip {
+        if ip.is_ipv6() {
+            forwarded.push(format!("for=\"[{}]\"", ip));
+        } else {
+            forwarded.push(format!("for={}", ip));
+        }
+    }
+    if let Some(host) = original_host {
+        forwarded.push(format!("host=\"{}\"", host));
+    }
+    forwarded.push(format!("proto={}", proto));
+    let element = forwarded.join(";");
+    let value = match headers.get("forwarded").and_then(|v| v.to_str().ok()) {
+        Some(prev) => format!("{}, {}", prev, element),
+        None => element,
+    };
+    headers.insert("forwarded", HeaderValue::from_str(&value)?);
+    Ok(req)
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Headers named in `Connection` are hop-by-hop as well.
+    let listed: Vec<HeaderName> = headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+fn is_timeout(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>()
+            && io.kind() == std::io::ErrorKind::TimedOut
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+fn error_response(status: StatusCode, msg: &'static str) -> Response<ResponseBody> {
+    Response::builder().status(status).body(full(msg)).unwrap()
+}
+
+/// Body wrapper that fails if the upstream goes quiet for longer than the
+/// read timeout between two frames. It also holds the backend's active
+/// request guard, so least-connections counts cover the whole response.
+struct TimeoutBody<B> {
+    inner: B,
+    timeout: Duration,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+    _guard: Option<ActiveGuard>,
+}
+
+impl<B> TimeoutBody<B> {
+    fn new(inner: B, timeout: Duration, guard: Option<ActiveGuard>) -> Self {
+        TimeoutBody {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            _guard: guard,
+        }
+    }
+}
+
+impl<B> Body for TimeoutBody<B>
+where
+    B: Body + Unpin,
+    B::Error: Into<BoxError>,
+{
+    type Data = B::Data;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(frame) => {
+                let deadline = tokio::time::Instant::now() + this.timeout;
+                this.sleep.as_mut().reset(deadline);
+                Poll::Ready(frame.map(|f| f.map_err(Into::into)))
+            }
+            Poll::Pending => match this.sleep.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Some(Err("upstream read timed out".into()))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
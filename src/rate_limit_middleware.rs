@@ -0,0 +1,103 @@
+use crate::handler_trait::{ConnectionInfo, Context, RequestBody, full};
+use crate::middleware_trait::{Flow, Middleware, MiddlewareFuture};
+use hyper::header::RETRY_AFTER;
+use hyper::{Request, Response, StatusCode};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Clients tracked before idle ones are forgotten.
+const MAX_CLIENTS: usize = 10_000;
+
+/// `rate_limit` options.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitOptions {
+    /// Sustained requests per second per client address.
+    #[serde(deserialize_with = "crate::config::scalar")]
+    pub requests_per_second: f64,
+    /// Requests a client may send at once (default: one second's worth).
+    #[serde(default, deserialize_with = "crate::config::opt_scalar")]
+    pub burst: Option<u32>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client address; requests over the limit get 429.
+pub struct RateLimitMiddleware {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(options: RateLimitOptions) -> Result<Self, String> {
+        let rate = options.requests_per_second;
+        if !(rate.is_finite() && rate > 0.0) {
+            return Err("requests_per_second must be positive".to_string());
+        }
+        let burst = match options.burst {
+            Some(0) => return Err("burst must be at least 1".to_string()),
+            Some(burst) => burst as f64,
+            None => rate.ceil(),
+        };
+        Ok(RateLimitMiddleware {
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Take a token for `client`, or return the seconds until one is free.
+    pub fn take(&self, client: Option<IpAddr>, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        }
+    }
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn before<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        _ctx: &'a Context,
+    ) -> MiddlewareFuture<'a, Flow> {
+        let client = req
+            .extensions()
+            .get::<ConnectionInfo>()
+            .map(|info| info.remote_addr.ip());
+        let flow = match self.take(client, Instant::now()) {
+            Ok(()) => Flow::Continue(req),
+            Err(retry_after) => Flow::Respond(
+                Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, retry_after.max(1))
+                    .body(full("Too Many Requests"))
+                    .unwrap(),
+            ),
+        };
+        Box::pin(async move { flow })
+    }
+}
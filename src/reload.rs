@@ -0,0 +1,237 @@
+//! Hot-reload bookkeeping: which plugins survive a reload and which
+//! listeners have to be bound or closed.
+//!
+//! The server rebuilds its routing table from the new config, asking the
+//! `PluginCache` for every plugin. Files that did not change keep their
+//! loaded instance; `sweep` then hands back the plugins the new table no
+//! longer uses so they can be shut down.
+//!
+//! Reloads are triggered by SIGHUP and by `ConfigWatcher`, which waits for
+//! the config files to settle before asking for one.
+use crate::config::ConfigSources;
+use crate::vhost::Listen;
+use notify::event::ModifyKind;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, mpsc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the config files must be left alone before a reload.
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+struct Cached<P> {
+    modified: Option<SystemTime>,
+    plugin: P,
+    used: bool,
+}
+
+/// Loaded plugins by file path, shared between routing table builds.
+pub struct PluginCache<P> {
+    entries: HashMap<PathBuf, Cached<P>>,
+    /// Instances replaced by a newer version of their file since the last
+    /// `sweep`.
+    replaced: Vec<P>,
+}
+
+impl<P> Default for PluginCache<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P> PluginCache<P> {
+    pub fn new() -> Self {
+        PluginCache {
+            entries: HashMap::new(),
+            replaced: Vec::new(),
+        }
+    }
+
+    /// Forget every plugin, returning them to be shut down.
+    pub fn drain(&mut self) -> Vec<P> {
+        let mut all = std::mem::take(&mut self.replaced);
+        all.extend(self.entries.drain().map(|(_, cached)| cached.plugin));
+        all
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<P: Clone> PluginCache<P> {
+    /// The plugin at `path`: the cached instance if the file is unchanged,
+    /// otherwise a fresh one from `load`. When a changed file fails to load
+    /// the previous instance is kept.
+    pub fn get_or_load<F>(&mut self, path: &Path, load: F) -> Option<P>
+    where
+        F: FnOnce(&Path) -> Option<P>,
+    {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if let Some(cached) = self.entries.get_mut(path)
+            && cached.modified == modified
+        {
+            cached.used = true;
+            return Some(cached.plugin.clone());
+        }
+        let Some(plugin) = load(path) else {
+            let cached = self.entries.get_mut(path)?;
+            log::error!(
+                "[hot-reload] keeping the loaded version of {}",
+                path.display()
+            );
+            cached.used = true;
+            return Some(cached.plugin.clone());
+        };
+        let fresh = Cached {
+            modified,
+            plugin: plugin.clone(),
+            used: true,
+        };
+        if let Some(old) = self.entries.insert(path.to_path_buf(), fresh) {
+            self.replaced.push(old.plugin);
+        }
+        Some(plugin)
+    }
+
+    /// Forget the plugins no build asked for since the last sweep, and the
+    /// replaced ones, returning them to be shut down.
+    pub fn sweep(&mut self) -> Vec<P> {
+        let mut removed = std::mem::take(&mut self.replaced);
+        self.entries.retain(|_, cached| {
+            if !cached.used {
+                removed.push(cached.plugin.clone());
+            }
+            std::mem::replace(&mut cached.used, false)
+        });
+        removed
+    }
+}
+
+/// Listeners to close and to bind when going from `old` to `new`. An address
+/// that switches between plain and ssl is in both lists.
+pub fn diff_listens(old: &[Listen], new: &[Listen]) -> (Vec<Listen>, Vec<Listen>) {
+    let removed = old.iter().filter(|l| !new.contains(l)).copied().collect();
+    let added = new.iter().filter(|l| !old.contains(l)).copied().collect();
+    (removed, added)
+}
+
+/// Reload counters and the outcome of the latest reload.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReloadStatus {
+    /// Reloads attempted since startup.
+    pub reloads: u64,
+    pub failures: u64,
+    /// Unix time of the latest reload.
+    pub last_reload: Option<u64>,
+    /// Why the latest reload failed; `None` if it succeeded.
+    pub last_error: Option<String>,
+}
+
+impl ReloadStatus {
+    pub fn record(&mut self, result: Result<(), String>) {
+        self.reloads += 1;
+        self.last_reload = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs());
+        self.last_error = result.err();
+        if self.last_error.is_some() {
+            self.failures += 1;
+        }
+    }
+}
+
+/// Watches the config file and its includes. Their directories are watched
+/// rather than the files, so a file replaced by a rename is still seen.
+pub struct ConfigWatcher {
+    watcher: RecommendedWatcher,
+    sources: Arc<RwLock<ConfigSources>>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+impl ConfigWatcher {
+    /// Call `on_change` once the files of `sources` were modified, created
+    /// or renamed into place and then left alone for `debounce`.
+    pub fn new<F>(sources: ConfigSources, debounce: Duration, on_change: F) -> notify::Result<Self>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let shared = Arc::new(RwLock::new(ConfigSources::default()));
+        let filter = shared.clone();
+        let (tx, rx) = mpsc::channel();
+        let watcher = RecommendedWatcher::new(
+            move |res: notify::Result<notify::Event>| match res {
+                Ok(event) => {
+                    let sources = filter.read().unwrap();
+                    if is_change(&event.kind) && event.paths.iter().any(|p| sources.matches(p)) {
+                        let _ = tx.send(());
+                    }
+                }
+                Err(e) => log::error!("[hot-reload] watch error: {}", e),
+            },
+            notify::Config::default(),
+        )?;
+        // Ends when the watcher, and with it the sender, is dropped.
+        std::thread::spawn(move || {
+            while rx.recv().is_ok() {
+                loop {
+                    match rx.recv_timeout(debounce) {
+                        Ok(()) => continue,
+                        Err(mpsc::RecvTimeoutError::Timeout) => break,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                on_change();
+            }
+        });
+        let mut config_watcher = ConfigWatcher {
+            watcher,
+            sources: shared,
+            dirs: BTreeSet::new(),
+        };
+        config_watcher.update(sources);
+        Ok(config_watcher)
+    }
+
+    /// Follow a new set of files, e.g. after the includes changed.
+    pub fn update(&mut self, sources: ConfigSources) {
+        let wanted = sources.dirs();
+        for dir in self.dirs.difference(&wanted) {
+            let _ = self.watcher.unwatch(dir);
+        }
+        self.dirs.retain(|dir| wanted.contains(dir));
+        for dir in wanted {
+            if self.dirs.contains(&dir) {
+                continue;
+            }
+            match self.watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.dirs.insert(dir);
+                }
+                Err(e) => log::error!("[hot-reload] watching {}: {}", dir.display(), e),
+            }
+        }
+        *self.sources.write().unwrap() = sources;
+    }
+
+    pub fn sources(&self) -> ConfigSources {
+        self.sources.read().unwrap().clone()
+    }
+}
+
+/// Writes, creations and renames; not reads or attribute changes.
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        _ => false,
+    }
+}
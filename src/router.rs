@@ -0,0 +1,397 @@
+//! Request router: exact, prefix, wildcard (`/api/*`), named parameter
+//! (`/users/:id`) and regex routes, each with an optional HTTP method filter.
+//!
+//! Routes are tried in the order they were added; the first route whose
+//! pattern and method filter both accept the request wins.
+use crate::config::{Config, RouteConfig};
+use hyper::Method;
+use regex::Regex;
+use std::fmt;
+
+/// Parameters captured while matching a route, in pattern order.
+///
+/// The router also inserts this into the request extensions so `Handler`
+/// implementations can read it with `req.extensions().get::<RouteParams>()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteParams(Vec<(String, String)>);
+
+impl RouteParams {
+    pub fn new() -> Self {
+        RouteParams(Vec::new())
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.0.push((name.into(), value.into()));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Renders as space separated `name=value` pairs.
+impl fmt::Display for RouteParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (k, v)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    InvalidRegex(regex::Error),
+    InvalidMethod(String),
+    InvalidPattern(String),
+    InvalidTarget,
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::InvalidRegex(e) => write!(f, "invalid route regex: {}", e),
+            RouteError::InvalidMethod(m) => write!(f, "invalid HTTP method in route: {}", m),
+            RouteError::InvalidPattern(p) => write!(f, "invalid route pattern: {}", p),
+            RouteError::InvalidTarget => {
+                write!(f, "route needs exactly one of plugin or handler")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    /// Segment pattern; `wildcard` names the parameter that captures the rest
+    /// of the path when the pattern ends in `*` or `*name`.
+    Segments {
+        segments: Vec<Segment>,
+        wildcard: Option<String>,
+    },
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn parse_path(path: &str) -> Result<Self, RouteError> {
+        if !path.starts_with('/') {
+            return Err(RouteError::InvalidPattern(path.to_string()));
+        }
+        if !path.contains(':') && !path.contains('*') {
+            return Ok(Pattern::Exact(path.to_string()));
+        }
+        let parts: Vec<&str> = path[1..].split('/').collect();
+        let mut segments = Vec::new();
+        let mut wildcard = None;
+        for (i, part) in parts.iter().enumerate() {
+            if let Some(name) = part.strip_prefix('*') {
+                if i != parts.len() - 1 {
+                    return Err(RouteError::InvalidPattern(path.to_string()));
+                }
+                let name = if name.is_empty() { "*" } else { name };
+                wildcard = Some(name.to_string());
+            } else if let Some(name) = part.strip_prefix(':') {
+                if name.is_empty() {
+                    return Err(RouteError::InvalidPattern(path.to_string()));
+                }
+                segments.push(Segment::Param(name.to_string()));
+            } else {
+                segments.push(Segment::Static(part.to_string()));
+            }
+        }
+        Ok(Pattern::Segments { segments, wildcard })
+    }
+
+    fn matches(&self, path: &str) -> Option<RouteParams> {
+        match self {
+            Pattern::Exact(p) => (p == path).then(RouteParams::new),
+            Pattern::Prefix(p) => {
+                let boundary = p.ends_with('/')
+                    || path.len() == p.len()
+                    || path.as_bytes().get(p.len()) == Some(&b'/');
+                (path.starts_with(p.as_str()) && boundary).then(RouteParams::new)
+            }
+            Pattern::Segments { segments, wildcard } => {
+                let mut params = RouteParams::new();
+                let mut rest = path.strip_prefix('/')?;
+                for segment in segments {
+                    let (part, tail) = match rest.find('/') {
+                        Some(i) => (&rest[..i], &rest[i + 1..]),
+                        None => (rest, ""),
+                    };
+                    match segment {
+                        Segment::Static(s) if s == part => {}
+                        Segment::Static(_) => return None,
+                        Segment::Param(_) if part.is_empty() => return None,
+                        Segment::Param(name) => params.push(name.as_str(), part),
+                    }
+                    rest = tail;
+                }
+                // Anything left over must be captured by a wildcard.
+                match wildcard {
+                    Some(name) => params.push(name.as_str(), rest),
+                    None if !rest.is_empty() => return None,
+                    None => {}
+                }
+                Some(params)
+            }
+            Pattern::Regex(re) => {
+                let caps = re.captures(path)?;
+                let mut params = RouteParams::new();
+                for (i, name) in re.capture_names().enumerate().skip(1) {
+                    if let Some(m) = caps.get(i) {
+                        match name {
+                            Some(name) => params.push(name, m.as_str()),
+                            None => params.push(i.to_string(), m.as_str()),
+                        }
+                    }
+                }
+                Some(params)
+            }
+        }
+    }
+}
+
+struct Route<T> {
+    pattern: Pattern,
+    methods: Option<Vec<Method>>,
+    target: T,
+}
+
+/// Result of a successful route lookup.
+pub struct RouteMatch<'a, T> {
+    pub target: &'a T,
+    pub params: RouteParams,
+}
+
+pub enum RouteLookup<'a, T> {
+    Found(RouteMatch<'a, T>),
+    /// A route matched the path but none accepted the method; carries the
+    /// methods that would have been accepted, for the `Allow` header.
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// What a configured route points at, before the caller resolves it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteTarget {
+    /// Plugin file name, relative to `plugins_dir`.
+    Plugin(String),
+    /// Name of a built-in handler, e.g. `static`.
+    Handler(String),
+}
+
+pub struct Router<T> {
+    routes: Vec<Route<T>>,
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Self {
+        Router { routes: Vec::new() }
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a route from a path pattern: `/exact`, `/users/:id`, `/api/*` or
+    /// `/files/*path`.
+    pub fn add(
+        &mut self,
+        path: &str,
+        methods: Option<&[String]>,
+        target: T,
+    ) -> Result<(), RouteError> {
+        let pattern = Pattern::parse_path(path)?;
+        self.push(pattern, methods, target)
+    }
+
+    /// Add a route matching `prefix` and everything below it on a segment
+    /// boundary, so `/plugin` matches `/plugin` and `/plugin/foo` but not
+    /// `/pluginx`.
+    pub fn add_prefix(
+        &mut self,
+        prefix: &str,
+        methods: Option<&[String]>,
+        target: T,
+    ) -> Result<(), RouteError> {
+        if !prefix.starts_with('/') {
+            return Err(RouteError::InvalidPattern(prefix.to_string()));
+        }
+        self.push(Pattern::Prefix(prefix.to_string()), methods, target)
+    }
+
+    /// Add a regex route, which must match the whole path. Named groups become
+    /// named params, unnamed groups are exposed by their index (`"1"`, `"2"`,
+    /// ...).
+    pub fn add_regex(
+        &mut self,
+        re: &str,
+        methods: Option<&[String]>,
+        target: T,
+    ) -> Result<(), RouteError> {
+        let re = Regex::new(&format!("^(?:{})$", re)).map_err(RouteError::InvalidRegex)?;
+        self.push(Pattern::Regex(re), methods, target)
+    }
+
+    /// Add one `routes:` entry by its `path`, `prefix` or `regex`.
+    fn add_route(&mut self, route: &RouteConfig, target: T) -> Result<(), RouteError> {
+        let methods = route.methods.as_deref();
+        match (&route.path, &route.prefix, &route.regex) {
+            (Some(path), None, None) => self.add(path, methods, target),
+            (None, Some(prefix), None) => self.add_prefix(prefix, methods, target),
+            (None, None, Some(re)) => self.add_regex(re, methods, target),
+            _ => Err(RouteError::InvalidPattern(
+                "route needs exactly one of path, prefix or regex".to_string(),
+            )),
+        }
+    }
+
+    fn push(
+        &mut self,
+        pattern: Pattern,
+        methods: Option<&[String]>,
+        target: T,
+    ) -> Result<(), RouteError> {
+        let methods = match methods {
+            Some(list) => Some(
+                list.iter()
+                    .map(|m| {
+                        Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                            .map_err(|_| RouteError::InvalidMethod(m.clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        self.routes.push(Route {
+            pattern,
+            methods,
+            target,
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    /// Every route target, in insertion order.
+    pub fn targets(&self) -> impl Iterator<Item = &T> {
+        self.routes.iter().map(|route| &route.target)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn find(&self, method: &Method, path: &str) -> RouteLookup<'_, T> {
+        let mut allowed: Vec<Method> = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(path) else {
+                continue;
+            };
+            match &route.methods {
+                Some(methods) if !methods.contains(method) => {
+                    for m in methods {
+                        if !allowed.contains(m) {
+                            allowed.push(m.clone());
+                        }
+                    }
+                }
+                _ => {
+                    return RouteLookup::Found(RouteMatch {
+                        target: &route.target,
+                        params,
+                    });
+                }
+            }
+        }
+        if allowed.is_empty() {
+            RouteLookup::NotFound
+        } else {
+            RouteLookup::MethodNotAllowed(allowed)
+        }
+    }
+
+    /// Build a router from `config.routes` followed by `config.plugin_endpoints`.
+    ///
+    /// `plugin_endpoints` entries become prefix routes, longest first, so
+    /// `/plugin/foo` reaches the plugin mapped at `/plugin`. `resolve` turns a
+    /// route target into the caller's handler type and also gets the route's
+    /// config (`None` for `plugin_endpoints`); routes it rejects and routes
+    /// that fail to parse are logged and skipped.
+    pub fn from_config<F>(config: &Config, mut resolve: F) -> Self
+    where
+        F: FnMut(&RouteTarget, Option<&RouteConfig>) -> Option<T>,
+    {
+        let mut router = Router::new();
+        for route in config.routes.iter().flatten() {
+            let Some(target) = route_target(route) else {
+                log::error!("[router] route has no plugin or handler: {:?}", route);
+                continue;
+            };
+            let Some(resolved) = resolve(&target, Some(route)) else {
+                continue;
+            };
+            if let Err(e) = router.add_route(route, resolved) {
+                log::error!("[router] skipping route {:?}: {}", route, e);
+            }
+        }
+        if let Some(ref mapping) = config.plugin_endpoints {
+            let mut endpoints: Vec<_> = mapping.iter().collect();
+            endpoints.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+            for (endpoint, filename) in endpoints {
+                let Some(resolved) = resolve(&RouteTarget::Plugin(filename.clone()), None) else {
+                    continue;
+                };
+                if let Err(e) = router.add_prefix(endpoint, None, resolved) {
+                    log::error!("[router] skipping plugin endpoint {}: {}", endpoint, e);
+                }
+            }
+        }
+        router
+    }
+}
+
+/// Check a `routes:` entry the way `Router::from_config` will parse it.
+pub fn check_route(route: &RouteConfig) -> Result<RouteTarget, RouteError> {
+    let target = route_target(route).ok_or(RouteError::InvalidTarget)?;
+    Router::new().add_route(route, ())?;
+    Ok(target)
+}
+
+fn route_target(route: &RouteConfig) -> Option<RouteTarget> {
+    match (&route.plugin, &route.handler) {
+        (Some(plugin), None) => Some(RouteTarget::Plugin(plugin.clone())),
+        (None, Some(handler)) => Some(RouteTarget::Handler(handler.clone())),
+        _ => None,
+    }
+}
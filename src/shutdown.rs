@@ -0,0 +1,126 @@
+//! Graceful shutdown: signal handling, connection draining and the pid file.
+//!
+//! On SIGTERM or SIGINT the server stops accepting, tells every open
+//! connection to finish its in-flight requests (`GracefulShutdown`) and waits
+//! up to `shutdown_timeout` seconds for them before shutting plugins down.
+//! SIGUSR2 does the same once a new binary has taken over the listeners
+//! (see `upgrade`).
+use hyper_util::server::graceful::GracefulShutdown;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_PID_FILE: &str = "server.pid";
+
+/// What a process signal asks the server to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGTERM or SIGINT: drain and exit.
+    Terminate(&'static str),
+    /// SIGUSR2: hand the listeners to a new binary, then drain and exit.
+    Upgrade,
+    /// SIGHUP: reload the config.
+    Reload,
+    /// SIGUSR1: reopen the log files.
+    Reopen,
+}
+
+/// The signals the server reacts to, registered once at startup.
+pub struct Signals {
+    term: tokio::signal::unix::Signal,
+    int: tokio::signal::unix::Signal,
+    usr2: tokio::signal::unix::Signal,
+    hup: tokio::signal::unix::Signal,
+    usr1: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Signals {
+            term: signal(SignalKind::terminate())?,
+            int: signal(SignalKind::interrupt())?,
+            usr2: signal(SignalKind::user_defined2())?,
+            hup: signal(SignalKind::hangup())?,
+            usr1: signal(SignalKind::user_defined1())?,
+        })
+    }
+
+    /// Wait for the next signal.
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.term.recv() => Signal::Terminate("SIGTERM"),
+            _ = self.int.recv() => Signal::Terminate("SIGINT"),
+            _ = self.usr2.recv() => Signal::Upgrade,
+            _ = self.hup.recv() => Signal::Reload,
+            _ = self.usr1.recv() => Signal::Reopen,
+        }
+    }
+}
+
+/// Signal every connection watched by `graceful` to finish and wait for
+/// them, at most `timeout`. Returns false if connections were still open.
+pub async fn drain(graceful: GracefulShutdown, timeout: Duration) -> bool {
+    let open = graceful.count();
+    if open > 0 {
+        log::info!("[shutdown] draining {} connection(s)", open);
+    }
+    tokio::time::timeout(timeout, graceful.shutdown())
+        .await
+        .is_ok()
+}
+
+/// The pid file, written at startup and removed on a clean exit.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    /// The original path while rotated to `.oldbin`.
+    rotated_from: Option<PathBuf>,
+}
+
+impl PidFile {
+    /// Write the current process id to `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        std::fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile {
+            path,
+            rotated_from: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file aside to `<path>.oldbin` so a new binary can write its
+    /// own, as nginx does during an upgrade.
+    pub fn rotate(&mut self) -> std::io::Result<()> {
+        let mut oldbin = self.path.clone().into_os_string();
+        oldbin.push(".oldbin");
+        let oldbin = PathBuf::from(oldbin);
+        std::fs::rename(&self.path, &oldbin)?;
+        self.rotated_from = Some(std::mem::replace(&mut self.path, oldbin));
+        Ok(())
+    }
+
+    /// Undo `rotate` after a failed upgrade.
+    pub fn restore(&mut self) -> std::io::Result<()> {
+        let Some(original) = self.rotated_from.take() else {
+            return Ok(());
+        };
+        std::fs::rename(&self.path, &original)?;
+        self.path = original;
+        Ok(())
+    }
+
+    /// Remove the file, unless another process has rewritten it since.
+    pub fn remove(self) {
+        let ours = std::fs::read_to_string(&self.path)
+            .map(|s| s.trim() == std::process::id().to_string())
+            .unwrap_or(false);
+        if ours && let Err(e) = std::fs::remove_file(&self.path) {
+            log::error!("[shutdown] removing {}: {}", self.path.display(), e);
+        }
+    }
+}
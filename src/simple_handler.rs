@@ -2,43 +2,42 @@ pub fn simple_handler_response(method: &hyper::Method, uri: &hyper::Uri) -> Stri
     format!("SimpleHandler: {} {}", method, uri)
 }
 use crate::config::Config;
-use std::sync::RwLock;
-use crate::handler_trait::Handler;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
+use crate::handler_trait::{Handler, HandlerFuture, RequestBody, full};
+use crate::static_files::{self, DirectoryOptions};
 use hyper::{Request, Response, StatusCode};
-use std::convert::Infallible;
-use std::future::Future;
-use std::pin::Pin;
 use std::sync::Arc;
-use tokio::fs;
-use tokio::io::AsyncReadExt;
+use std::sync::RwLock;
 
 pub struct SimpleHandler;
 
 impl Handler for SimpleHandler {
     fn handle<'a>(
         &'a self,
-        req: Request<Incoming>,
+        req: Request<RequestBody>,
         config: Arc<RwLock<Config>>,
-    ) -> Pin<Box<dyn Future<Output = Result<Response<Full<Bytes>>, Infallible>> + Send + 'a>> {
+    ) -> HandlerFuture<'a> {
         Box::pin(async move {
             // Clone needed config fields before any await
-            let static_dir = {
+            let (static_dir, options) = {
                 let config_read = config.read().unwrap();
-                config_read.static_dir.clone()
+                // Routes with `handler: static` carry their own directory options.
+                let options = req
+                    .extensions()
+                    .get::<DirectoryOptions>()
+                    .cloned()
+                    .unwrap_or_else(|| DirectoryOptions::from_config(&config_read, None));
+                (config_read.static_dir.clone(), options)
             };
             // Serve static files if static_dir is set
             if let Some(ref static_dir) = static_dir {
-                let mut path = req.uri().path().trim_start_matches('/').to_string();
-                if path.is_empty() {
-                    path = "index.html".to_string();
-                }
-                let file_path = std::path::Path::new(static_dir).join(&path);
-                if let Ok(mut file) = fs::File::open(&file_path).await {
-                    let mut buf = Vec::new();
-                    if let Ok(_) = file.read_to_end(&mut buf).await {
-                        return Ok(Response::new(Full::new(Bytes::from(buf))));
+                match static_files::serve(std::path::Path::new(static_dir), &req, &options).await {
+                    Ok(resp) => return Ok(resp),
+                    Err(StatusCode::NOT_FOUND) => {}
+                    Err(status) => {
+                        return Ok(Response::builder()
+                            .status(status)
+                            .body(full(status.canonical_reason().unwrap_or("")))
+                            .unwrap());
                     }
                 }
             }
@@ -46,7 +45,7 @@ impl Handler for SimpleHandler {
             let body = simple_handler_response(req.method(), req.uri());
             Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
-                .body(Full::new(Bytes::from(body)))
+                .body(full(body))
                 .unwrap())
         })
     }
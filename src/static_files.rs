@@ -0,0 +1,613 @@
+//! Static file serving for `static_dir`.
+//!
+//! - Request paths are percent-decoded and normalised segment by segment, so
+//!   `..` can never climb above the static root.
+//! - `Content-Type` comes from the file extension.
+//! - Bodies are streamed from disk in chunks instead of read into memory.
+//! - `ETag`/`Last-Modified` are sent and conditional GETs answered with 304.
+//! - `Range` requests get 206, with `multipart/byteranges` for several ranges.
+//! - Directories redirect to their slash-terminated URL, then serve the first
+//!   existing index file, or an nginx-style autoindex listing if enabled.
+use crate::config::{AutoindexFormat, Config, RouteConfig};
+use crate::handler_trait::{BoxError, ResponseBody, full};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+/// Bytes read from disk per body frame.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Above this many ranges the `Range` header is ignored and the whole file sent.
+const MAX_RANGES: usize = 32;
+
+/// Map a request path onto a file below `root`.
+///
+/// Returns `None` if the path is malformed or would escape `root`.
+pub fn resolve_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(request_path)?;
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            s if s.contains('\0') || s.contains('\\') => return None,
+            s => segments.push(s),
+        }
+    }
+    let mut path = root.to_path_buf();
+    path.extend(segments);
+    Some(path)
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// How directories under the static root are answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryOptions {
+    /// Index files tried in order.
+    pub index: Vec<String>,
+    /// Render a listing when no index file exists.
+    pub autoindex: bool,
+    pub autoindex_format: AutoindexFormat,
+}
+
+impl Default for DirectoryOptions {
+    fn default() -> Self {
+        DirectoryOptions {
+            index: vec!["index.html".to_string()],
+            autoindex: false,
+            autoindex_format: AutoindexFormat::Html,
+        }
+    }
+}
+
+impl DirectoryOptions {
+    /// Options for a route, falling back to the top-level config values.
+    pub fn from_config(config: &Config, route: Option<&RouteConfig>) -> Self {
+        let defaults = DirectoryOptions::default();
+        DirectoryOptions {
+            index: route
+                .and_then(|r| r.index.clone())
+                .or_else(|| config.index.clone())
+                .unwrap_or(defaults.index),
+            autoindex: route
+                .and_then(|r| r.autoindex)
+                .or(config.autoindex)
+                .unwrap_or(defaults.autoindex),
+            autoindex_format: route
+                .and_then(|r| r.autoindex_format.clone())
+                .or_else(|| config.autoindex_format.clone())
+                .unwrap_or(defaults.autoindex_format),
+        }
+    }
+}
+
+/// Serve the file or directory under `root` that `req` points at.
+///
+/// `Err(StatusCode::NOT_FOUND)` means there is no such file and the caller
+/// may fall back to something else; other errors should be sent as-is.
+pub async fn serve<B>(
+    root: &Path,
+    req: &Request<B>,
+    options: &DirectoryOptions,
+) -> Result<Response<ResponseBody>, StatusCode> {
+    let path = resolve_path(root, req.uri().path()).ok_or(StatusCode::BAD_REQUEST)?;
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(full(""))
+            .unwrap());
+    }
+    if metadata.is_dir() {
+        return serve_directory(&path, req, options).await;
+    }
+    if !metadata.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    serve_file(&path, &metadata, req).await
+}
+
+/// Redirect to the slash-terminated URL, then try the index files, then the
+/// autoindex listing; 403 when none applies.
+async fn serve_directory<B>(
+    dir: &Path,
+    req: &Request<B>,
+    options: &DirectoryOptions,
+) -> Result<Response<ResponseBody>, StatusCode> {
+    let request_path = req.uri().path();
+    if !request_path.ends_with('/') {
+        let location = match req.uri().query() {
+            Some(q) => format!("{}/?{}", request_path, q),
+            None => format!("{}/", request_path),
+        };
+        return Ok(Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(header::LOCATION, location)
+            .body(full(""))
+            .unwrap());
+    }
+    for index in &options.index {
+        let path = dir.join(index);
+        if let Ok(metadata) = tokio::fs::metadata(&path).await
+            && metadata.is_file()
+        {
+            return serve_file(&path, &metadata, req).await;
+        }
+    }
+    if !options.autoindex {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let entries = read_listing(dir).await.map_err(|_| StatusCode::FORBIDDEN)?;
+    let (content_type, body) = match options.autoindex_format {
+        AutoindexFormat::Html => (
+            "text/html; charset=utf-8",
+            render_html(request_path, &entries),
+        ),
+        AutoindexFormat::Json => ("application/json", render_json(&entries)),
+    };
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len());
+    if req.method() == Method::HEAD {
+        return Ok(builder.body(full("")).unwrap());
+    }
+    Ok(builder.body(full(body)).unwrap())
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// Directory entries, directories first, each group sorted by name. Hidden
+/// files are skipped.
+async fn read_listing(dir: &Path) -> std::io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata().await else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+fn render_html(request_path: &str, entries: &[Entry]) -> String {
+    let title =
+        html_escape(&percent_decode(request_path).unwrap_or_else(|| request_path.to_string()));
+    let mut out = format!(
+        "<html>\r\n<head><title>Index of {0}</title></head>\r\n<body>\r\n<h1>Index of {0}</h1><hr><pre><a href=\"../\">../</a>\r\n",
+        title
+    );
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let modified = entry
+            .modified
+            .map(|m| {
+                chrono::DateTime::<chrono::Utc>::from(m)
+                    .format("%d-%b-%Y %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let shown = format!("{}{}", entry.name, suffix);
+        out.push_str(&format!(
+            "<a href=\"{}{}\">{}</a>{:pad$} {:>20}\r\n",
+            percent_encode(&entry.name),
+            suffix,
+            html_escape(&shown),
+            "",
+            format!("{} {:>10}", modified, size),
+            pad = 50usize.saturating_sub(shown.chars().count()),
+        ));
+    }
+    out.push_str("</pre><hr></body>\r\n</html>\r\n");
+    out
+}
+
+/// JSON listing in nginx's `autoindex_format json` shape.
+fn render_json(entries: &[Entry]) -> String {
+    let list: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| {
+            let mut item = serde_json::json!({
+                "name": entry.name,
+                "type": if entry.is_dir { "directory" } else { "file" },
+                "mtime": entry.modified.map(httpdate::fmt_http_date),
+            });
+            if !entry.is_dir {
+                item["size"] = entry.size.into();
+            }
+            item
+        })
+        .collect();
+    serde_json::Value::Array(list).to_string()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+async fn serve_file<B>(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    req: &Request<B>,
+) -> Result<Response<ResponseBody>, StatusCode> {
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag(len, modified);
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let content_type = mime_guess::from_path(path).first_or_octet_stream();
+
+    let mut builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(ref lm) = last_modified {
+        builder = builder.header(header::LAST_MODIFIED, lm);
+    }
+    if not_modified(req.headers(), &etag, modified) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(full(""))
+            .unwrap());
+    }
+
+    let ranges = match req.headers().get(header::RANGE) {
+        Some(range) if if_range_matches(req.headers(), &etag, modified) => {
+            match parse_range(range.to_str().unwrap_or(""), len) {
+                RangeSpec::Ranges(ranges) => Some(ranges),
+                RangeSpec::Ignore => None,
+                RangeSpec::Unsatisfiable => {
+                    return Ok(builder
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                        .body(full(""))
+                        .unwrap());
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let (status, parts, content_type, body_len) = match ranges {
+        None => (
+            StatusCode::OK,
+            vec![Part::File { start: 0, len }],
+            content_type.to_string(),
+            len,
+        ),
+        Some(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            builder = builder.header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, len),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                vec![Part::File {
+                    start,
+                    len: end - start + 1,
+                }],
+                content_type.to_string(),
+                end - start + 1,
+            )
+        }
+        Some(ranges) => {
+            let boundary = format!("{:016x}", boundary_seed(&etag));
+            let mut parts = Vec::new();
+            let mut total = 0;
+            for (start, end) in ranges {
+                let head = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end, len
+                );
+                total += head.len() as u64 + (end - start + 1);
+                parts.push(Part::Data(Bytes::from(head)));
+                parts.push(Part::File {
+                    start,
+                    len: end - start + 1,
+                });
+            }
+            let tail = format!("\r\n--{}--\r\n", boundary);
+            total += tail.len() as u64;
+            parts.push(Part::Data(Bytes::from(tail)));
+            (
+                StatusCode::PARTIAL_CONTENT,
+                parts,
+                format!("multipart/byteranges; boundary={}", boundary),
+                total,
+            )
+        }
+    };
+
+    let builder = builder
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body_len);
+    if req.method() == Method::HEAD {
+        return Ok(builder.body(full("")).unwrap());
+    }
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let body = FileBody::new(file, parts, body_len)
+        .map_err(BoxError::from)
+        .boxed();
+    Ok(builder.body(body).unwrap())
+}
+
+/// Strong ETag from size and mtime, in nginx's format.
+fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", mtime, len)
+}
+
+fn boundary_seed(etag: &str) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    etag.bytes()
+        .fold(nanos, |acc, b| acc.rotate_left(5) ^ b as u64)
+}
+
+fn etag_matches(list: &str, etag: &str) -> bool {
+    // Weak comparison: W/ prefixes are ignored.
+    let strip = |t: &str| t.trim().trim_start_matches("W/").to_string();
+    list.split(',')
+        .any(|t| t.trim() == "*" || strip(t) == strip(etag))
+}
+
+fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(inm) = headers.get(header::IF_NONE_MATCH) {
+        return inm.to_str().is_ok_and(|v| etag_matches(v, etag));
+    }
+    match (headers.get(header::IF_MODIFIED_SINCE), modified) {
+        (Some(ims), Some(modified)) => date_not_after(ims, modified),
+        _ => false,
+    }
+}
+
+/// `If-Range` allows the range only if the validator still matches.
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    match headers.get(header::IF_RANGE) {
+        None => true,
+        Some(v) => {
+            let s = v.to_str().unwrap_or("");
+            if s.starts_with('"') || s.starts_with("W/") {
+                s == etag
+            } else {
+                modified.is_some_and(|m| date_not_after(v, m))
+            }
+        }
+    }
+}
+
+/// Whether `modified` is not later than the HTTP date in `value`.
+fn date_not_after(value: &HeaderValue, modified: SystemTime) -> bool {
+    let Some(date) = value
+        .to_str()
+        .ok()
+        .and_then(|s| httpdate::parse_http_date(s).ok())
+    else {
+        return false;
+    };
+    // HTTP dates have second precision.
+    let secs = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    };
+    secs(modified) <= secs(date)
+}
+
+enum RangeSpec {
+    /// Inclusive `(start, end)` byte ranges.
+    Ranges(Vec<(u64, u64)>),
+    /// Malformed or unsupported; serve the whole file.
+    Ignore,
+    Unsatisfiable,
+}
+
+fn parse_range(value: &str, len: u64) -> RangeSpec {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeSpec::Ignore;
+    };
+    let mut ranges = Vec::new();
+    for part in spec.split(',') {
+        let Some((start, end)) = part.trim().split_once('-') else {
+            return RangeSpec::Ignore;
+        };
+        let range = match (start.trim(), end.trim()) {
+            ("", "") => return RangeSpec::Ignore,
+            ("", suffix) => match suffix.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) if len > 0 => Some((len.saturating_sub(n), len - 1)),
+                Ok(_) => None,
+                Err(_) => return RangeSpec::Ignore,
+            },
+            (start, end) => {
+                let Ok(start) = start.parse::<u64>() else {
+                    return RangeSpec::Ignore;
+                };
+                let end = if end.is_empty() {
+                    len.saturating_sub(1)
+                } else {
+                    match end.parse::<u64>() {
+                        Ok(e) if e >= start => e.min(len.saturating_sub(1)),
+                        _ => return RangeSpec::Ignore,
+                    }
+                };
+                (start < len).then_some((start, end))
+            }
+        };
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+    if ranges.len() > MAX_RANGES {
+        RangeSpec::Ignore
+    } else if ranges.is_empty() {
+        RangeSpec::Unsatisfiable
+    } else {
+        RangeSpec::Ranges(ranges)
+    }
+}
+
+enum Part {
+    Data(Bytes),
+    File { start: u64, len: u64 },
+}
+
+enum State {
+    Idle,
+    Seeking(u64),
+    Reading(u64),
+}
+
+/// Streams file ranges (and multipart separators) without buffering the file.
+struct FileBody {
+    file: tokio::fs::File,
+    parts: VecDeque<Part>,
+    state: State,
+    remaining: u64,
+    buf: Vec<u8>,
+}
+
+impl FileBody {
+    fn new(file: tokio::fs::File, parts: Vec<Part>, len: u64) -> Self {
+        FileBody {
+            file,
+            parts: parts.into(),
+            state: State::Idle,
+            remaining: len,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Body for FileBody {
+    type Data = Bytes;
+    type Error = std::io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, std::io::Error>>> {
+        let this = &mut *self;
+        loop {
+            match this.state {
+                State::Idle => match this.parts.pop_front() {
+                    None => return Poll::Ready(None),
+                    Some(Part::Data(bytes)) => {
+                        this.remaining -= bytes.len() as u64;
+                        return Poll::Ready(Some(Ok(Frame::data(bytes))));
+                    }
+                    Some(Part::File { start, len }) => {
+                        if let Err(e) = Pin::new(&mut this.file).start_seek(SeekFrom::Start(start))
+                        {
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                        this.state = State::Seeking(len);
+                    }
+                },
+                State::Seeking(len) => {
+                    if let Err(e) = ready!(Pin::new(&mut this.file).poll_complete(cx)) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    this.state = State::Reading(len);
+                }
+                State::Reading(0) => this.state = State::Idle,
+                State::Reading(left) => {
+                    let want = left.min(CHUNK_SIZE as u64) as usize;
+                    this.buf.resize(want, 0);
+                    let mut read_buf = ReadBuf::new(&mut this.buf);
+                    if let Err(e) = ready!(Pin::new(&mut this.file).poll_read(cx, &mut read_buf)) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    let n = read_buf.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Some(Err(std::io::ErrorKind::UnexpectedEof.into())));
+                    }
+                    this.state = State::Reading(left - n as u64);
+                    this.remaining -= n as u64;
+                    return Poll::Ready(Some(Ok(Frame::data(Bytes::copy_from_slice(
+                        &this.buf[..n],
+                    )))));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        SizeHint::with_exact(self.remaining)
+    }
+}
@@ -0,0 +1,232 @@
+//! TLS termination with rustls.
+//!
+//! Certificates from `tls.certificates` are picked per connection by SNI:
+//! exact names first, then `*.` wildcards, then the default certificate.
+//! `watch` reloads them when their files change; a set that fails to load
+//! leaves the previous certificates in place.
+use crate::config::{TlsCertificateConfig, TlsConfig};
+use crate::health_check::ERROR_LOG_TARGET;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::ServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+pub const DEFAULT_PORT: u16 = 8443;
+
+#[derive(Debug)]
+pub enum TlsError {
+    NoCertificates,
+    /// The certificate file at the path could not be used.
+    Certificate(String, String),
+    /// The key file at the path could not be used.
+    Key(String, String),
+    Config(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::NoCertificates => write!(f, "tls: no certificates configured"),
+            TlsError::Certificate(path, e) => write!(f, "tls: certificate {}: {}", path, e),
+            TlsError::Key(path, e) => write!(f, "tls: key {}: {}", path, e),
+            TlsError::Config(e) => write!(f, "tls: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+#[derive(Debug)]
+struct CertStore {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    /// Keyed by the part after `*.`.
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl CertStore {
+    fn load(config: &TlsConfig) -> Result<Self, TlsError> {
+        let mut exact = HashMap::new();
+        let mut wildcard = HashMap::new();
+        let mut default = None;
+        let mut first = None;
+        for cert in &config.certificates {
+            let key = load_certified_key(cert)?;
+            first.get_or_insert_with(|| key.clone());
+            let Some(ref names) = cert.server_names else {
+                default.get_or_insert_with(|| key.clone());
+                continue;
+            };
+            for name in names {
+                let name = normalize(name);
+                match name.strip_prefix("*.") {
+                    Some(suffix) => wildcard.insert(suffix.to_string(), key.clone()),
+                    None => exact.insert(name, key.clone()),
+                };
+            }
+        }
+        Ok(CertStore {
+            exact,
+            wildcard,
+            default: default.or(first).ok_or(TlsError::NoCertificates)?,
+        })
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = server_name.map(normalize) else {
+            return self.default.clone();
+        };
+        if let Some(key) = self.exact.get(&name) {
+            return key.clone();
+        }
+        name.split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Read one certificate chain and its private key.
+pub fn load_certified_key(config: &TlsCertificateConfig) -> Result<Arc<CertifiedKey>, TlsError> {
+    let cert_err = |e: &dyn fmt::Display| TlsError::Certificate(config.cert.clone(), e.to_string());
+    let key_err = |e: &dyn fmt::Display| TlsError::Key(config.key.clone(), e.to_string());
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .map_err(|e| cert_err(&e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| cert_err(&e))?;
+    if certs.is_empty() {
+        return Err(cert_err(&"no certificates in file"));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(|e| key_err(&e))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e| key_err(&e))?;
+    let certified = CertifiedKey::new(certs, key);
+    // Catches a reload that sees a new certificate before its new key.
+    certified.keys_match().map_err(|e| key_err(&e))?;
+    Ok(Arc::new(certified))
+}
+
+/// SNI certificate resolver whose certificates can be swapped at runtime.
+#[derive(Debug)]
+pub struct CertResolver {
+    store: RwLock<Arc<CertStore>>,
+    config: RwLock<TlsConfig>,
+}
+
+impl CertResolver {
+    pub fn from_config(config: &TlsConfig) -> Result<Self, TlsError> {
+        Ok(CertResolver {
+            store: RwLock::new(Arc::new(CertStore::load(config)?)),
+            config: RwLock::new(config.clone()),
+        })
+    }
+
+    pub fn config(&self) -> TlsConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Re-read the certificate files of the current config.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let config = self.config();
+        self.reload_config(&config)
+    }
+
+    /// Switch to the certificates of `config`; on error nothing changes.
+    pub fn reload_config(&self, config: &TlsConfig) -> Result<(), TlsError> {
+        let store = CertStore::load(config)?;
+        *self.store.write().unwrap() = Arc::new(store);
+        *self.config.write().unwrap() = config.clone();
+        Ok(())
+    }
+
+    /// The certificate a client sending `server_name` would get.
+    pub fn resolve_name(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        self.store.read().unwrap().lookup(server_name)
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        self.config
+            .read()
+            .unwrap()
+            .certificates
+            .iter()
+            .flat_map(|c| [&c.cert, &c.key])
+            .filter_map(|p| std::path::absolute(p).ok())
+            .collect()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.resolve_name(client_hello.server_name()))
+    }
+}
+
+/// rustls server config using `resolver`, offering `alpn_protocols` (see
+/// `connection::alpn_protocols`).
+pub fn server_config(
+    resolver: Arc<CertResolver>,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<Arc<ServerConfig>, TlsError> {
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Config)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn_protocols;
+    Ok(Arc::new(config))
+}
+
+/// Watch the directories of the configured certificate files and reload
+/// when one of the files is written, created or renamed into place. The
+/// directories are taken from the config at call time.
+pub fn watch(resolver: &Arc<CertResolver>) -> notify::Result<RecommendedWatcher> {
+    let weak = Arc::downgrade(resolver);
+    let mut watcher = RecommendedWatcher::new(
+        move |res: notify::Result<notify::Event>| {
+            let Ok(event) = res else {
+                return;
+            };
+            if !(event.kind.is_modify() || event.kind.is_create()) {
+                return;
+            }
+            let Some(resolver) = weak.upgrade() else {
+                return;
+            };
+            let files = resolver.files();
+            if !event.paths.iter().any(|p| files.contains(p)) {
+                return;
+            }
+            match resolver.reload() {
+                Ok(()) => log::info!("[tls] certificates reloaded"),
+                Err(e) => log::error!(
+                    target: ERROR_LOG_TARGET,
+                    "[tls] reload failed, keeping previous certificates: {}",
+                    e
+                ),
+            }
+        },
+        notify::Config::default(),
+    )?;
+    let mut dirs: Vec<PathBuf> = resolver
+        .files()
+        .iter()
+        .filter_map(|p| p.parent().map(Path::to_path_buf))
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
@@ -0,0 +1,143 @@
+//! Zero-downtime binary upgrade, nginx style.
+//!
+//! On SIGUSR2 the running server re-executes its binary with every listening
+//! socket inherited and their addresses in `WIGSPACE_LISTEN_FDS`
+//! (`addr=fd;addr=fd`). The new process adopts those sockets instead of
+//! binding, so the kernel keeps queueing connections throughout. Once the new
+//! process has written the pid file the old one drains and exits.
+use std::collections::HashMap;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+pub const LISTEN_FDS_ENV: &str = "WIGSPACE_LISTEN_FDS";
+/// How long a new binary gets to take over before the upgrade is abandoned.
+pub const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listening sockets handed over by the process that started us.
+pub fn inherited_listeners() -> HashMap<SocketAddr, TcpListener> {
+    match std::env::var(LISTEN_FDS_ENV) {
+        Ok(value) => adopt(&value),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Take ownership of the sockets listed in `value` (`addr=fd;...`). Entries
+/// that do not parse, or whose fd is not listening on `addr`, are skipped.
+pub fn adopt(value: &str) -> HashMap<SocketAddr, TcpListener> {
+    let mut listeners = HashMap::new();
+    for entry in value.split(';').filter(|e| !e.is_empty()) {
+        let Some((addr, fd)) = entry.split_once('=') else {
+            log::error!("[upgrade] invalid {} entry {:?}", LISTEN_FDS_ENV, entry);
+            continue;
+        };
+        let (Ok(addr), Ok(fd)) = (addr.parse::<SocketAddr>(), fd.parse::<RawFd>()) else {
+            log::error!("[upgrade] invalid {} entry {:?}", LISTEN_FDS_ENV, entry);
+            continue;
+        };
+        if !is_listening_on(fd, addr) {
+            log::error!("[upgrade] fd {} is not listening on {}", fd, addr);
+            continue;
+        }
+        // SAFETY: the fd was checked to be a TCP listener and is owned by
+        // nothing else in this process.
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        set_cloexec(fd, true);
+        log::info!("[upgrade] inherited listener {} (fd {})", addr, fd);
+        listeners.insert(addr, listener);
+    }
+    listeners
+}
+
+/// Whether `fd` is a listening TCP socket bound to `addr`, checked without
+/// taking ownership of it.
+fn is_listening_on(fd: RawFd, addr: SocketAddr) -> bool {
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: getsockopt writes at most `len` bytes into `listening`.
+    let ok = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut listening as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    } == 0;
+    if !ok || listening == 0 {
+        return false;
+    }
+    // SAFETY: ManuallyDrop keeps the borrowed fd from being closed.
+    let listener = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(fd) });
+    listener.local_addr().is_ok_and(|bound| bound == addr)
+}
+
+fn set_cloexec(fd: RawFd, on: bool) {
+    // SAFETY: fcntl on an fd we own; failure leaves the flags unchanged.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags >= 0 {
+            let flags = if on {
+                flags | libc::FD_CLOEXEC
+            } else {
+                flags & !libc::FD_CLOEXEC
+            };
+            libc::fcntl(fd, libc::F_SETFD, flags);
+        }
+    }
+}
+
+/// Start the new binary with `listeners` inherited. The binary is the one we
+/// were started as (argv[0]), so a binary replaced on disk is picked up.
+pub fn spawn(listeners: &[(SocketAddr, RawFd)]) -> io::Result<Child> {
+    let mut args = std::env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => std::env::current_exe()?.into_os_string(),
+    };
+    let fds: Vec<RawFd> = listeners.iter().map(|&(_, fd)| fd).collect();
+    let value = listeners
+        .iter()
+        .map(|(addr, fd)| format!("{}={}", addr, fd))
+        .collect::<Vec<_>>()
+        .join(";");
+    let mut command = Command::new(program);
+    command.args(args).env(LISTEN_FDS_ENV, value);
+    // SAFETY: only async-signal-safe fcntl calls run between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                set_cloexec(fd, false);
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// Wait until `child` has written its pid to `pid_file`, meaning it has taken
+/// over the listeners. Fails if it exits first or takes longer than `timeout`.
+pub async fn wait_ready(child: &mut Child, pid_file: &Path, timeout: Duration) -> io::Result<()> {
+    let pid = child.id().to_string();
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!("new binary exited: {}", status)));
+        }
+        if std::fs::read_to_string(pid_file).is_ok_and(|s| s.trim() == pid) {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "new binary did not start in time",
+            ));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,357 @@
+//! Named upstream groups for the reverse proxy.
+//!
+//! `proxy_pass: http://<name>/...` refers to the group `<name>` under
+//! `upstreams:` in the config. Each group picks a backend per request with
+//! one of the `LoadBalance` strategies. Backends that fail `max_fails` times
+//! in a row are taken out of rotation for `fail_timeout` seconds.
+use crate::config::{Config, HealthCheckConfig, LoadBalance, UpstreamConfig};
+use hyper::HeaderMap;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_FAILS: u32 = 1;
+pub const DEFAULT_FAIL_TIMEOUT_SECS: u64 = 10;
+
+/// Virtual nodes per unit of weight on the consistent-hash ring.
+const HASH_REPLICAS: u32 = 160;
+
+/// One server of an upstream group, shared between config generations so
+/// connection counts and failure state survive a hot-reload.
+#[derive(Debug)]
+pub struct Backend {
+    pub address: String,
+    pub weight: u32,
+    active: AtomicUsize,
+    fails: AtomicU32,
+    down_until: Mutex<Option<Instant>>,
+    healthy: AtomicBool,
+    check_successes: AtomicU32,
+    check_failures: AtomicU32,
+}
+
+impl Backend {
+    pub fn new(address: impl Into<String>, weight: u32) -> Self {
+        Backend {
+            address: address.into(),
+            weight: weight.max(1),
+            active: AtomicUsize::new(0),
+            fails: AtomicU32::new(0),
+            down_until: Mutex::new(None),
+            healthy: AtomicBool::new(true),
+            check_successes: AtomicU32::new(0),
+            check_failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Requests currently in flight to this backend.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Whether the backend may receive requests: healthy according to
+    /// active checks and not marked down by passive failure tracking.
+    pub fn is_available(&self) -> bool {
+        if !self.is_healthy() {
+            return false;
+        }
+        match *self.down_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    /// Record a failed request; marks the backend down for `fail_timeout`
+    /// once `max_fails` consecutive failures are reached.
+    pub fn record_failure(&self, max_fails: u32, fail_timeout: Duration) {
+        let fails = self.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if max_fails > 0 && fails >= max_fails {
+            *self.down_until.lock().unwrap() = Some(Instant::now() + fail_timeout);
+            self.fails.store(0, Ordering::Relaxed);
+            log::error!(
+                "[upstream] backend {} marked down for {:?} after {} failures",
+                self.address,
+                fail_timeout,
+                fails
+            );
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Apply one active health-check result. The backend turns healthy after
+    /// `rise` consecutive passes and unhealthy after `fall` consecutive
+    /// failures; returns the new state when it changed.
+    pub fn record_check(&self, ok: bool, rise: u32, fall: u32) -> Option<bool> {
+        let (streak, other, threshold) = if ok {
+            (&self.check_successes, &self.check_failures, rise)
+        } else {
+            (&self.check_failures, &self.check_successes, fall)
+        };
+        other.store(0, Ordering::Relaxed);
+        let count = streak.fetch_add(1, Ordering::Relaxed) + 1;
+        if count >= threshold.max(1) && self.healthy.swap(ok, Ordering::Relaxed) != ok {
+            Some(ok)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.fails.store(0, Ordering::Relaxed);
+        *self.down_until.lock().unwrap() = None;
+    }
+
+    /// Count a request as in flight until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> ActiveGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard(self.clone())
+    }
+}
+
+/// Keeps a backend's active-request count raised while alive.
+#[derive(Debug)]
+pub struct ActiveGuard(Arc<Backend>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What the consistent-hash strategy hashes on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HashKey {
+    ClientIp,
+    Header(String),
+}
+
+impl HashKey {
+    /// Parse `client_ip` or `header:<name>`.
+    pub fn parse(s: &str) -> Option<Self> {
+        if s == "client_ip" {
+            Some(HashKey::ClientIp)
+        } else {
+            s.strip_prefix("header:")
+                .map(|name| HashKey::Header(name.trim().to_ascii_lowercase()))
+        }
+    }
+}
+
+pub struct UpstreamPool {
+    pub name: String,
+    backends: Vec<Arc<Backend>>,
+    strategy: LoadBalance,
+    hash_key: HashKey,
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+    /// Active health-check settings; `None` disables probing.
+    pub health_check: Option<HealthCheckConfig>,
+    next: AtomicUsize,
+    /// Current weights for smooth weighted round-robin.
+    current_weights: Mutex<Vec<i64>>,
+    /// Consistent-hash ring of (point, backend index), sorted by point.
+    ring: Vec<(u64, usize)>,
+}
+
+impl UpstreamPool {
+    pub fn new(
+        name: impl Into<String>,
+        backends: Vec<Arc<Backend>>,
+        config: &UpstreamConfig,
+    ) -> Self {
+        let name = name.into();
+        let hash_key = match config.hash_key.as_deref() {
+            None => HashKey::ClientIp,
+            Some(s) => HashKey::parse(s).unwrap_or_else(|| {
+                log::error!(
+                    "[upstream] {}: invalid hash_key {:?}, using client_ip",
+                    name,
+                    s
+                );
+                HashKey::ClientIp
+            }),
+        };
+        let mut ring = Vec::new();
+        if config.strategy == LoadBalance::Hash {
+            for (i, backend) in backends.iter().enumerate() {
+                for replica in 0..HASH_REPLICAS * backend.weight {
+                    ring.push((
+                        fnv1a(format!("{}#{}", backend.address, replica).as_bytes()),
+                        i,
+                    ));
+                }
+            }
+            ring.sort_unstable();
+        }
+        if config.health_check.is_none() {
+            // Backends carried over from a generation that had checks must
+            // not stay stuck unhealthy.
+            for backend in &backends {
+                backend.healthy.store(true, Ordering::Relaxed);
+            }
+        }
+        UpstreamPool {
+            name,
+            current_weights: Mutex::new(vec![0; backends.len()]),
+            backends,
+            strategy: config.strategy.clone(),
+            hash_key,
+            max_fails: config.max_fails.unwrap_or(DEFAULT_MAX_FAILS),
+            fail_timeout: Duration::from_secs(
+                config.fail_timeout.unwrap_or(DEFAULT_FAIL_TIMEOUT_SECS),
+            ),
+            health_check: config.health_check.clone(),
+            next: AtomicUsize::new(0),
+            ring,
+        }
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.backends.is_empty()
+    }
+
+    /// The value the hash strategy should use for a request, if any.
+    pub fn hash_value(&self, headers: &HeaderMap, client_ip: Option<IpAddr>) -> Option<String> {
+        match &self.hash_key {
+            HashKey::ClientIp => client_ip.map(|ip| ip.to_string()),
+            HashKey::Header(name) => headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+
+    /// Pick an available backend, skipping indices in `exclude` (backends
+    /// already tried for this request). `key` is only used by `Hash`.
+    pub fn pick(&self, key: Option<&str>, exclude: &[usize]) -> Option<usize> {
+        let usable = |i: usize| !exclude.contains(&i) && self.backends[i].is_available();
+        if !(0..self.backends.len()).any(usable) {
+            return None;
+        }
+        match self.strategy {
+            LoadBalance::RoundRobin => {
+                let n = self.backends.len();
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..n).map(|k| (start + k) % n).find(|&i| usable(i))
+            }
+            LoadBalance::Weighted => {
+                // Smooth weighted round-robin, as in nginx.
+                let mut current = self.current_weights.lock().unwrap();
+                let mut total = 0i64;
+                let mut best: Option<usize> = None;
+                for (i, backend) in self.backends.iter().enumerate() {
+                    if !usable(i) {
+                        continue;
+                    }
+                    let weight = backend.weight as i64;
+                    current[i] += weight;
+                    total += weight;
+                    if best.is_none_or(|b| current[i] > current[b]) {
+                        best = Some(i);
+                    }
+                }
+                let best = best?;
+                current[best] -= total;
+                Some(best)
+            }
+            LoadBalance::LeastConn => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                let n = self.backends.len();
+                (0..n)
+                    .map(|k| (start + k) % n)
+                    .filter(|&i| usable(i))
+                    .min_by(|&a, &b| {
+                        // Compare active/weight without dividing.
+                        let (ba, bb) = (&self.backends[a], &self.backends[b]);
+                        (ba.active() as u64 * bb.weight as u64)
+                            .cmp(&(bb.active() as u64 * ba.weight as u64))
+                    })
+            }
+            LoadBalance::Hash => {
+                let Some(key) = key else {
+                    // No key to hash on: fall back to round-robin.
+                    let n = self.backends.len();
+                    let start = self.next.fetch_add(1, Ordering::Relaxed);
+                    return (0..n).map(|k| (start + k) % n).find(|&i| usable(i));
+                };
+                let point = fnv1a(key.as_bytes());
+                let start = self.ring.partition_point(|&(p, _)| p < point);
+                (0..self.ring.len())
+                    .map(|k| self.ring[(start + k) % self.ring.len()].1)
+                    .find(|&i| usable(i))
+            }
+        }
+    }
+
+    pub fn backend(&self, index: usize) -> &Arc<Backend> {
+        &self.backends[index]
+    }
+}
+
+/// All upstream groups of one config generation.
+#[derive(Default)]
+pub struct Upstreams {
+    pools: HashMap<String, Arc<UpstreamPool>>,
+}
+
+impl Upstreams {
+    /// Build the groups from `config.upstreams`. Backends whose address and
+    /// weight are unchanged from `previous` keep their state, so in-flight
+    /// counts and down marks carry over a hot-reload.
+    pub fn from_config(config: &Config, previous: Option<&Upstreams>) -> Self {
+        let mut pools = HashMap::new();
+        for (name, upstream) in config.upstreams.iter().flatten() {
+            let old = previous.and_then(|p| p.get(name));
+            let backends = upstream
+                .servers
+                .iter()
+                .map(|server| {
+                    let weight = server.weight.unwrap_or(1).max(1);
+                    old.and_then(|pool| {
+                        pool.backends
+                            .iter()
+                            .find(|b| b.address == server.address && b.weight == weight)
+                            .cloned()
+                    })
+                    .unwrap_or_else(|| Arc::new(Backend::new(server.address.clone(), weight)))
+                })
+                .collect();
+            pools.insert(
+                name.clone(),
+                Arc::new(UpstreamPool::new(name.clone(), backends, upstream)),
+            );
+        }
+        Upstreams { pools }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<UpstreamPool>> {
+        self.pools.get(name)
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Arc<UpstreamPool>> {
+        self.pools.values()
+    }
+}
+
+/// 64-bit FNV-1a; stable across runs, unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
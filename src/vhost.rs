@@ -0,0 +1,222 @@
+//! Virtual hosts: picking the `servers:` entry that answers a request.
+//!
+//! Every listen address has its own `VirtualHosts` table. A request's host
+//! name (the `Host` header, `:authority` for HTTP/2, or the TLS SNI name) is
+//! matched like nginx does: exact names first, then the longest leading
+//! wildcard (`*.example.com`), the longest trailing wildcard (`www.*`), and
+//! regexes in config order. Anything else goes to the default server.
+use hyper::Request;
+use hyper::header::HOST;
+use regex::Regex;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug)]
+pub enum VhostError {
+    InvalidRegex(regex::Error),
+    InvalidListen(String),
+}
+
+impl fmt::Display for VhostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VhostError::InvalidRegex(e) => write!(f, "invalid server_name regex: {}", e),
+            VhostError::InvalidListen(l) => write!(f, "invalid listen address: {}", l),
+        }
+    }
+}
+
+impl std::error::Error for VhostError {}
+
+/// One `listen` entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Listen {
+    pub addr: SocketAddr,
+    pub tls: bool,
+}
+
+impl Listen {
+    /// Parse `host:port`, `[v6]:port`, `*:port` or `port`, optionally
+    /// followed by ` ssl`. A bare port binds `default_address`.
+    pub fn parse(s: &str, default_address: &str) -> Result<Self, VhostError> {
+        let invalid = || VhostError::InvalidListen(s.to_string());
+        let mut parts = s.split_whitespace();
+        let addr = parts.next().ok_or_else(invalid)?;
+        let tls = match parts.next() {
+            None => false,
+            Some("ssl") => true,
+            Some(_) => return Err(invalid()),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        let addr = if let Ok(port) = addr.parse::<u16>() {
+            let ip: IpAddr = default_address.parse().map_err(|_| invalid())?;
+            SocketAddr::new(ip, port)
+        } else if let Some(port) = addr.strip_prefix("*:") {
+            SocketAddr::new([0, 0, 0, 0].into(), port.parse().map_err(|_| invalid())?)
+        } else {
+            addr.parse().map_err(|_| invalid())?
+        };
+        Ok(Listen { addr, tls })
+    }
+}
+
+enum Name {
+    Exact(String),
+    /// `*.example.com`, stored as `.example.com`.
+    Leading(String),
+    /// `www.example.*`, stored as `www.example.`.
+    Trailing(String),
+    Regex(Regex),
+}
+
+impl Name {
+    fn parse(name: &str) -> Result<Vec<Self>, VhostError> {
+        if let Some(re) = name.strip_prefix('~') {
+            return Ok(vec![Name::Regex(
+                Regex::new(re).map_err(VhostError::InvalidRegex)?,
+            )]);
+        }
+        let name = normalize(name);
+        Ok(if let Some(suffix) = name.strip_prefix('*') {
+            vec![Name::Leading(suffix.to_string())]
+        } else if let Some(prefix) = name.strip_suffix('*') {
+            vec![Name::Trailing(prefix.to_string())]
+        } else if let Some(domain) = name.strip_prefix('.') {
+            vec![Name::Exact(domain.to_string()), Name::Leading(name.clone())]
+        } else {
+            vec![Name::Exact(name)]
+        })
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Server names of one listen address, mapped to the caller's site type.
+pub struct VirtualHosts<T> {
+    targets: Vec<T>,
+    exact: HashMap<String, usize>,
+    leading: Vec<(String, usize)>,
+    trailing: Vec<(String, usize)>,
+    regex: Vec<(Regex, usize)>,
+    default: Option<usize>,
+}
+
+impl<T> Default for VirtualHosts<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> VirtualHosts<T> {
+    pub fn new() -> Self {
+        VirtualHosts {
+            targets: Vec::new(),
+            exact: HashMap::new(),
+            leading: Vec::new(),
+            trailing: Vec::new(),
+            regex: Vec::new(),
+            default: None,
+        }
+    }
+
+    /// Add a server answering to `names`. The first server added, or the
+    /// last one added with `is_default`, becomes the default. Names already
+    /// taken by an earlier server keep pointing at it.
+    pub fn add(&mut self, names: &[String], target: T, is_default: bool) -> Result<(), VhostError> {
+        let mut parsed = Vec::new();
+        for name in names {
+            parsed.extend(Name::parse(name)?);
+        }
+        let index = self.targets.len();
+        self.targets.push(target);
+        if is_default || self.default.is_none() {
+            self.default = Some(index);
+        }
+        for name in parsed {
+            match name {
+                Name::Exact(n) => match self.exact.entry(n) {
+                    Entry::Occupied(e) => {
+                        log::warn!("[vhost] conflicting server name {}, ignored", e.key())
+                    }
+                    Entry::Vacant(e) => {
+                        e.insert(index);
+                    }
+                },
+                Name::Leading(n) => self.leading.push((n, index)),
+                Name::Trailing(n) => self.trailing.push((n, index)),
+                Name::Regex(re) => self.regex.push((re, index)),
+            }
+        }
+        // Longest wildcard wins; the stable sort keeps config order on ties.
+        self.leading
+            .sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        self.trailing
+            .sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Every server, in the order added.
+    pub fn targets(&self) -> impl Iterator<Item = &T> {
+        self.targets.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// The server for `host`, falling back to the default server.
+    pub fn find(&self, host: Option<&str>) -> Option<&T> {
+        let index = host
+            .map(normalize)
+            .and_then(|host| self.lookup(&host))
+            .or(self.default)?;
+        self.targets.get(index)
+    }
+
+    fn lookup(&self, host: &str) -> Option<usize> {
+        if let Some(&index) = self.exact.get(host) {
+            return Some(index);
+        }
+        self.leading
+            .iter()
+            .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            .or_else(|| {
+                self.trailing.iter().find(|(prefix, _)| {
+                    host.len() > prefix.len() && host.starts_with(prefix.as_str())
+                })
+            })
+            .map(|&(_, index)| index)
+            .or_else(|| {
+                self.regex
+                    .iter()
+                    .find(|(re, _)| re.is_match(host))
+                    .map(|&(_, index)| index)
+            })
+    }
+}
+
+/// The host name a request is addressed to, without the port: the `Host`
+/// header, or the URI authority for HTTP/2.
+pub fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let authority = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))?;
+    let host = match authority.strip_prefix('[') {
+        // IPv6 literal: keep it bracketed, as clients send it.
+        Some(rest) => &authority[..rest.find(']')? + 2],
+        None => authority.split(':').next()?,
+    };
+    Some(normalize(host))
+}
@@ -0,0 +1,88 @@
+//! Tests for command-line parsing, `-s` signalling and the `-T` dump
+use std::path::PathBuf;
+use wigspace_rust::cli::{Action, CliError, Options, SignalCommand, send_signal};
+use wigspace_rust::config::{Config, RouteConfig};
+
+#[test]
+fn test_parse_options() {
+    let options = Options::parse(Vec::<String>::new()).unwrap();
+    assert_eq!(options.config, PathBuf::from("config.yaml"));
+    assert_eq!(options.action, Action::Run);
+
+    let options = Options::parse(["-c", "/etc/wigspace.yaml", "-t"]).unwrap();
+    assert_eq!(options.config, PathBuf::from("/etc/wigspace.yaml"));
+    assert_eq!(options.action, Action::Test);
+    assert_eq!(
+        Options::parse(["--test-config"]).unwrap().action,
+        Action::Test
+    );
+    assert_eq!(Options::parse(["-T"]).unwrap().action, Action::Dump);
+    assert_eq!(
+        Options::parse(["-s", "reopen"]).unwrap().action,
+        Action::Signal(SignalCommand::Reopen)
+    );
+
+    assert_eq!(Options::parse(["-c"]), Err(CliError::MissingValue("-c")));
+    assert_eq!(
+        Options::parse(["-s", "restart"]),
+        Err(CliError::UnknownSignal("restart".to_string()))
+    );
+    assert_eq!(
+        Options::parse(["--verbose"]),
+        Err(CliError::UnknownOption("--verbose".to_string()))
+    );
+}
+
+#[test]
+fn test_send_signal_reads_pid_file() {
+    let dir = std::env::temp_dir().join(format!("wigspace_cli_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.pid");
+    assert!(send_signal(&path, SignalCommand::Reload).is_err());
+
+    std::fs::write(&path, "not-a-pid\n").unwrap();
+    let err = send_signal(&path, SignalCommand::Reload).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // Signal a child we own, so nothing else is disturbed.
+    let mut child = std::process::Command::new("sleep")
+        .arg("30")
+        .spawn()
+        .unwrap();
+    std::fs::write(&path, format!("{}\n", child.id())).unwrap();
+    assert_eq!(
+        send_signal(&path, SignalCommand::Stop).unwrap(),
+        child.id() as i32
+    );
+    let status = child.wait().unwrap();
+    assert!(!status.success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dump_leaves_out_unset_options() {
+    let config = Config {
+        routes: Some(vec![RouteConfig {
+            path: Some("/users/:id".to_string()),
+            prefix: None,
+            regex: None,
+            methods: None,
+            plugin: Some("hello.lua".to_string()),
+            handler: None,
+            index: None,
+            autoindex: None,
+            autoindex_format: None,
+            middleware: None,
+        }]),
+        ..Config::default()
+    };
+    assert_eq!(
+        config.dump(),
+        "address: 127.0.0.1\n\
+         port: 8080\n\
+         plugins_dir: plugins\n\
+         routes:\n\
+         - path: /users/:id\n\
+         \x20 plugin: hello.lua\n"
+    );
+}
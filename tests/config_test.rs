@@ -0,0 +1,370 @@
+//! Tests for config loading errors and validation
+use std::path::PathBuf;
+use wigspace_rust::config::{ConfigError, FieldError, config_sources, expand_env, load_config};
+
+/// A fresh directory with a `plugins/` and `public/` dir for one test.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wigspace_config_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("plugins")).unwrap();
+    std::fs::create_dir_all(dir.join("public")).unwrap();
+    dir
+}
+
+fn write_config(dir: &std::path::Path, yaml: &str) -> PathBuf {
+    let path = dir.join("config.yaml");
+    std::fs::write(&path, yaml).unwrap();
+    path
+}
+
+#[test]
+fn test_parse_error_has_location_and_field() {
+    let dir = temp_dir("parse");
+    let path = write_config(
+        &dir,
+        "address: 127.0.0.1\nport: 8080\nroutes:\n  - path: /a\n    methods: GET\n",
+    );
+    match load_config(&path) {
+        Err(ConfigError::Parse {
+            field,
+            line,
+            column,
+            message,
+            ..
+        }) => {
+            assert_eq!(field.as_deref(), Some("routes[0].methods"));
+            assert_eq!((line, column), (Some(5), Some(14)));
+            assert_eq!(message, "invalid type: string \"GET\", expected a sequence");
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+    let path = write_config(&dir, "address: 127.0.0.1\nport: 70000\n");
+    let err = load_config(&path).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "{}:2:7: port: invalid value: integer `70000`, expected u16",
+            path.display()
+        )
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_missing_file_is_an_io_error() {
+    let err = load_config("/nonexistent/wigspace.yaml").unwrap_err();
+    assert!(matches!(err, ConfigError::Io { .. }), "{:?}", err);
+}
+
+#[test]
+fn test_semantic_validation() {
+    let dir = temp_dir("semantic");
+    let yaml = format!(
+        "address: localhost\n\
+         port: 0\n\
+         plugins_dir: {plugins}\n\
+         static_dir: {missing}\n\
+         routes:\n\
+         \x20 - path: /a\n\
+         \x20   plugin: gone.lua\n\
+         \x20 - prefix: nope\n\
+         \x20   handler: static\n\
+         servers:\n\
+         \x20 - listen: [\"8080 http3\"]\n\
+         \x20   server_name: [\"~(\"]\n\
+         \x20   routes:\n\
+         \x20     - path: /b\n\
+         \x20       handler: fastcgi\n",
+        plugins = dir.join("plugins").display(),
+        missing = dir.join("missing").display(),
+    );
+    let path = write_config(&dir, &yaml);
+    let Err(ConfigError::Invalid { errors, .. }) = load_config(&path) else {
+        panic!("expected validation errors");
+    };
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+        fields,
+        vec![
+            "address",
+            "port",
+            "static_dir",
+            "routes[0].plugin",
+            "routes[1]",
+            "servers[0].routes[0].handler",
+            "servers[0].listen[0]",
+            "servers[0].server_name",
+        ]
+    );
+    assert_eq!(
+        errors[5],
+        FieldError {
+            field: "servers[0].routes[0].handler".to_string(),
+            message: "unknown handler \"fastcgi\"".to_string(),
+        }
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_valid_config_loads() {
+    let dir = temp_dir("valid");
+    std::fs::write(
+        dir.join("plugins/hello.lua"),
+        "function handle(i) return i end",
+    )
+    .unwrap();
+    let yaml = format!(
+        "address: 127.0.0.1\n\
+         port: 8080\n\
+         plugins_dir: {plugins}\n\
+         static_dir: {public}\n\
+         plugin_endpoints:\n\
+         \x20 /lua: hello.lua\n\
+         routes:\n\
+         \x20 - path: /users/:id\n\
+         \x20   plugin: hello.lua\n",
+        plugins = dir.join("plugins").display(),
+        public = dir.join("public").display(),
+    );
+    let config = load_config(write_config(&dir, &yaml)).unwrap();
+    assert_eq!(config.port, 8080);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_toml_and_json_by_extension() {
+    let dir = temp_dir("formats");
+    let toml = dir.join("config.toml");
+    std::fs::write(
+        &toml,
+        "address = \"127.0.0.1\"\nport = 9000\n\n[[routes]]\npath = \"/x\"\nhandler = \"static\"\n",
+    )
+    .unwrap();
+    let config = load_config(&toml).unwrap();
+    assert_eq!(config.port, 9000);
+    assert_eq!(config.routes.unwrap()[0].path.as_deref(), Some("/x"));
+
+    let json = dir.join("config.json");
+    std::fs::write(&json, r#"{"address": "127.0.0.1", "port": 9001}"#).unwrap();
+    assert_eq!(load_config(&json).unwrap().port, 9001);
+
+    std::fs::write(&json, "{\"address\": \"127.0.0.1\",\n \"port\": \"http\"}").unwrap();
+    match load_config(&json) {
+        Err(ConfigError::Parse {
+            field,
+            line,
+            column,
+            ..
+        }) => {
+            assert_eq!(field.as_deref(), Some("port"));
+            assert_eq!((line, column), (Some(2), Some(15)));
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+    std::fs::write(&toml, "address = \"127.0.0.1\"\nport = [1]\n").unwrap();
+    match load_config(&toml) {
+        Err(ConfigError::Parse { field, line, .. }) => {
+            assert_eq!(field.as_deref(), Some("port"));
+            assert_eq!(line, Some(2));
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_includes_are_merged() {
+    let dir = temp_dir("include");
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+    std::fs::write(
+        dir.join("conf.d/10-users.yaml"),
+        "port: 1111\nroutes:\n  - path: /users\n    handler: static\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/20-api.json"),
+        r#"{"routes": [{"prefix": "/api", "handler": "proxy"}], "proxy_pass": "http://127.0.0.1:9000"}"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("conf.d/notes.txt"), "ignored").unwrap();
+    let path = write_config(
+        &dir,
+        "address: 127.0.0.1\nport: 8080\ninclude: [conf.d/*.yaml, conf.d/*.json]\nroutes:\n  - path: /\n    handler: static\n",
+    );
+    let config = load_config(&path).unwrap();
+    assert_eq!(config.port, 8080, "the including file keeps its scalars");
+    assert_eq!(config.proxy_pass.as_deref(), Some("http://127.0.0.1:9000"));
+    let routes: Vec<_> = config
+        .routes
+        .unwrap()
+        .into_iter()
+        .map(|r| r.path.or(r.prefix).unwrap())
+        .collect();
+    assert_eq!(routes, vec!["/", "/users", "/api"]);
+
+    let path = write_config(
+        &dir,
+        "address: 127.0.0.1\nport: 8080\ninclude: missing.yaml\n",
+    );
+    assert!(matches!(
+        load_config(&path),
+        Err(ConfigError::Include { .. })
+    ));
+    std::fs::write(dir.join("conf.d/loop.yaml"), "include: ../config.yaml\n").unwrap();
+    let path = write_config(
+        &dir,
+        "address: 127.0.0.1\nport: 8080\ninclude: conf.d/loop.yaml\n",
+    );
+    let err = load_config(&path).unwrap_err();
+    assert!(err.to_string().ends_with("include cycle"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_environment_variables_are_expanded() {
+    let env = |name: &str| match name {
+        "HOST" => Some("10.0.0.1".to_string()),
+        "EMPTY" => Some(String::new()),
+        _ => None,
+    };
+    assert_eq!(
+        expand_env("http://${HOST}:${PORT:-80}/", env).unwrap(),
+        "http://10.0.0.1:80/"
+    );
+    assert_eq!(expand_env("${EMPTY:-fallback}", env).unwrap(), "fallback");
+    assert_eq!(
+        expand_env("cost: $$5, $HOME", env).unwrap(),
+        "cost: $5, $HOME"
+    );
+    assert_eq!(
+        expand_env("${MISSING}", env).unwrap_err(),
+        "environment variable MISSING is not set"
+    );
+    assert!(expand_env("${HOST", env).is_err());
+
+    let dir = temp_dir("env");
+    let path = write_config(
+        &dir,
+        "address: ${WIGSPACE_TEST_UNSET_ADDRESS:-127.0.0.1}\nport: 8080\nproxy_pass: ${WIGSPACE_TEST_UNSET_UPSTREAM}\n",
+    );
+    let Err(ConfigError::Invalid { errors, .. }) = load_config(&path) else {
+        panic!("expected an unset variable error");
+    };
+    assert_eq!(errors[0].field, "proxy_pass");
+    let path = write_config(
+        &dir,
+        "address: ${WIGSPACE_TEST_UNSET_ADDRESS:-127.0.0.1}\nport: 8080\n",
+    );
+    assert_eq!(load_config(&path).unwrap().address, "127.0.0.1");
+
+    // Number and bool fields take the expanded strings; string fields keep
+    // them as strings, even when they look like numbers.
+    let path = write_config(
+        &dir,
+        concat!(
+            "address: 127.0.0.1\nport: ${WIGSPACE_TEST_UNSET_PORT:-9090}\n",
+            "autoindex: ${WIGSPACE_TEST_UNSET_AUTOINDEX:-true}\n",
+            "index: ['${WIGSPACE_TEST_UNSET_INDEX:-404}']\n",
+            "middleware:\n",
+            "  - {name: auth, api_keys: {ci: '${WIGSPACE_TEST_UNSET_KEY:-20261017}'}}\n",
+            "  - {name: rate_limit, requests_per_second: '${WIGSPACE_TEST_UNSET_RATE:-2.5}'}\n",
+        ),
+    );
+    let config = load_config(&path).unwrap();
+    assert_eq!(config.port, 9090);
+    assert_eq!(config.autoindex, Some(true));
+    assert_eq!(config.index, Some(vec!["404".to_string()]));
+    let path = write_config(
+        &dir,
+        "address: 127.0.0.1\nport: ${WIGSPACE_TEST_UNSET_PORT:-http}\n",
+    );
+    let Err(ConfigError::Parse { field, message, .. }) = load_config(&path) else {
+        panic!("expected a parse error");
+    };
+    assert_eq!(field.as_deref(), Some("port"));
+    assert_eq!(message, "invalid type: string \"http\", expected u16");
+    let path = dir.join("config.toml");
+    std::fs::write(
+        &path,
+        "address = \"127.0.0.1\"\nport = \"${WIGSPACE_TEST_UNSET_PORT:-9091}\"\n",
+    )
+    .unwrap();
+    assert_eq!(load_config(&path).unwrap().port, 9091);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_sources_follow_includes() {
+    let dir = temp_dir("sources");
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+    std::fs::write(dir.join("conf.d/10-users.yaml"), "port: 1111\n").unwrap();
+    let path = write_config(
+        &dir,
+        "address: 127.0.0.1\nport: 8080\ninclude: conf.d/*.yaml\n",
+    );
+    let dir = std::fs::canonicalize(&dir).unwrap();
+    let sources = config_sources(&path);
+    assert_eq!(
+        sources.files,
+        vec![dir.join("config.yaml"), dir.join("conf.d/10-users.yaml")]
+    );
+    assert!(sources.matches(&dir.join("conf.d/20-new.yaml")));
+    assert!(!sources.matches(&dir.join("conf.d/notes.txt")));
+    assert!(!sources.matches(&dir.join(".config.yaml.swp")));
+    assert_eq!(
+        sources.dirs().into_iter().collect::<Vec<_>>(),
+        vec![dir.clone(), dir.join("conf.d")]
+    );
+
+    // A broken include is still watched, so fixing it triggers a reload.
+    std::fs::write(dir.join("conf.d/10-users.yaml"), "port: [\n").unwrap();
+    assert_eq!(config_sources(&path).files.len(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_middleware_lists_are_validated() {
+    let dir = temp_dir("middleware");
+    let yaml = "address: 127.0.0.1\n\
+                port: 8080\n\
+                servers:\n\
+                \x20 - middleware: [logging, {name: headers, set: {X-Frame-Options: DENY}}]\n\
+                \x20   routes:\n\
+                \x20     - prefix: /api\n\
+                \x20       handler: proxy\n\
+                \x20       middleware:\n\
+                \x20         - compression\n\
+                \x20         - {name: rate_limit, burst: 5}\n\
+                \x20 - middleware: [gzip]\n";
+    let path = write_config(&dir, yaml);
+    let Err(ConfigError::Invalid { errors, .. }) = load_config(&path) else {
+        panic!("expected validation errors");
+    };
+    let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+    assert_eq!(
+        fields,
+        vec![
+            "servers[0].routes[0].middleware[1]",
+            "servers[1].middleware[0]",
+        ]
+    );
+    assert!(
+        errors[0]
+            .message
+            .contains("missing field `requests_per_second`"),
+        "{}",
+        errors[0]
+    );
+
+    let path = write_config(
+        &dir,
+        &yaml
+            .replace("burst: 5", "requests_per_second: 5")
+            .replace("[gzip]", "[compression]"),
+    );
+    let config = load_config(&path).unwrap();
+    let servers = config.servers.unwrap();
+    assert_eq!(servers[0].middleware.as_ref().unwrap()[1].name(), "headers");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
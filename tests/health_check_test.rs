@@ -0,0 +1,122 @@
+//! Tests for active upstream health checks
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use wigspace_rust::config::{
+    HealthCheckConfig, HealthCheckKind, LoadBalance, UpstreamConfig, UpstreamServerConfig,
+};
+use wigspace_rust::health_check::{check_pool, probe};
+use wigspace_rust::upstream::{Backend, UpstreamPool};
+
+/// Backend answering 200 on `/health` and 503 everywhere else.
+async fn spawn_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let status = if req.uri().path() == "/health" {
+                        StatusCode::OK
+                    } else {
+                        StatusCode::SERVICE_UNAVAILABLE
+                    };
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .status(status)
+                            .body(Full::new(Bytes::new()))
+                            .unwrap(),
+                    )
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+fn check(kind: HealthCheckKind, path: &str) -> HealthCheckConfig {
+    HealthCheckConfig {
+        kind,
+        path: Some(path.to_string()),
+        interval: Some(1),
+        timeout: Some(1),
+        rise: Some(1),
+        fall: Some(1),
+    }
+}
+
+#[test]
+fn test_rise_and_fall_thresholds() {
+    let backend = Backend::new("127.0.0.1:1", 1);
+    assert_eq!(backend.record_check(false, 2, 3), None);
+    assert_eq!(backend.record_check(false, 2, 3), None);
+    assert_eq!(backend.record_check(false, 2, 3), Some(false));
+    assert!(!backend.is_available());
+    assert_eq!(backend.record_check(false, 2, 3), None);
+    assert_eq!(backend.record_check(true, 2, 3), None);
+    assert_eq!(backend.record_check(true, 2, 3), Some(true));
+    assert!(backend.is_available());
+}
+
+#[tokio::test]
+async fn test_probe_http_and_tcp() {
+    let live = spawn_backend().await;
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    assert!(probe(&live.to_string(), &check(HealthCheckKind::Http, "/health")).await);
+    assert!(!probe(&live.to_string(), &check(HealthCheckKind::Http, "/missing")).await);
+    assert!(probe(&live.to_string(), &check(HealthCheckKind::Tcp, "/")).await);
+    assert!(!probe(&closed.to_string(), &check(HealthCheckKind::Tcp, "/")).await);
+}
+
+#[tokio::test]
+async fn test_check_pool_feeds_backend_selection() {
+    let live = spawn_backend().await;
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = UpstreamConfig {
+        servers: [closed, live]
+            .iter()
+            .map(|addr| UpstreamServerConfig {
+                address: addr.to_string(),
+                weight: None,
+            })
+            .collect(),
+        strategy: LoadBalance::RoundRobin,
+        hash_key: None,
+        max_fails: None,
+        fail_timeout: None,
+        health_check: Some(check(HealthCheckKind::Http, "/health")),
+    };
+    let backends = config
+        .servers
+        .iter()
+        .map(|s| Arc::new(Backend::new(s.address.clone(), 1)))
+        .collect();
+    let pool = Arc::new(UpstreamPool::new("backend", backends, &config));
+
+    check_pool(pool.clone()).await;
+    assert!(!pool.backend(0).is_healthy());
+    assert!(pool.backend(1).is_healthy());
+    for _ in 0..4 {
+        assert_eq!(pool.pick(None, &[]), Some(1));
+    }
+}
@@ -0,0 +1,154 @@
+//! Tests for HTTP/2 over TLS (ALPN) and cleartext (prior knowledge)
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper::{Request, StatusCode, Version};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::pki_types::ServerName;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use wigspace_rust::config::{Config, Http2Config, TlsCertificateConfig, TlsConfig};
+use wigspace_rust::connection;
+use wigspace_rust::handler_trait::{Handler, request_body};
+use wigspace_rust::logging_middleware::LoggingMiddleware;
+use wigspace_rust::middleware_chain::MiddlewareChainBuilder;
+use wigspace_rust::simple_handler::SimpleHandler;
+use wigspace_rust::tls::{self, CertResolver};
+
+/// Serve the core middleware chain the way `main` does, optionally over TLS.
+async fn spawn_server(http2: Option<Http2Config>, tls: Option<TlsAcceptor>) -> SocketAddr {
+    let config = Arc::new(RwLock::new(Config {
+        http2,
+        ..Config::default()
+    }));
+    let handler: Arc<dyn Handler> = Arc::new(SimpleHandler);
+    let chain = MiddlewareChainBuilder::new()
+        .add_middleware(Arc::new(LoggingMiddleware::new()))
+        .build(handler);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let config = config.clone();
+            let chain = chain.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let builder = connection::builder(config.read().unwrap().http2.as_ref());
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let chain = chain.clone();
+                    let config = config.clone();
+                    async move { chain.handle(req.map(request_body), config).await }
+                });
+                match tls {
+                    Some(acceptor) => {
+                        let stream = acceptor.accept(stream).await.unwrap();
+                        let _ = builder
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    }
+                    None => {
+                        let _ = builder
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+async fn get(addr: SocketAddr, http2_only: bool) -> Result<(Version, String), String> {
+    let client = Client::builder(TokioExecutor::new())
+        .http2_only(http2_only)
+        .build_http::<Empty<Bytes>>();
+    let uri = format!("http://{}/x", addr).parse::<hyper::Uri>().unwrap();
+    let resp = client.get(uri).await.map_err(|e| e.to_string())?;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let version = resp.version();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    Ok((version, String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[tokio::test]
+async fn test_cleartext_serves_http1_and_h2c() {
+    let addr = spawn_server(None, None).await;
+    let (version, body) = get(addr, false).await.unwrap();
+    assert_eq!(version, Version::HTTP_11);
+    assert_eq!(body, "SimpleHandler: GET /x");
+
+    let (version, body) = get(addr, true).await.unwrap();
+    assert_eq!(version, Version::HTTP_2);
+    assert!(body.starts_with("SimpleHandler: GET "), "{}", body);
+    assert!(body.ends_with("/x"), "{}", body);
+}
+
+#[tokio::test]
+async fn test_http2_can_be_disabled() {
+    let config = Http2Config {
+        enabled: Some(false),
+        ..Http2Config::default()
+    };
+    assert_eq!(
+        connection::alpn_protocols(Some(&config)),
+        vec![b"http/1.1".to_vec()]
+    );
+    let addr = spawn_server(Some(config), None).await;
+    assert_eq!(get(addr, false).await.unwrap().0, Version::HTTP_11);
+    assert!(get(addr, true).await.is_err());
+}
+
+#[tokio::test]
+async fn test_tls_negotiates_h2_with_alpn() {
+    let dir = std::env::temp_dir().join(format!("wigspace_h2_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+    std::fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+    let tls_config = TlsConfig {
+        port: None,
+        certificates: vec![TlsCertificateConfig {
+            cert: dir.join("cert.pem").display().to_string(),
+            key: dir.join("key.pem").display().to_string(),
+            server_names: None,
+        }],
+    };
+    let resolver = Arc::new(CertResolver::from_config(&tls_config).unwrap());
+    let server_config = tls::server_config(resolver, connection::alpn_protocols(None)).unwrap();
+    let addr = spawn_server(None, Some(TlsAcceptor::from(server_config))).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(generated.cert.der().clone()).unwrap();
+    let mut client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost").unwrap(), stream)
+        .await
+        .unwrap();
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+    let (mut sender, conn) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .unwrap();
+    tokio::spawn(conn);
+    let req = Request::get(format!("https://localhost:{}/x", addr.port()))
+        .body(Empty::<Bytes>::new())
+        .unwrap();
+    let resp = sender.send_request(req).await.unwrap();
+    assert_eq!(resp.version(), Version::HTTP_2);
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&body).ends_with("/x"));
+}
@@ -0,0 +1,84 @@
+//! Tests for the pooled and shared Lua states of a script
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use wigspace_rust::modules::dynamic_loader::{DynamicModule, ScriptingModule};
+use wigspace_rust::modules::envelope::PluginRequest;
+
+fn script(name: &str, source: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("wigspace_lua_pool_{}_{}", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+/// Replace the script, moving its modification time forward so the change is
+/// seen even within the file system's timestamp granularity.
+fn rewrite(path: &Path, source: &str) {
+    let before = std::fs::metadata(path).unwrap().modified().unwrap();
+    std::fs::write(path, source).unwrap();
+    let later = before.max(SystemTime::now()) + Duration::from_secs(1);
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(later)
+        .unwrap();
+}
+
+fn get(module: &ScriptingModule) -> String {
+    let resp = module.call(&PluginRequest::new("GET", "/"));
+    String::from_utf8(resp.body).unwrap()
+}
+
+const COUNTER: &str = r#"
+    hits = 0
+    function handle_request(req)
+      hits = hits + 1
+      return tostring(hits)
+    end
+"#;
+
+#[test]
+fn test_states_are_reused() {
+    let path = script("pooled.lua", COUNTER);
+    let module = ScriptingModule::load(&path).unwrap();
+    // One request at a time gets the same idle state back.
+    assert_eq!(
+        (get(&module), get(&module)),
+        ("1".to_string(), "2".to_string())
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_shared_state_and_rebuild_on_change() {
+    let path = script("shared.lua", &format!("shared_state = true\n{}", COUNTER));
+    let module = ScriptingModule::load(&path).unwrap();
+    let hits: Vec<_> = (0..3).map(|_| get(&module)).collect();
+    assert_eq!(hits, ["1", "2", "3"]);
+
+    rewrite(&path, "function handle_request(req) return 'v2' end");
+    assert_eq!(get(&module), "v2");
+
+    // A broken version keeps the last one that loaded.
+    rewrite(&path, "function handle_request(req) return end end");
+    assert_eq!(get(&module), "v2");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_script_errors_fail_the_load() {
+    let path = script("broken.lua", "function handle_request(req)");
+    let err = ScriptingModule::load(&path).err().unwrap();
+    assert!(
+        err.to_string().contains("[Lua error] script load"),
+        "{}",
+        err
+    );
+
+    std::fs::write(&path, "error('no config')").unwrap();
+    let err = ScriptingModule::load(&path).err().unwrap();
+    assert!(err.to_string().contains("no config"), "{}", err);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -1,9 +1,17 @@
-use hyper::{Method, Uri};
-use std::sync::Arc;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Bytes, Frame};
+use hyper::{Method, Request, Response, Uri};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::Poll;
 use wigspace_rust::config::Config;
-use wigspace_rust::handler_trait::Handler;
+use wigspace_rust::handler_trait::{
+    BoxError, Context, Handler, HandlerFuture, RequestBody, ResponseBody, full, request_body,
+};
 use wigspace_rust::logging_middleware::LoggingMiddleware;
 use wigspace_rust::middleware_chain::MiddlewareChainBuilder;
+use wigspace_rust::middleware_trait::{Flow, Middleware, MiddlewareFuture, RequestHead};
 use wigspace_rust::simple_handler::SimpleHandler;
 use wigspace_rust::simple_handler::simple_handler_response;
 
@@ -22,3 +30,121 @@ async fn test_logging_middleware_chain() {
     let body_str = simple_handler_response(&method, &uri);
     assert!(body_str.contains("SimpleHandler"));
 }
+
+/// Echoes the request body and the `User` an outer middleware put in the
+/// context, streaming the response in two frames.
+struct EchoHandler;
+
+#[derive(Clone, Debug, PartialEq)]
+struct User(String);
+
+impl Handler for EchoHandler {
+    fn handle<'a>(
+        &'a self,
+        mut req: Request<RequestBody>,
+        _config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            let ctx = Context::of(&mut req);
+            let user = ctx.get::<User>().map(|u| u.0).unwrap_or_default();
+            ctx.insert(Handled(true));
+            let body = req.into_body().collect().await.unwrap().to_bytes();
+            let chunks = Chunks(vec![Bytes::from(format!("{}:", user)), body].into());
+            Ok(Response::new(chunks.boxed()))
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Handled(bool);
+
+/// A body sent one chunk per frame.
+struct Chunks(VecDeque<Bytes>);
+
+impl Body for Chunks {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(chunk))))
+    }
+}
+
+/// Rejects requests without `authorization`, records the user otherwise and
+/// tags every response on the way out.
+struct AuthMiddleware;
+
+impl Middleware for AuthMiddleware {
+    fn before<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        ctx: &'a Context,
+    ) -> MiddlewareFuture<'a, Flow> {
+        Box::pin(async move {
+            match req.headers().get("authorization") {
+                Some(user) => {
+                    ctx.insert(User(user.to_str().unwrap().to_string()));
+                    Flow::Continue(req)
+                }
+                None => Flow::Respond(
+                    Response::builder()
+                        .status(401)
+                        .body(full("denied"))
+                        .unwrap(),
+                ),
+            }
+        })
+    }
+
+    fn after<'a>(
+        &'a self,
+        _head: &'a RequestHead,
+        ctx: &'a Context,
+        mut resp: Response<ResponseBody>,
+    ) -> MiddlewareFuture<'a, Response<ResponseBody>> {
+        Box::pin(async move {
+            let handled = ctx.get::<Handled>().is_some_and(|h| h.0);
+            resp.headers_mut()
+                .insert("x-handled", handled.to_string().parse().unwrap());
+            resp
+        })
+    }
+}
+
+async fn call(chain: &Arc<dyn Handler>, auth: Option<&str>, body: &str) -> (u16, String, String) {
+    let mut req = Request::builder().uri("/echo");
+    if let Some(user) = auth {
+        req = req.header("authorization", user);
+    }
+    let req = req
+        .body(request_body(Full::new(Bytes::from(body.to_string()))))
+        .unwrap();
+    let resp = chain
+        .handle(req, Arc::new(RwLock::new(Config::default())))
+        .await
+        .unwrap();
+    let status = resp.status().as_u16();
+    let handled = resp.headers()["x-handled"].to_str().unwrap().to_string();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    (status, handled, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[tokio::test]
+async fn test_hooks_short_circuit_and_context() {
+    let chain = MiddlewareChainBuilder::new()
+        .add_middleware(Arc::new(LoggingMiddleware::new()))
+        .add_middleware(Arc::new(AuthMiddleware))
+        .build(Arc::new(EchoHandler));
+    assert_eq!(
+        call(&chain, Some("ana"), "hello").await,
+        (200, "true".to_string(), "ana:hello".to_string())
+    );
+    // Short-circuited: the handler never ran, but `after` still did.
+    assert_eq!(
+        call(&chain, None, "hello").await,
+        (401, "false".to_string(), "denied".to_string())
+    );
+}
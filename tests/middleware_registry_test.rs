@@ -0,0 +1,212 @@
+//! Tests for the named middleware and the registry that builds them
+use flate2::read::GzDecoder;
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use wigspace_rust::auth_middleware::AuthUser;
+use wigspace_rust::compression_middleware::accepts_gzip;
+use wigspace_rust::config::{Config, MiddlewareConfig};
+use wigspace_rust::handler_trait::{
+    Context, Handler, HandlerFuture, RequestBody, full, request_body,
+};
+use wigspace_rust::middleware_registry::{MiddlewareRegistry, chain};
+use wigspace_rust::rate_limit_middleware::{RateLimitMiddleware, RateLimitOptions};
+use wigspace_rust::static_files::{DirectoryOptions, serve};
+
+fn middleware(yaml: &str) -> MiddlewareConfig {
+    serde_yaml::from_str(yaml).unwrap()
+}
+
+/// Answers with a long text body and the authenticated user, if any.
+struct TextHandler;
+
+impl Handler for TextHandler {
+    fn handle<'a>(
+        &'a self,
+        mut req: Request<RequestBody>,
+        _config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        let user = Context::of(&mut req).get::<AuthUser>();
+        let body = format!(
+            "user={:?} {}",
+            user.map(|u| u.0),
+            "lorem ipsum ".repeat(100)
+        );
+        Box::pin(async move {
+            Ok(Response::builder()
+                .header("content-type", "text/plain; charset=utf-8")
+                .header("server", "wigspace")
+                .body(full(body))
+                .unwrap())
+        })
+    }
+}
+
+/// Serves files from a static root, ranges included.
+struct FileHandler(std::path::PathBuf);
+
+impl Handler for FileHandler {
+    fn handle<'a>(
+        &'a self,
+        req: Request<RequestBody>,
+        _config: Arc<RwLock<Config>>,
+    ) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            Ok(serve(&self.0, &req, &DirectoryOptions::default())
+                .await
+                .unwrap())
+        })
+    }
+}
+
+async fn get(handler: &Arc<dyn Handler>, headers: &[(&str, &str)]) -> Response<Vec<u8>> {
+    let mut req = Request::builder().uri("/");
+    for (name, value) in headers {
+        req = req.header(*name, *value);
+    }
+    let req = req
+        .body(request_body(http_body_util::Empty::new()))
+        .unwrap();
+    let resp = handler
+        .handle(req, Arc::new(RwLock::new(Config::default())))
+        .await
+        .unwrap();
+    let (parts, body) = resp.into_parts();
+    let body = body.collect().await.unwrap().to_bytes().to_vec();
+    Response::from_parts(parts, body)
+}
+
+#[test]
+fn test_registry_reports_unknown_names_and_bad_options() {
+    let registry = MiddlewareRegistry::default();
+    assert_eq!(
+        registry.names().collect::<Vec<_>>(),
+        ["auth", "compression", "headers", "logging", "rate_limit"]
+    );
+    assert!(registry.build(&middleware("logging")).is_ok());
+    let err = registry.build(&middleware("gzip")).err().unwrap();
+    assert!(err.starts_with("unknown middleware \"gzip\""), "{}", err);
+    let err = registry
+        .build(&middleware("{name: rate_limit, requests_per_second: 0}"))
+        .err()
+        .unwrap();
+    assert_eq!(err, "rate_limit: requests_per_second must be positive");
+    let err = registry
+        .build(&middleware("{name: headers, add: {x: y}}"))
+        .err()
+        .unwrap();
+    assert!(err.contains("unknown field `add`"), "{}", err);
+}
+
+#[tokio::test]
+async fn test_auth_headers_and_compression_chain() {
+    let registry = MiddlewareRegistry::default();
+    let configs = [
+        middleware("{name: headers, set: {X-Frame-Options: DENY}, remove: [server]}"),
+        middleware("compression"),
+        middleware("{name: auth, api_keys: {ci: s3cret}}"),
+    ];
+    let middleware: Vec<_> = configs.iter().map(|c| registry.build(c).unwrap()).collect();
+    let handler = chain(&middleware, Arc::new(TextHandler));
+
+    let resp = get(&handler, &[]).await;
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["www-authenticate"], "Bearer");
+    assert_eq!(resp.headers()["x-frame-options"], "DENY");
+    let resp = get(&handler, &[("authorization", "Bearer wrong")]).await;
+    assert_eq!(resp.status(), 401);
+
+    let resp = get(&handler, &[("authorization", "Bearer s3cret")]).await;
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.headers().contains_key("server"));
+    assert!(!resp.headers().contains_key("content-encoding"));
+    assert!(String::from_utf8_lossy(resp.body()).starts_with("user=Some(\"ci\")"));
+
+    let resp = get(
+        &handler,
+        &[
+            ("authorization", "Bearer s3cret"),
+            ("accept-encoding", "br, gzip;q=0.8"),
+        ],
+    )
+    .await;
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    let mut text = String::new();
+    GzDecoder::new(&resp.body()[..])
+        .read_to_string(&mut text)
+        .unwrap();
+    assert!(
+        text.starts_with("user=Some(\"ci\") lorem ipsum"),
+        "{}",
+        text
+    );
+    assert!(resp.body().len() < text.len());
+}
+
+#[tokio::test]
+async fn test_compression_leaves_ranges_alone() {
+    let root = std::env::temp_dir().join(format!("wigspace_gzip_range_{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("index.html"), "0123456789".repeat(100)).unwrap();
+    let compression = MiddlewareRegistry::default()
+        .build(&middleware("compression"))
+        .unwrap();
+    let handler = chain(&[compression], Arc::new(FileHandler(root.clone())));
+
+    let resp = get(
+        &handler,
+        &[("range", "bytes=0-9"), ("accept-encoding", "gzip")],
+    )
+    .await;
+    assert_eq!(resp.status(), 206);
+    assert!(!resp.headers().contains_key("content-encoding"));
+    assert_eq!(resp.headers()["content-range"], "bytes 0-9/1000");
+    assert_eq!(resp.body(), b"0123456789");
+
+    // The whole file is gzipped, and is no longer offered in ranges under a
+    // strong validator.
+    let resp = get(&handler, &[("accept-encoding", "gzip")]).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert!(!resp.headers().contains_key("accept-ranges"));
+    let etag = resp.headers()["etag"].to_str().unwrap();
+    assert!(etag.starts_with("W/\""), "{}", etag);
+
+    let resp = get(&handler, &[]).await;
+    assert_eq!(resp.headers()["accept-ranges"], "bytes");
+    assert!(resp.headers()["etag"].to_str().unwrap().starts_with('"'));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_accept_encoding() {
+    assert!(accepts_gzip("gzip"));
+    assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+    assert!(accepts_gzip("*"));
+    assert!(!accepts_gzip("gzip;q=0"));
+    assert!(!accepts_gzip("br, deflate"));
+}
+
+#[test]
+fn test_rate_limit_token_bucket() {
+    let limiter = RateLimitMiddleware::new(RateLimitOptions {
+        requests_per_second: 2.0,
+        burst: Some(3),
+    })
+    .unwrap();
+    let client = Some("10.0.0.1".parse().unwrap());
+    let now = Instant::now();
+    for _ in 0..3 {
+        assert_eq!(limiter.take(client, now), Ok(()));
+    }
+    assert_eq!(limiter.take(client, now), Err(1));
+    // Other clients have their own bucket.
+    assert_eq!(limiter.take(Some("10.0.0.2".parse().unwrap()), now), Ok(()));
+    // Two tokens a second come back.
+    let later = now + Duration::from_millis(500);
+    assert_eq!(limiter.take(client, later), Ok(()));
+    assert_eq!(limiter.take(client, later), Err(1));
+}
@@ -0,0 +1,178 @@
+//! Tests for the memory contract of native plugins, using a small C plugin
+//! built with the system `cc`
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use wigspace_rust::modules::dynamic_loader::{
+    CAbiModule, DynamicModule, PluginManifest, RustDylibModule,
+};
+use wigspace_rust::modules::envelope::PluginRequest;
+
+/// Exports both the C ABI `handle_request` and a Rust dylib vtable, so one
+/// library serves both loaders. `GET /null` answers NULL and `GET /bad`
+/// invalid UTF-8; `freed_count` tells how many strings were released.
+/// `ABI` and `CAPS` set what `plugin_info` reports.
+const PLUGIN_SOURCE: &str = r#"
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifndef ABI
+#define ABI 1
+#endif
+#ifndef CAPS
+#define CAPS 0
+#endif
+struct plugin_info {
+    uint32_t abi_version;
+    const char *name;
+    const char *version;
+    uint32_t capabilities;
+};
+static const struct plugin_info INFO = { ABI, "contract", "0.1.0", CAPS };
+#ifndef NO_INFO
+const struct plugin_info *plugin_info(void) { return &INFO; }
+#endif
+
+static int freed = 0;
+int freed_count(void) { return freed; }
+
+static char *answer(const char *input, size_t len) {
+    if (len >= 9 && memcmp(input, "GET /null", 9) == 0) return NULL;
+    char *out = malloc(len + 6);
+    if (len >= 8 && memcmp(input, "GET /bad", 8) == 0) {
+        out[0] = (char)0xff; out[1] = 0;
+        return out;
+    }
+    memcpy(out, "echo ", 5);
+    memcpy(out + 5, input, len);
+    out[len + 5] = 0;
+    return out;
+}
+
+char *handle_request(const unsigned char *input, size_t len) {
+    return answer((const char *)input, len);
+}
+
+static char *handle(const char *input) { return answer(input, strlen(input)); }
+struct vtable { char *(*handle)(const char *); };
+static const struct vtable VTABLE = { handle };
+const struct vtable *get_plugin_vtable(void) { return &VTABLE; }
+
+#ifndef NO_FREE
+void free_string(char *s) { freed++; free(s); }
+#endif
+"#;
+
+/// Build the plugin into a temp dir, or `None` without a C compiler.
+fn build_plugin(name: &str, defines: &[&str]) -> Option<PathBuf> {
+    let dir = std::env::temp_dir().join(format!("wigspace_native_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir.join(format!("{}.c", name));
+    std::fs::write(&source, PLUGIN_SOURCE).unwrap();
+    let lib = dir.join(format!("lib{}.so", name));
+    let status = Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(&lib)
+        .arg(&source)
+        .args(defines)
+        .status();
+    match status {
+        Ok(s) if s.success() => Some(lib),
+        _ => {
+            eprintln!("skipping: cannot build a C plugin with cc");
+            None
+        }
+    }
+}
+
+fn freed_count(path: &Path) -> i32 {
+    // Opening the library again returns the already loaded instance.
+    unsafe {
+        let lib = libloading::Library::new(path).unwrap();
+        let count: libloading::Symbol<unsafe extern "C" fn() -> i32> =
+            lib.get(b"freed_count").unwrap();
+        count()
+    }
+}
+
+#[test]
+fn test_native_plugins_free_and_reject_bad_strings() {
+    let Some(path) = build_plugin("contract", &[]) else {
+        return;
+    };
+    let c_module = unsafe { CAbiModule::load(&path) }.unwrap();
+    let rust_module = unsafe { RustDylibModule::load(&path) }.unwrap();
+    assert_eq!(
+        c_module.manifest(),
+        &PluginManifest {
+            name: "contract".to_string(),
+            version: "0.1.0".to_string(),
+            capabilities: 0,
+        }
+    );
+    let modules: [(&str, &dyn DynamicModule); 2] =
+        [("c_plugin", &c_module), ("rust_plugin", &rust_module)];
+    for (kind, module) in modules {
+        let freed = freed_count(&path);
+        let resp = module.call(&PluginRequest::new("GET", "/ok"));
+        assert_eq!(
+            (resp.status, resp.body.as_slice()),
+            (200, &b"echo GET /ok"[..])
+        );
+        assert_eq!(freed_count(&path), freed + 1, "{} freed its output", kind);
+
+        let resp = module.call(&PluginRequest::new("GET", "/null"));
+        assert_eq!(resp.status, 500);
+        assert_eq!(
+            String::from_utf8(resp.body).unwrap(),
+            format!("[{}] plugin returned NULL", kind)
+        );
+        let resp = module.call(&PluginRequest::new("GET", "/bad"));
+        assert_eq!(resp.status, 500);
+        let body = String::from_utf8(resp.body).unwrap();
+        assert!(body.contains("returned invalid UTF-8"), "{}", body);
+        assert_eq!(freed_count(&path), freed + 2, "{} freed bad output", kind);
+    }
+}
+
+#[test]
+fn test_native_plugins_must_export_free_string() {
+    let Some(path) = build_plugin("leaky", &["-DNO_FREE"]) else {
+        return;
+    };
+    let err = unsafe { CAbiModule::load(&path) }.err().unwrap();
+    assert!(err.to_string().contains("free_string"), "{}", err);
+    let err = unsafe { RustDylibModule::load(&path) }.err().unwrap();
+    assert!(err.to_string().contains("free_string"), "{}", err);
+}
+
+#[test]
+fn test_incompatible_plugins_are_refused() {
+    let cases = [
+        (
+            "abi2",
+            &["-DABI=2"][..],
+            "incompatible plugin: built for plugin ABI 2, this server supports 1",
+        ),
+        (
+            "noinfo",
+            &["-DNO_INFO"][..],
+            "incompatible plugin: no plugin_info export; rebuild it against wigspace-plugin-api",
+        ),
+    ];
+    for (name, defines, message) in cases {
+        let Some(path) = build_plugin(name, defines) else {
+            return;
+        };
+        let err = unsafe { CAbiModule::load(&path) }.err().unwrap();
+        assert_eq!(err.to_string(), message);
+        let err = unsafe { RustDylibModule::load(&path) }.err().unwrap();
+        assert_eq!(err.to_string(), message);
+    }
+    // Claiming envelope support without exporting `handle_envelope`.
+    let Some(path) = build_plugin("noenvelope", &["-DCAPS=1"]) else {
+        return;
+    };
+    let err = unsafe { CAbiModule::load(&path) }.err().unwrap();
+    assert!(err.to_string().contains("handle_envelope"), "{}", err);
+}
@@ -0,0 +1,424 @@
+//! Tests for plugins behind the `Handler` trait and the request envelope,
+//! without a socket
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use wigspace_rust::config::Config;
+use wigspace_rust::handler_trait::{ConnectionInfo, Handler, ResponseBody, request_body};
+use wigspace_rust::logging_middleware::LoggingMiddleware;
+use wigspace_rust::middleware_registry::chain;
+use wigspace_rust::modules::dynamic_loader::{
+    DynamicModule, PluginLifecycle, ScriptingModule, WasmModule,
+};
+use wigspace_rust::modules::envelope::{PluginRequest, PluginResponse};
+use wigspace_rust::plugin_handler::{
+    LifecycleAction, LifecycleHandler, MAX_BODY_SIZE, PluginHandler, SharedPlugin, Unloader,
+};
+use wigspace_rust::reload::PluginCache;
+use wigspace_rust::router::RouteParams;
+
+/// Echoes its input and counts lifecycle calls.
+#[derive(Default)]
+struct Echo {
+    inits: u32,
+}
+
+impl DynamicModule for Echo {
+    fn handle(&self, input: &str) -> String {
+        format!("echo: {}", input)
+    }
+}
+
+impl PluginLifecycle for Echo {
+    fn init(&mut self) -> String {
+        self.inits += 1;
+        format!("[echo] init: {}", self.inits)
+    }
+    fn shutdown(&mut self) -> String {
+        "[echo] shutdown".to_string()
+    }
+    fn reload(&mut self) -> String {
+        "[echo] reload: success".to_string()
+    }
+}
+
+async fn call(handler: &dyn Handler, req: Request<()>) -> (u16, String) {
+    call_with(handler, req, Config::default()).await
+}
+
+async fn call_with(handler: &dyn Handler, req: Request<()>, config: Config) -> (u16, String) {
+    let req = req.map(|_| request_body(http_body_util::Empty::new()));
+    let resp: Response<ResponseBody> = handler
+        .handle(req, Arc::new(RwLock::new(config)))
+        .await
+        .unwrap();
+    let status = resp.status().as_u16();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_plugin_handler_passes_request_line_and_params() {
+    let plugin: SharedPlugin = Arc::new(RwLock::new(Echo::default()));
+    let handler = PluginHandler::new(plugin);
+    let req = Request::get("/users/7?full=1").body(()).unwrap();
+    assert_eq!(
+        call(&handler, req).await,
+        (200, "echo: GET /users/7?full=1".to_string())
+    );
+
+    // Composes with middleware like any other handler.
+    let handler = chain(&[Arc::new(LoggingMiddleware::new())], Arc::new(handler));
+    let mut params = RouteParams::new();
+    params.push("id", "7");
+    let mut req = Request::post("/users/7").body(()).unwrap();
+    req.extensions_mut().insert(params);
+    assert_eq!(
+        call(handler.as_ref(), req).await,
+        (200, "echo: POST /users/7 id=7".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_lifecycle_handler() {
+    let slot = Arc::new(Mutex::new(None::<Echo>));
+    let init = LifecycleHandler::new("echo", slot.clone(), LifecycleAction::Init);
+    let reload = LifecycleHandler::new("echo", slot.clone(), LifecycleAction::Reload)
+        .with_loader(Arc::new(|| Ok(Echo::default())));
+    let get = || Request::get("/").body(()).unwrap();
+
+    assert_eq!(
+        call(&init, get()).await,
+        (500, "[echo] not loaded".to_string())
+    );
+    assert_eq!(
+        call(&reload, get()).await,
+        (200, "[echo] loaded".to_string())
+    );
+    assert_eq!(
+        call(&init, get()).await,
+        (200, "[echo] init: 1".to_string())
+    );
+    assert_eq!(
+        call(&reload, get()).await,
+        (200, "[echo] reload: success".to_string())
+    );
+
+    let failing =
+        LifecycleHandler::new("echo", Arc::new(Mutex::new(None)), LifecycleAction::Reload)
+            .with_loader(Arc::new(|| Err::<Echo, _>("not found".to_string())));
+    assert_eq!(
+        call(&failing, get()).await,
+        (500, "[echo] reload error: not found".to_string())
+    );
+
+    // Plugin code runs on a blocking thread, not the runtime's.
+    let runtime_thread = std::thread::current().id();
+    let off_runtime =
+        LifecycleHandler::new("echo", Arc::new(Mutex::new(None)), LifecycleAction::Reload)
+            .with_loader(Arc::new(move || {
+                assert_ne!(std::thread::current().id(), runtime_thread);
+                Ok(Echo::default())
+            }));
+    assert_eq!(
+        call(&off_runtime, get()).await,
+        (200, "[echo] loaded".to_string())
+    );
+}
+
+#[test]
+fn test_envelope_json() {
+    let mut req = PluginRequest::new("POST", "/users/7");
+    req.headers.push(("x-a".to_string(), "1".to_string()));
+    req.body = vec![0, 159, 146, 150];
+    req.params.push("id", "7");
+    req.params.push("action", "edit");
+    let json = req.to_json();
+    assert!(
+        json.contains(r#""params":{"id":"7","action":"edit"}"#),
+        "{}",
+        json
+    );
+    assert!(json.contains(r#""body":"AJ+Slg==""#), "{}", json);
+    assert_eq!(serde_json::from_str::<PluginRequest>(&json).unwrap(), req);
+
+    let resp = PluginResponse::from_json(r#"{"version": 1, "body": "aGk="}"#).unwrap();
+    assert_eq!((resp.status, resp.body.as_slice()), (200, &b"hi"[..]));
+    let err = PluginResponse::from_json(r#"{"version": 2}"#).unwrap_err();
+    assert_eq!(
+        err,
+        "response envelope version 2 is not supported (expected 1)"
+    );
+    assert!(PluginResponse::from_json(r#"{"status": 200}"#).is_err());
+}
+
+fn plugin_file(name: &str, source: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "wigspace_plugin_handler_{}_{}",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn test_lua_request_envelope() {
+    let path = plugin_file(
+        "echo.lua",
+        r#"
+        function handle_request(req)
+          return {
+            status = 201,
+            headers = { ["x-echo"] = req.headers["x-a"] },
+            body = req.method .. " " .. req.params.id .. " " .. req.client .. " " .. req.body,
+          }
+        end
+        "#,
+    );
+    let module = ScriptingModule::load(&path).unwrap();
+    let handler = PluginHandler::new(Arc::new(RwLock::new(module)));
+    let mut params = RouteParams::new();
+    params.push("id", "7");
+    let mut req = Request::put("/users/7")
+        .header("x-a", "1")
+        .header("x-a", "2")
+        .body(request_body(Full::new(Bytes::from("payload"))))
+        .unwrap();
+    req.extensions_mut().insert(params);
+    req.extensions_mut().insert(ConnectionInfo {
+        remote_addr: "10.0.0.1:5000".parse().unwrap(),
+        tls: false,
+    });
+    let resp = handler
+        .handle(req, Arc::new(RwLock::new(Config::default())))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    assert_eq!(resp.headers()["x-echo"], "1, 2");
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "PUT 7 10.0.0.1:5000 payload");
+
+    std::fs::write(&path, "function handle_request(req) return 42 end").unwrap();
+    let module = ScriptingModule::load(&path).unwrap();
+    let handler = PluginHandler::new(Arc::new(RwLock::new(module)));
+    let (status, body) = call(&handler, Request::get("/").body(()).unwrap()).await;
+    assert_eq!(status, 500);
+    assert!(body.contains("expected a string or table"), "{}", body);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_wasm_request_envelope_and_body_limit() {
+    // Answers 418 when the envelope it was given ends in `}`, otherwise with
+    // the empty string at 64.
+    let path = plugin_file(
+        "teapot.wat",
+        r#"(module
+          (import "env" "memory" (memory 1))
+          (func (export "handle_envelope") (param i32 i32) (result i32)
+            (if (result i32)
+              (i32.eq
+                (i32.load8_u (i32.sub (i32.add (local.get 0) (local.get 1)) (i32.const 1)))
+                (i32.const 125))
+              (then (i32.const 0))
+              (else (i32.const 64))))
+          (data (i32.const 0) "{\"version\": 1, \"status\": 418, \"body\": \"dGVh\"}\00"))"#,
+    );
+    let module = WasmModule::load(&path).unwrap();
+    let handler = PluginHandler::new(Arc::new(RwLock::new(module)));
+    assert_eq!(
+        call(&handler, Request::get("/").body(()).unwrap()).await,
+        (418, "tea".to_string())
+    );
+
+    // Larger than the module's one page of memory.
+    let req = Request::post("/")
+        .body(request_body(Full::new(Bytes::from(vec![b'x'; 100 * 1024]))))
+        .unwrap();
+    let resp = handler
+        .handle(req, Arc::new(RwLock::new(Config::default())))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 418);
+
+    let req = Request::post("/")
+        .body(request_body(Full::new(Bytes::from(vec![
+            b'x';
+            MAX_BODY_SIZE + 1
+        ]))))
+        .unwrap();
+    let resp = handler
+        .handle(req, Arc::new(RwLock::new(Config::default())))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 413);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Takes 100ms per call and records how many calls overlapped.
+#[derive(Default)]
+struct Slow {
+    running: AtomicUsize,
+    most: AtomicUsize,
+}
+
+impl DynamicModule for Slow {
+    fn handle(&self, _input: &str) -> String {
+        let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.most.fetch_max(now, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(100));
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        "done".to_string()
+    }
+}
+
+#[tokio::test]
+async fn test_plugin_concurrency_limit() {
+    let slow = Arc::new(RwLock::new(Slow::default()));
+    let handler = Arc::new(PluginHandler::new(slow.clone()).with_concurrency(2));
+    let calls: Vec<_> = (0..6)
+        .map(|_| {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                call(handler.as_ref(), Request::get("/").body(()).unwrap()).await
+            })
+        })
+        .collect();
+    for c in calls {
+        assert_eq!(c.await.unwrap(), (200, "done".to_string()));
+    }
+    assert_eq!(slow.read().unwrap().most.load(Ordering::SeqCst), 2);
+}
+
+/// A config answering plugin calls 504 after a second.
+fn one_second_timeout() -> Config {
+    Config {
+        plugin_timeout: Some(1),
+        ..Config::default()
+    }
+}
+
+/// Answers after two seconds, whatever the timeout; records its shutdown.
+struct Stuck {
+    shut_down: Arc<AtomicBool>,
+}
+
+impl DynamicModule for Stuck {
+    fn handle(&self, _input: &str) -> String {
+        std::thread::sleep(Duration::from_secs(2));
+        assert!(!self.shut_down.load(Ordering::SeqCst));
+        "late".to_string()
+    }
+}
+
+impl PluginLifecycle for Stuck {
+    fn init(&mut self) -> String {
+        String::new()
+    }
+    fn shutdown(&mut self) -> String {
+        self.shut_down.store(true, Ordering::SeqCst);
+        "[stuck] shutdown".to_string()
+    }
+    fn reload(&mut self) -> String {
+        String::new()
+    }
+}
+
+#[tokio::test]
+async fn test_plugin_unloaded_after_its_timed_out_call_returns() {
+    let shut_down = Arc::new(AtomicBool::new(false));
+    let plugin: SharedPlugin = Arc::new(RwLock::new(Stuck {
+        shut_down: shut_down.clone(),
+    }));
+    let (unloader, unloading) = Unloader::spawn(Arc::new(RwLock::new(one_second_timeout())));
+    let mut cache = PluginCache::new();
+    let handler = cache
+        .get_or_load(Path::new("stuck.so"), |_| {
+            Some(Arc::new(
+                PluginHandler::new(plugin.clone()).on_release(unloader.release(plugin)),
+            ))
+        })
+        .unwrap();
+    assert!(cache.sweep().is_empty());
+
+    let get = Request::get("/").body(()).unwrap();
+    assert_eq!(
+        call_with(handler.as_ref(), get, one_second_timeout()).await,
+        (504, "Plugin timed out".to_string())
+    );
+    // A reload no longer uses the plugin while its call is still running:
+    // the sweep does not wait for it, and the shutdown waits for the call.
+    drop(handler);
+    let started = std::time::Instant::now();
+    assert_eq!(cache.sweep().len(), 1);
+    drop(unloader);
+    assert!(started.elapsed() < Duration::from_millis(100));
+    assert!(!shut_down.load(Ordering::SeqCst));
+    unloading.await.unwrap();
+    assert!(shut_down.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn test_lua_timeout_and_sleep_host_call() {
+    let path = plugin_file(
+        "spin.lua",
+        r#"
+        function handle_request(req)
+          if req.uri == "/spin" then
+            while true do end
+          end
+          sleep(20)
+          return "slept"
+        end
+        "#,
+    );
+    let module = ScriptingModule::load(&path).unwrap();
+    let handler = PluginHandler::new(Arc::new(RwLock::new(module))).with_concurrency(1);
+    let get = |uri| Request::get(uri).body(()).unwrap();
+    assert_eq!(
+        call_with(&handler, get("/spin"), one_second_timeout()).await,
+        (504, "Plugin timed out".to_string())
+    );
+    // The spinning script was stopped, so its slot is free again.
+    assert_eq!(
+        call_with(&handler, get("/"), one_second_timeout()).await,
+        (200, "slept".to_string())
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_wasm_timeout_and_sleep_host_call() {
+    // `handle` spins on the 9-byte request line `GET /spin`, otherwise
+    // sleeps and answers.
+    let path = plugin_file(
+        "spin.wat",
+        r#"(module
+          (import "env" "memory" (memory 1))
+          (import "env" "sleep_ms" (func $sleep (param i32)))
+          (func (export "handle") (param i32 i32) (result i32)
+            (if (i32.eq (local.get 1) (i32.const 9))
+              (then (loop $spin (br $spin))))
+            (call $sleep (i32.const 20))
+            (i32.const 0))
+          (data (i32.const 0) "slept\00"))"#,
+    );
+    let module = WasmModule::load(&path).unwrap();
+    assert_eq!(module.handle("GET /"), "slept");
+    let handler = PluginHandler::new(Arc::new(RwLock::new(module))).with_concurrency(1);
+    let get = |uri| Request::get(uri).body(()).unwrap();
+    assert_eq!(
+        call_with(&handler, get("/spin"), one_second_timeout()).await,
+        (504, "Plugin timed out".to_string())
+    );
+    assert_eq!(
+        call_with(&handler, get("/"), one_second_timeout()).await,
+        (200, "slept".to_string())
+    );
+    std::fs::remove_file(&path).unwrap();
+}
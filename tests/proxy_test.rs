@@ -0,0 +1,215 @@
+//! Integration tests for the proxy_pass reverse proxy
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use wigspace_rust::config::{Config, LoadBalance, UpstreamConfig, UpstreamServerConfig};
+use wigspace_rust::handler_trait::{ConnectionInfo, Handler, request_body};
+use wigspace_rust::proxy::ProxyHandler;
+
+/// Upstream that echoes the request line, forwarding headers and body.
+async fn spawn_echo_upstream(delay: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    tokio::time::sleep(delay).await;
+                    let header = |name: &str| {
+                        req.headers()
+                            .get(name)
+                            .map(|v| v.to_str().unwrap().to_string())
+                            .unwrap_or_default()
+                    };
+                    let head = format!(
+                        "{} {}\nhost: {}\nx-forwarded-for: {}\nx-forwarded-proto: {}\nforwarded: {}\n",
+                        req.method(),
+                        req.uri(),
+                        header("host"),
+                        header("x-forwarded-for"),
+                        header("x-forwarded-proto"),
+                        header("forwarded"),
+                    );
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    let mut out = head.into_bytes();
+                    out.extend_from_slice(&body);
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header("connection", "close")
+                            .body(Full::new(Bytes::from(out)))
+                            .unwrap(),
+                    )
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+/// Front server that sends every request through `ProxyHandler`.
+async fn spawn_proxy(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(ProxyHandler::from_config(&config));
+    let config = Arc::new(RwLock::new(config));
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = listener.accept().await.unwrap();
+            let handler = handler.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |mut req: Request<Incoming>| {
+                    let handler = handler.clone();
+                    let config = config.clone();
+                    async move {
+                        req.extensions_mut().insert(ConnectionInfo {
+                            remote_addr,
+                            tls: false,
+                        });
+                        handler.handle(req.map(request_body), config).await
+                    }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+async fn send(addr: SocketAddr, req: Request<Full<Bytes>>) -> (StatusCode, String) {
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let (parts, body) = req.into_parts();
+    let mut req = Request::from_parts(parts, body);
+    let uri = format!("http://{}{}", addr, req.uri().path_and_query().unwrap());
+    *req.uri_mut() = uri.parse().unwrap();
+    let resp = client.request(req).await.unwrap();
+    let status = resp.status();
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    (status, String::from_utf8_lossy(&body).into_owned())
+}
+
+#[tokio::test]
+async fn test_proxy_forwards_request_and_rewrites_headers() {
+    let upstream = spawn_echo_upstream(Duration::ZERO).await;
+    let proxy = spawn_proxy(Config {
+        proxy_pass: Some(format!("http://{}/api", upstream)),
+        ..Config::default()
+    })
+    .await;
+
+    let req = Request::post("/users?id=1")
+        .header("host", "example.test")
+        .header("x-forwarded-for", "10.0.0.1")
+        .body(Full::new(Bytes::from("payload")))
+        .unwrap();
+    let (status, body) = send(proxy, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("POST /api/users?id=1\n"), "{}", body);
+    assert!(body.contains(&format!("host: {}\n", upstream)), "{}", body);
+    assert!(
+        body.contains("x-forwarded-for: 10.0.0.1, 127.0.0.1\n"),
+        "{}",
+        body
+    );
+    assert!(body.contains("x-forwarded-proto: http\n"), "{}", body);
+    assert!(
+        body.contains("forwarded: for=127.0.0.1;host=\"example.test\";proto=http\n"),
+        "{}",
+        body
+    );
+    assert!(body.ends_with("payload"), "{}", body);
+}
+
+#[tokio::test]
+async fn test_proxy_returns_502_when_upstream_down() {
+    // Reserve a port and close it so connections are refused
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = spawn_proxy(Config {
+        proxy_pass: Some(format!("http://{}", closed)),
+        ..Config::default()
+    })
+    .await;
+
+    let req = Request::get("/").body(Full::new(Bytes::new())).unwrap();
+    let (status, _) = send(proxy, req).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn test_proxy_returns_504_on_read_timeout() {
+    let upstream = spawn_echo_upstream(Duration::from_secs(3)).await;
+    let proxy = spawn_proxy(Config {
+        proxy_pass: Some(format!("http://{}", upstream)),
+        proxy_read_timeout: Some(1),
+        ..Config::default()
+    })
+    .await;
+
+    let req = Request::get("/slow").body(Full::new(Bytes::new())).unwrap();
+    let (status, _) = send(proxy, req).await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn test_proxy_retries_idempotent_request_on_next_backend() {
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let live = spawn_echo_upstream(Duration::ZERO).await;
+    let mut upstreams = HashMap::new();
+    upstreams.insert(
+        "backend".to_string(),
+        UpstreamConfig {
+            servers: [closed, live]
+                .iter()
+                .map(|addr| UpstreamServerConfig {
+                    address: addr.to_string(),
+                    weight: None,
+                })
+                .collect(),
+            strategy: LoadBalance::RoundRobin,
+            hash_key: None,
+            max_fails: Some(1),
+            fail_timeout: Some(30),
+            health_check: None,
+        },
+    );
+    let proxy = spawn_proxy(Config {
+        proxy_pass: Some("http://backend".to_string()),
+        upstreams: Some(upstreams),
+        ..Config::default()
+    })
+    .await;
+
+    for _ in 0..3 {
+        let req = Request::get("/retry")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let (status, body) = send(proxy, req).await;
+        assert_eq!(status, StatusCode::OK);
+        // Host is the group name, as nginx sends $proxy_host
+        assert!(body.starts_with("GET /retry\nhost: backend\n"), "{}", body);
+    }
+}
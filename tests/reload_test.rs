@@ -0,0 +1,129 @@
+//! Tests for the hot-reload plugin cache, listener diff and config watcher
+use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use wigspace_rust::config::config_sources;
+use wigspace_rust::reload::{ConfigWatcher, PluginCache, ReloadStatus, diff_listens};
+use wigspace_rust::vhost::Listen;
+
+#[test]
+fn test_plugin_cache_reuses_unchanged_files() {
+    let dir = std::env::temp_dir().join(format!("wigspace_reload_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let hello = dir.join("hello.lua");
+    let other = dir.join("other.lua");
+    std::fs::write(&hello, "v1").unwrap();
+    std::fs::write(&other, "v1").unwrap();
+
+    let mut cache = PluginCache::new();
+    let mut loads = 0;
+    let mut load = |path: &std::path::Path| {
+        loads += 1;
+        Some(format!(
+            "{}#{}",
+            std::fs::read_to_string(path).unwrap(),
+            loads
+        ))
+    };
+    assert_eq!(cache.get_or_load(&hello, &mut load).unwrap(), "v1#1");
+    assert_eq!(cache.get_or_load(&other, &mut load).unwrap(), "v1#2");
+    assert!(cache.sweep().is_empty());
+
+    // Unchanged: the same instance. Changed: a new one, the old is swept.
+    assert_eq!(cache.get_or_load(&hello, &mut load).unwrap(), "v1#1");
+    std::fs::write(&other, "v2").unwrap();
+    let later = SystemTime::now() + Duration::from_secs(10);
+    File::options()
+        .write(true)
+        .open(&other)
+        .unwrap()
+        .set_modified(later)
+        .unwrap();
+    assert_eq!(cache.get_or_load(&other, &mut load).unwrap(), "v2#3");
+    assert_eq!(cache.sweep(), vec!["v1#2".to_string()]);
+
+    // A plugin the new routing table leaves out is unloaded.
+    assert_eq!(cache.get_or_load(&other, &mut load).unwrap(), "v2#3");
+    assert_eq!(cache.sweep(), vec!["v1#1".to_string()]);
+    assert_eq!(cache.len(), 1);
+
+    // A changed file that fails to load keeps the loaded instance.
+    File::options()
+        .write(true)
+        .open(&other)
+        .unwrap()
+        .set_modified(later + Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(cache.get_or_load(&other, |_| None).unwrap(), "v2#3");
+    assert!(cache.sweep().is_empty());
+    assert_eq!(cache.get_or_load(&hello, |_| None), None);
+    assert_eq!(cache.drain(), vec!["v2#3".to_string()]);
+    assert!(cache.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_diff_listens() {
+    let listen = |s: &str| Listen::parse(s, "127.0.0.1").unwrap();
+    let old = [listen("8080"), listen("8443 ssl"), listen("9000")];
+    let new = [listen("8080"), listen("8443"), listen("9100")];
+    let (removed, added) = diff_listens(&old, &new);
+    assert_eq!(removed, vec![listen("8443 ssl"), listen("9000")]);
+    assert_eq!(added, vec![listen("8443"), listen("9100")]);
+    assert_eq!(diff_listens(&new, &new), (vec![], vec![]));
+}
+
+#[test]
+fn test_reload_status_counts_failures() {
+    let mut status = ReloadStatus::default();
+    status.record(Err("config.yaml: bad port".to_string()));
+    status.record(Ok(()));
+    assert_eq!(status.reloads, 2);
+    assert_eq!(status.failures, 1);
+    assert!(status.last_reload.is_some());
+    assert_eq!(status.last_error, None);
+}
+
+#[test]
+fn test_config_watcher_debounces_and_filters() {
+    let dir = std::env::temp_dir().join(format!("wigspace_watch_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.yaml");
+    std::fs::write(&path, "port: 8080\n").unwrap();
+
+    let changes = Arc::new(AtomicUsize::new(0));
+    let counter = changes.clone();
+    let _watcher = ConfigWatcher::new(
+        config_sources(&path),
+        Duration::from_millis(200),
+        move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        },
+    )
+    .unwrap();
+    let settle = || std::thread::sleep(Duration::from_millis(800));
+
+    // Other files in the directory are ignored.
+    std::fs::write(dir.join("notes.txt"), "hello").unwrap();
+    settle();
+    assert_eq!(changes.load(Ordering::SeqCst), 0);
+
+    // A burst of writes is one reload.
+    for port in 8081..8086 {
+        std::fs::write(&path, format!("port: {}\n", port)).unwrap();
+    }
+    settle();
+    assert_eq!(changes.load(Ordering::SeqCst), 1);
+
+    // Editors that write a temp file and rename it over the config, twice
+    // to check the watch survives the replaced inode.
+    for port in [9000, 9001] {
+        let tmp = dir.join(".config.yaml.tmp");
+        std::fs::write(&tmp, format!("port: {}\n", port)).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        settle();
+    }
+    assert_eq!(changes.load(Ordering::SeqCst), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
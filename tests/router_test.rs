@@ -0,0 +1,155 @@
+//! Integration tests for the request router
+use hyper::Method;
+use std::collections::HashMap;
+use wigspace_rust::config::{Config, RouteConfig};
+use wigspace_rust::router::{RouteLookup, RouteTarget, Router};
+
+fn found<'a>(
+    router: &'a Router<&'static str>,
+    method: Method,
+    path: &str,
+) -> Option<(&'a str, Vec<(String, String)>)> {
+    match router.find(&method, path) {
+        RouteLookup::Found(m) => Some((
+            *m.target,
+            m.params
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_router_patterns() {
+    let mut router = Router::new();
+    router.add("/users/:id", None, "user").unwrap();
+    router.add("/api/*", None, "api").unwrap();
+    router.add_prefix("/plugin", None, "plugin").unwrap();
+    router
+        .add_regex(r"^/v(?P<version>\d+)/items$", None, "items")
+        .unwrap();
+    router.add("/exact", None, "exact").unwrap();
+
+    assert_eq!(
+        found(&router, Method::GET, "/users/42"),
+        Some(("user", vec![("id".into(), "42".into())]))
+    );
+    assert_eq!(found(&router, Method::GET, "/users/42/posts"), None);
+    assert_eq!(found(&router, Method::GET, "/users/"), None);
+    assert_eq!(
+        found(&router, Method::GET, "/api/a/b"),
+        Some(("api", vec![("*".into(), "a/b".into())]))
+    );
+    assert_eq!(
+        found(&router, Method::GET, "/plugin/foo").map(|f| f.0),
+        Some("plugin")
+    );
+    assert_eq!(
+        found(&router, Method::GET, "/plugin").map(|f| f.0),
+        Some("plugin")
+    );
+    assert_eq!(found(&router, Method::GET, "/pluginx"), None);
+    assert_eq!(
+        found(&router, Method::GET, "/v2/items"),
+        Some(("items", vec![("version".into(), "2".into())]))
+    );
+    assert_eq!(
+        found(&router, Method::GET, "/exact").map(|f| f.0),
+        Some("exact")
+    );
+    assert_eq!(found(&router, Method::GET, "/exact/more"), None);
+}
+
+#[test]
+fn test_regex_routes_match_the_whole_path() {
+    let mut router = Router::new();
+    router.add_regex(r"/files/(\d+)", None, "file").unwrap();
+    router.add_regex(r"/a|/b", None, "ab").unwrap();
+
+    assert_eq!(
+        found(&router, Method::GET, "/files/7"),
+        Some(("file", vec![("1".into(), "7".into())]))
+    );
+    assert_eq!(found(&router, Method::GET, "/files/7/raw"), None);
+    assert_eq!(found(&router, Method::GET, "/old/files/7"), None);
+    assert_eq!(found(&router, Method::GET, "/b").map(|f| f.0), Some("ab"));
+    assert_eq!(found(&router, Method::GET, "/a/b"), None);
+}
+
+#[test]
+fn test_router_method_filter() {
+    let mut router = Router::new();
+    router
+        .add("/items/:id", Some(&["get".to_string()]), "read")
+        .unwrap();
+    router
+        .add("/items/:id", Some(&["DELETE".to_string()]), "delete")
+        .unwrap();
+
+    assert_eq!(
+        found(&router, Method::GET, "/items/1").map(|f| f.0),
+        Some("read")
+    );
+    assert_eq!(
+        found(&router, Method::DELETE, "/items/1").map(|f| f.0),
+        Some("delete")
+    );
+    match router.find(&Method::POST, "/items/1") {
+        RouteLookup::MethodNotAllowed(allowed) => {
+            assert_eq!(allowed, vec![Method::GET, Method::DELETE])
+        }
+        _ => panic!("expected 405 for POST"),
+    }
+    assert!(matches!(
+        router.find(&Method::POST, "/other"),
+        RouteLookup::NotFound
+    ));
+}
+
+#[test]
+fn test_router_from_config() {
+    let mut endpoints = HashMap::new();
+    endpoints.insert("/plugin".to_string(), "libplugin_example.so".to_string());
+    endpoints.insert("/plugin/admin".to_string(), "admin.lua".to_string());
+    let config = Config {
+        plugin_endpoints: Some(endpoints),
+        routes: Some(vec![RouteConfig {
+            path: Some("/users/:id".to_string()),
+            prefix: None,
+            regex: None,
+            methods: Some(vec!["GET".to_string()]),
+            plugin: None,
+            handler: Some("static".to_string()),
+            index: None,
+            autoindex: None,
+            autoindex_format: None,
+            middleware: None,
+        }]),
+        ..Config::default()
+    };
+    let router = Router::from_config(&config, |target, _| Some(target.clone()));
+
+    match router.find(&Method::GET, "/users/7") {
+        RouteLookup::Found(m) => {
+            assert_eq!(*m.target, RouteTarget::Handler("static".to_string()));
+            assert_eq!(m.params.get("id"), Some("7"));
+        }
+        _ => panic!("expected /users/7 to match"),
+    }
+    // Longest plugin endpoint wins regardless of map order
+    match router.find(&Method::GET, "/plugin/admin/x") {
+        RouteLookup::Found(m) => {
+            assert_eq!(*m.target, RouteTarget::Plugin("admin.lua".to_string()))
+        }
+        _ => panic!("expected /plugin/admin/x to match"),
+    }
+    match router.find(&Method::POST, "/plugin/foo") {
+        RouteLookup::Found(m) => assert_eq!(
+            *m.target,
+            RouteTarget::Plugin("libplugin_example.so".to_string())
+        ),
+        _ => panic!("expected /plugin/foo to match"),
+    }
+}
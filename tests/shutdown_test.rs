@@ -0,0 +1,103 @@
+//! Tests for graceful shutdown: connection draining, pid file, plugin hooks
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use wigspace_rust::connection;
+use wigspace_rust::modules::dynamic_loader::{PluginLifecycle, ScriptingModule};
+use wigspace_rust::shutdown::{self, PidFile};
+
+/// Open a connection to a server whose responses take `delay`, watched by
+/// `graceful`, and start a request on it.
+async fn slow_request(
+    graceful: &GracefulShutdown,
+    delay: Duration,
+) -> tokio::task::JoinHandle<Result<Response<Incoming>, hyper::Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let watcher = graceful.watcher();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(move |_req: Request<Incoming>| async move {
+            tokio::time::sleep(delay).await;
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("done"))))
+        });
+        let builder = connection::builder(None);
+        let conn = builder.serve_connection(TokioIo::new(stream), service);
+        let _ = watcher.watch(conn).await;
+    });
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let req = Request::get("/slow").body(Empty::<Bytes>::new()).unwrap();
+    let resp = tokio::spawn(async move { sender.send_request(req).await });
+    // Let the request reach the handler before shutting down.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    resp
+}
+
+#[tokio::test]
+async fn test_drain_waits_for_in_flight_requests() {
+    let graceful = GracefulShutdown::new();
+    let resp = slow_request(&graceful, Duration::from_millis(200)).await;
+    assert!(shutdown::drain(graceful, Duration::from_secs(5)).await);
+    let resp = resp.await.unwrap().unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"done");
+}
+
+#[tokio::test]
+async fn test_drain_gives_up_after_timeout() {
+    let graceful = GracefulShutdown::new();
+    let _resp = slow_request(&graceful, Duration::from_secs(10)).await;
+    assert!(!shutdown::drain(graceful, Duration::from_millis(100)).await);
+}
+
+#[test]
+fn test_pid_file_create_and_remove() {
+    let dir = std::env::temp_dir().join(format!("wigspace_pid_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.pid");
+    let pid = PidFile::create(&path).unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.trim(), std::process::id().to_string());
+    pid.remove();
+    assert!(!path.exists());
+
+    // A pid file rewritten by another process is left alone.
+    let pid = PidFile::create(&path).unwrap();
+    std::fs::write(&path, "1\n").unwrap();
+    pid.remove();
+    assert!(path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_lua_plugin_lifecycle_hooks() {
+    let dir = std::env::temp_dir().join(format!("wigspace_lua_hooks_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("hooks.lua");
+    std::fs::write(
+        &path,
+        "function init() return 'ready' end\n\
+         function shutdown() return 'bye' end\n\
+         function handle(input) return input end\n",
+    )
+    .unwrap();
+    let mut plugin = ScriptingModule::load(&path).unwrap();
+    assert_eq!(plugin.init(), "[lua_plugin] init: ready");
+    assert_eq!(plugin.shutdown(), "[lua_plugin] shutdown: bye");
+
+    std::fs::write(&path, "function handle(input) return input end\n").unwrap();
+    let mut plugin = ScriptingModule::load(&path).unwrap();
+    assert_eq!(plugin.shutdown(), "[lua_plugin] no shutdown fn");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,260 @@
+//! Tests for static file serving
+use http_body_util::BodyExt;
+use hyper::header;
+use hyper::{Request, StatusCode};
+use std::path::{Path, PathBuf};
+use wigspace_rust::config::AutoindexFormat;
+use wigspace_rust::static_files::{DirectoryOptions, resolve_path, serve};
+
+/// Fresh static root under the system temp dir.
+fn static_root(name: &str) -> PathBuf {
+    let root =
+        std::env::temp_dir().join(format!("wigspace_static_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("css")).unwrap();
+    std::fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+    std::fs::write(root.join("css/site.css"), "body{}").unwrap();
+    std::fs::write(root.join("digits.txt"), "0123456789").unwrap();
+    root
+}
+
+async fn body_string(resp: hyper::Response<wigspace_rust::handler_trait::ResponseBody>) -> String {
+    let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[test]
+fn test_resolve_path_blocks_traversal() {
+    let root = Path::new("/srv/www");
+    assert_eq!(resolve_path(root, "/a/../b.txt"), Some(root.join("b.txt")));
+    assert_eq!(
+        resolve_path(root, "/css/./site%2Ecss"),
+        Some(root.join("css/site.css"))
+    );
+    assert_eq!(resolve_path(root, "/../etc/passwd"), None);
+    assert_eq!(resolve_path(root, "/%2e%2e/etc/passwd"), None);
+    assert_eq!(resolve_path(root, "/a/../../etc/passwd"), None);
+    assert_eq!(resolve_path(root, "/a%00b"), None);
+}
+
+#[tokio::test]
+async fn test_serves_file_with_type_and_validators() {
+    let root = static_root("get");
+    let req = Request::get("/css/site.css").body(()).unwrap();
+    let resp = serve(&root, &req, &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/css");
+    assert_eq!(resp.headers()[header::CONTENT_LENGTH], "6");
+    assert!(resp.headers().contains_key(header::ETAG));
+    assert!(resp.headers().contains_key(header::LAST_MODIFIED));
+    assert_eq!(body_string(resp).await, "body{}");
+
+    let req = Request::get("/").body(()).unwrap();
+    let resp = serve(&root, &req, &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/html");
+    assert_eq!(body_string(resp).await, "<h1>home</h1>");
+
+    let req = Request::get("/missing.txt").body(()).unwrap();
+    assert_eq!(
+        serve(&root, &req, &DirectoryOptions::default()).await.err(),
+        Some(StatusCode::NOT_FOUND)
+    );
+    let req = Request::get("/../secret").body(()).unwrap();
+    assert_eq!(
+        serve(&root, &req, &DirectoryOptions::default()).await.err(),
+        Some(StatusCode::BAD_REQUEST)
+    );
+}
+
+#[tokio::test]
+async fn test_conditional_get_returns_304() {
+    let root = static_root("conditional");
+    let req = Request::get("/digits.txt").body(()).unwrap();
+    let resp = serve(&root, &req, &DirectoryOptions::default())
+        .await
+        .unwrap();
+    let etag = resp.headers()[header::ETAG].clone();
+    let last_modified = resp.headers()[header::LAST_MODIFIED].clone();
+
+    let req = Request::get("/digits.txt")
+        .header(header::IF_NONE_MATCH, etag)
+        .body(())
+        .unwrap();
+    assert_eq!(
+        serve(&root, &req, &DirectoryOptions::default())
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::NOT_MODIFIED
+    );
+
+    let req = Request::get("/digits.txt")
+        .header(header::IF_MODIFIED_SINCE, last_modified)
+        .body(())
+        .unwrap();
+    assert_eq!(
+        serve(&root, &req, &DirectoryOptions::default())
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::NOT_MODIFIED
+    );
+
+    let req = Request::get("/digits.txt")
+        .header(header::IF_NONE_MATCH, "\"other\"")
+        .body(())
+        .unwrap();
+    assert_eq!(
+        serve(&root, &req, &DirectoryOptions::default())
+            .await
+            .unwrap()
+            .status(),
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn test_range_requests() {
+    let root = static_root("range");
+    let range = |value: &str| {
+        Request::get("/digits.txt")
+            .header(header::RANGE, value)
+            .body(())
+            .unwrap()
+    };
+
+    let resp = serve(&root, &range("bytes=2-4"), &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+    assert_eq!(body_string(resp).await, "234");
+
+    let resp = serve(&root, &range("bytes=-3"), &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(body_string(resp).await, "789");
+
+    let resp = serve(&root, &range("bytes=20-"), &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes */10");
+
+    let resp = serve(&root, &range("bytes=0-1,8-"), &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+    let content_type = resp.headers()[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap()
+        .to_string();
+    let length: usize = resp.headers()[header::CONTENT_LENGTH]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body = body_string(resp).await;
+    assert_eq!(body.len(), length);
+    assert!(
+        body.contains("Content-Range: bytes 0-1/10\r\n\r\n01\r\n"),
+        "{}",
+        body
+    );
+    assert!(
+        body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"),
+        "{}",
+        body
+    );
+    assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+}
+
+#[tokio::test]
+async fn test_head_has_headers_but_no_body() {
+    let root = static_root("head");
+    let req = Request::head("/digits.txt").body(()).unwrap();
+    let resp = serve(&root, &req, &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(resp.headers()[header::CONTENT_LENGTH], "10");
+    assert_eq!(body_string(resp).await, "");
+}
+
+#[tokio::test]
+async fn test_directory_redirects_and_index_list() {
+    let root = static_root("dir_index");
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::write(root.join("docs/default.htm"), "docs home").unwrap();
+
+    let req = Request::get("/docs?x=1").body(()).unwrap();
+    let resp = serve(&root, &req, &DirectoryOptions::default())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+    assert_eq!(resp.headers()[header::LOCATION], "/docs/?x=1");
+
+    let req = Request::get("/docs/").body(()).unwrap();
+    assert_eq!(
+        serve(&root, &req, &DirectoryOptions::default()).await.err(),
+        Some(StatusCode::FORBIDDEN)
+    );
+
+    let options = DirectoryOptions {
+        index: vec!["index.html".to_string(), "default.htm".to_string()],
+        ..DirectoryOptions::default()
+    };
+    let resp = serve(&root, &req, &options).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(body_string(resp).await, "docs home");
+
+    let req = Request::get("/").body(()).unwrap();
+    let resp = serve(&root, &req, &options).await.unwrap();
+    assert_eq!(body_string(resp).await, "<h1>home</h1>");
+}
+
+#[tokio::test]
+async fn test_autoindex_html_and_json() {
+    let root = static_root("autoindex");
+    std::fs::write(root.join("css/a <b>.css"), "x").unwrap();
+    let mut options = DirectoryOptions {
+        autoindex: true,
+        ..DirectoryOptions::default()
+    };
+
+    let req = Request::get("/css/").body(()).unwrap();
+    let resp = serve(&root, &req, &options).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(
+        resp.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html")
+    );
+    let html = body_string(resp).await;
+    assert!(html.contains("Index of /css/"));
+    assert!(html.contains("<a href=\"a%20%3Cb%3E.css\">a &lt;b&gt;.css</a>"));
+    assert!(html.contains("<a href=\"site.css\">site.css</a>"));
+
+    options.autoindex_format = AutoindexFormat::Json;
+    let req = Request::get("/").body(()).unwrap();
+    options.index.clear();
+    let resp = serve(&root, &req, &options).await.unwrap();
+    assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+    let json = body_string(resp).await;
+    // Directories come first.
+    assert!(json.starts_with("[{\"mtime\""), "{}", json);
+    assert!(json.contains("\"name\":\"css\""));
+    assert!(json.contains("\"type\":\"directory\""));
+    assert!(json.contains("\"name\":\"digits.txt\""));
+    assert!(json.contains("\"size\":10"));
+    assert!(json.find("\"css\"").unwrap() < json.find("\"digits.txt\"").unwrap());
+}
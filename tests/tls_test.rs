@@ -0,0 +1,261 @@
+//! Tests for TLS termination: SNI certificate selection and hot-reload
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::{CertificateDer, ServerName};
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use wigspace_rust::config::{Config, TlsCertificateConfig, TlsConfig};
+use wigspace_rust::handler_trait::{ConnectionInfo, Handler, request_body};
+use wigspace_rust::proxy::ProxyHandler;
+use wigspace_rust::tls::{self, CertResolver};
+
+fn cert_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wigspace_tls_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write a fresh self-signed certificate for `names` and return its DER.
+fn write_cert(dir: &Path, file: &str, names: &[&str]) -> CertificateDer<'static> {
+    let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    let generated = rcgen::generate_simple_self_signed(names).unwrap();
+    std::fs::write(dir.join(format!("{}.pem", file)), generated.cert.pem()).unwrap();
+    std::fs::write(
+        dir.join(format!("{}.key", file)),
+        generated.key_pair.serialize_pem(),
+    )
+    .unwrap();
+    generated.cert.der().clone()
+}
+
+fn cert_config(dir: &Path, file: &str, names: Option<&[&str]>) -> TlsCertificateConfig {
+    TlsCertificateConfig {
+        cert: dir.join(format!("{}.pem", file)).display().to_string(),
+        key: dir.join(format!("{}.key", file)).display().to_string(),
+        server_names: names.map(|n| n.iter().map(|s| s.to_string()).collect()),
+    }
+}
+
+/// TLS server that answers every connection with "ok".
+async fn spawn_tls_server(resolver: Arc<CertResolver>) -> std::net::SocketAddr {
+    let acceptor =
+        TlsAcceptor::from(tls::server_config(resolver, vec![b"http/1.1".to_vec()]).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.write_all(b"ok").await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Handshake with `server_name`, trusting only `root`; returns the body.
+async fn fetch(
+    addr: std::net::SocketAddr,
+    server_name: &str,
+    root: &CertificateDer<'static>,
+) -> Result<String, std::io::Error> {
+    send(addr, server_name, root, b"").await
+}
+
+/// Like `fetch`, writing `request` after the handshake and returning all
+/// that comes back.
+async fn send(
+    addr: std::net::SocketAddr,
+    server_name: &str,
+    root: &CertificateDer<'static>,
+    request: &[u8],
+) -> Result<String, std::io::Error> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(root.clone()).unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(addr).await?;
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let mut stream = connector.connect(name, stream).await?;
+    stream.write_all(request).await?;
+    let mut body = String::new();
+    stream.read_to_string(&mut body).await?;
+    Ok(body)
+}
+
+#[tokio::test]
+async fn test_sni_selects_certificate() {
+    let dir = cert_dir("sni");
+    let a = write_cert(&dir, "a", &["a.test"]);
+    let wild = write_cert(&dir, "wild", &["x.example.test"]);
+    let fallback = write_cert(&dir, "default", &["localhost"]);
+    let config = TlsConfig {
+        port: None,
+        certificates: vec![
+            cert_config(&dir, "a", Some(&["a.test"])),
+            cert_config(&dir, "wild", Some(&["*.example.test"])),
+            cert_config(&dir, "default", None),
+        ],
+    };
+    let resolver = Arc::new(CertResolver::from_config(&config).unwrap());
+    assert_eq!(resolver.resolve_name(Some("A.test")).cert[0], a);
+    assert_eq!(resolver.resolve_name(Some("x.example.test")).cert[0], wild);
+    assert_eq!(
+        resolver.resolve_name(Some("a.b.example.test")).cert[0],
+        fallback
+    );
+    assert_eq!(resolver.resolve_name(None).cert[0], fallback);
+
+    let addr = spawn_tls_server(resolver).await;
+    assert_eq!(fetch(addr, "a.test", &a).await.unwrap(), "ok");
+    assert_eq!(fetch(addr, "x.example.test", &wild).await.unwrap(), "ok");
+    assert_eq!(fetch(addr, "localhost", &fallback).await.unwrap(), "ok");
+    // The default certificate is not trusted for a.test's root.
+    assert!(fetch(addr, "other.test", &a).await.is_err());
+}
+
+#[tokio::test]
+async fn test_failed_reload_keeps_certificates() {
+    let dir = cert_dir("bad_reload");
+    let first = write_cert(&dir, "site", &["localhost"]);
+    let config = TlsConfig {
+        port: None,
+        certificates: vec![cert_config(&dir, "site", None)],
+    };
+    let resolver = CertResolver::from_config(&config).unwrap();
+    std::fs::write(dir.join("site.pem"), "not a certificate").unwrap();
+    assert!(resolver.reload().is_err());
+    assert_eq!(resolver.resolve_name(Some("localhost")).cert[0], first);
+}
+
+#[tokio::test]
+async fn test_certificates_reload_on_file_change() {
+    let dir = cert_dir("watch");
+    let first = write_cert(&dir, "site", &["localhost"]);
+    let config = TlsConfig {
+        port: None,
+        certificates: vec![cert_config(&dir, "site", None)],
+    };
+    let resolver = Arc::new(CertResolver::from_config(&config).unwrap());
+    let _watcher = tls::watch(&resolver).unwrap();
+    let addr = spawn_tls_server(resolver.clone()).await;
+    assert_eq!(fetch(addr, "localhost", &first).await.unwrap(), "ok");
+
+    let second = write_cert(&dir, "site", &["localhost"]);
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if resolver.resolve_name(Some("localhost")).cert[0] == second {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "certificate was not reloaded");
+    assert_eq!(fetch(addr, "localhost", &second).await.unwrap(), "ok");
+    assert!(fetch(addr, "localhost", &first).await.is_err());
+}
+
+/// Upstream answering with the forwarding headers it was sent.
+async fn spawn_forwarded_echo() -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let header = |name: &str| {
+                        req.headers()
+                            .get(name)
+                            .map(|v| v.to_str().unwrap().to_string())
+                            .unwrap_or_default()
+                    };
+                    let body = format!(
+                        "x-forwarded-proto: {}\nforwarded: {}\n",
+                        header("x-forwarded-proto"),
+                        header("forwarded")
+                    );
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(body))))
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_proxy_over_tls_forwards_https() {
+    let dir = cert_dir("proxy");
+    let root = write_cert(&dir, "site", &["localhost"]);
+    let resolver = Arc::new(
+        CertResolver::from_config(&TlsConfig {
+            port: None,
+            certificates: vec![cert_config(&dir, "site", None)],
+        })
+        .unwrap(),
+    );
+    let upstream = spawn_forwarded_echo().await;
+    let config = Config {
+        proxy_pass: Some(format!("http://{}", upstream)),
+        ..Config::default()
+    };
+    let handler = Arc::new(ProxyHandler::from_config(&config));
+    let config = Arc::new(RwLock::new(config));
+
+    // Terminates TLS and proxies like the server's ssl listeners.
+    let acceptor =
+        TlsAcceptor::from(tls::server_config(resolver, vec![b"http/1.1".to_vec()]).unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, remote_addr) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            let handler = handler.clone();
+            let config = config.clone();
+            async move {
+                req.extensions_mut().insert(ConnectionInfo {
+                    remote_addr,
+                    tls: true,
+                });
+                handler.handle(req.map(request_body), config).await
+            }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let request = b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n";
+    let resp = send(addr, "localhost", &root, request).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+    assert!(resp.contains("x-forwarded-proto: https\n"), "{}", resp);
+    assert!(
+        resp.contains("forwarded: for=127.0.0.1;host=\"localhost\";proto=https\n"),
+        "{}",
+        resp
+    );
+}
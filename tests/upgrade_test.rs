@@ -0,0 +1,52 @@
+//! Tests for the binary upgrade socket handoff and pid file rotation
+use std::net::TcpListener;
+use std::os::fd::IntoRawFd;
+use wigspace_rust::shutdown::PidFile;
+use wigspace_rust::upgrade;
+
+#[test]
+fn test_adopt_inherited_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fd = listener.into_raw_fd();
+    let other = TcpListener::bind("127.0.0.1:0").unwrap();
+    let other_addr = other.local_addr().unwrap();
+
+    // The second entry names an fd that is not listening on that address.
+    let value = format!("{addr}={fd};127.0.0.1:1={fd};garbage");
+    let mut listeners = upgrade::adopt(&value);
+    assert_eq!(listeners.len(), 1);
+    let adopted = listeners.remove(&addr).unwrap();
+    assert_eq!(adopted.local_addr().unwrap(), addr);
+
+    // The adopted socket still accepts connections.
+    let client = std::net::TcpStream::connect(addr).unwrap();
+    let (_, peer) = adopted.accept().unwrap();
+    assert_eq!(peer, client.local_addr().unwrap());
+    assert!(upgrade::adopt(&format!("{}=-1", other_addr)).is_empty());
+}
+
+#[test]
+fn test_pid_file_rotates_to_oldbin() {
+    let dir = std::env::temp_dir().join(format!("wigspace_oldbin_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("server.pid");
+    let oldbin = dir.join("server.pid.oldbin");
+
+    let mut pid = PidFile::create(&path).unwrap();
+    pid.rotate().unwrap();
+    assert!(!path.exists());
+    assert_eq!(pid.path(), oldbin);
+    pid.restore().unwrap();
+    assert_eq!(pid.path(), path);
+    assert!(!oldbin.exists());
+
+    // After a successful upgrade the new binary owns server.pid and the old
+    // one only removes its .oldbin.
+    pid.rotate().unwrap();
+    std::fs::write(&path, "1\n").unwrap();
+    pid.remove();
+    assert!(!oldbin.exists());
+    assert!(path.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,120 @@
+//! Tests for upstream groups and load-balancing strategies
+use hyper::HeaderMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use wigspace_rust::config::{Config, LoadBalance, UpstreamConfig, UpstreamServerConfig};
+use wigspace_rust::upstream::{Backend, UpstreamPool, Upstreams};
+
+fn upstream(strategy: LoadBalance, servers: &[(&str, u32)]) -> UpstreamConfig {
+    UpstreamConfig {
+        servers: servers
+            .iter()
+            .map(|(address, weight)| UpstreamServerConfig {
+                address: address.to_string(),
+                weight: Some(*weight),
+            })
+            .collect(),
+        strategy,
+        hash_key: None,
+        max_fails: None,
+        fail_timeout: None,
+        health_check: None,
+    }
+}
+
+fn pool(config: &UpstreamConfig) -> UpstreamPool {
+    let backends = config
+        .servers
+        .iter()
+        .map(|s| Arc::new(Backend::new(s.address.clone(), s.weight.unwrap_or(1))))
+        .collect();
+    UpstreamPool::new("backend", backends, config)
+}
+
+#[test]
+fn test_round_robin_and_weighted() {
+    let rr = pool(&upstream(
+        LoadBalance::RoundRobin,
+        &[("a:1", 1), ("b:1", 1), ("c:1", 1)],
+    ));
+    let picks: Vec<_> = (0..6).map(|_| rr.pick(None, &[]).unwrap()).collect();
+    assert_eq!(picks, vec![0, 1, 2, 0, 1, 2]);
+
+    let weighted = pool(&upstream(
+        LoadBalance::Weighted,
+        &[("a:1", 5), ("b:1", 1), ("c:1", 1)],
+    ));
+    let mut counts = [0; 3];
+    for _ in 0..7 {
+        counts[weighted.pick(None, &[]).unwrap()] += 1;
+    }
+    assert_eq!(counts, [5, 1, 1]);
+}
+
+#[test]
+fn test_least_conn_prefers_idle_backend() {
+    let lc = pool(&upstream(LoadBalance::LeastConn, &[("a:1", 1), ("b:1", 1)]));
+    let _busy = lc.backend(0).track();
+    for _ in 0..4 {
+        assert_eq!(lc.pick(None, &[]), Some(1));
+    }
+}
+
+#[test]
+fn test_consistent_hash_is_sticky() {
+    let mut config = upstream(LoadBalance::Hash, &[("a:1", 1), ("b:1", 1), ("c:1", 1)]);
+    config.hash_key = Some("header:X-User".to_string());
+    let hash = pool(&config);
+    let mut headers = HeaderMap::new();
+    headers.insert("x-user", "alice".parse().unwrap());
+    let key = hash.hash_value(&headers, None).unwrap();
+    let first = hash.pick(Some(&key), &[]).unwrap();
+    for _ in 0..5 {
+        assert_eq!(hash.pick(Some(&key), &[]), Some(first));
+    }
+    // Excluding the owner moves the key elsewhere, consistently
+    let second = hash.pick(Some(&key), &[first]).unwrap();
+    assert_ne!(second, first);
+    assert_eq!(hash.pick(Some(&key), &[first]), Some(second));
+}
+
+#[test]
+fn test_failed_backend_is_skipped_until_timeout() {
+    let rr = pool(&upstream(
+        LoadBalance::RoundRobin,
+        &[("a:1", 1), ("b:1", 1)],
+    ));
+    rr.backend(0).record_failure(1, Duration::from_millis(50));
+    assert!(!rr.backend(0).is_available());
+    for _ in 0..4 {
+        assert_eq!(rr.pick(None, &[]), Some(1));
+    }
+    assert_eq!(rr.pick(None, &[1]), None);
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(rr.backend(0).is_available());
+}
+
+#[test]
+fn test_reload_keeps_unchanged_backends() {
+    let mut upstreams = HashMap::new();
+    upstreams.insert(
+        "backend".to_string(),
+        upstream(LoadBalance::RoundRobin, &[("a:1", 1), ("b:1", 1)]),
+    );
+    let mut config = Config {
+        upstreams: Some(upstreams),
+        ..Config::default()
+    };
+    let old = Upstreams::from_config(&config, None);
+    let kept = old.get("backend").unwrap().backend(0).clone();
+
+    config.upstreams.as_mut().unwrap().insert(
+        "backend".to_string(),
+        upstream(LoadBalance::RoundRobin, &[("a:1", 1), ("c:1", 1)]),
+    );
+    let new = Upstreams::from_config(&config, Some(&old));
+    let pool = new.get("backend").unwrap();
+    assert!(Arc::ptr_eq(pool.backend(0), &kept));
+    assert_eq!(pool.backend(1).address, "c:1");
+}
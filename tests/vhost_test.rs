@@ -0,0 +1,115 @@
+//! Tests for virtual host selection and server blocks
+use hyper::Request;
+use wigspace_rust::config::{Config, ServerConfig, TlsConfig};
+use wigspace_rust::vhost::{Listen, VirtualHosts, request_host};
+
+fn names(list: &[&str]) -> Vec<String> {
+    list.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn test_server_name_precedence() {
+    let mut hosts = VirtualHosts::new();
+    hosts.add(&names(&["fallback"]), "first", false).unwrap();
+    hosts
+        .add(&names(&["example.com", "www.example.com"]), "exact", false)
+        .unwrap();
+    hosts
+        .add(&names(&["*.example.com"]), "leading", false)
+        .unwrap();
+    hosts
+        .add(&names(&["*.api.example.com"]), "longer", false)
+        .unwrap();
+    hosts.add(&names(&["mail.*"]), "trailing", false).unwrap();
+    hosts
+        .add(&names(&[r"~^(\w+)\.test$"]), "regex", false)
+        .unwrap();
+    hosts.add(&names(&[".example.org"]), "dot", false).unwrap();
+
+    assert_eq!(hosts.find(Some("WWW.Example.com.")), Some(&"exact"));
+    assert_eq!(hosts.find(Some("shop.example.com")), Some(&"leading"));
+    assert_eq!(hosts.find(Some("v1.api.example.com")), Some(&"longer"));
+    assert_eq!(hosts.find(Some("mail.example.com")), Some(&"leading"));
+    assert_eq!(hosts.find(Some("mail.example.net")), Some(&"trailing"));
+    assert_eq!(hosts.find(Some("foo.test")), Some(&"regex"));
+    assert_eq!(hosts.find(Some("example.org")), Some(&"dot"));
+    assert_eq!(hosts.find(Some("a.b.example.org")), Some(&"dot"));
+    assert_eq!(hosts.find(Some("unknown.net")), Some(&"first"));
+    assert_eq!(hosts.find(None), Some(&"first"));
+}
+
+#[test]
+fn test_default_server_and_conflicts() {
+    let mut hosts = VirtualHosts::new();
+    hosts.add(&names(&["a.test"]), 1, false).unwrap();
+    hosts.add(&names(&["a.test", "b.test"]), 2, true).unwrap();
+    assert_eq!(hosts.find(Some("a.test")), Some(&1));
+    assert_eq!(hosts.find(Some("b.test")), Some(&2));
+    assert_eq!(hosts.find(Some("c.test")), Some(&2));
+    assert!(hosts.add(&names(&["~(unclosed"]), 3, false).is_err());
+}
+
+#[test]
+fn test_listen_and_request_host_parsing() {
+    let listen = Listen::parse("8080", "127.0.0.1").unwrap();
+    assert_eq!(listen.addr, "127.0.0.1:8080".parse().unwrap());
+    assert!(!listen.tls);
+    let listen = Listen::parse("*:8443 ssl", "127.0.0.1").unwrap();
+    assert_eq!(listen.addr, "0.0.0.0:8443".parse().unwrap());
+    assert!(listen.tls);
+    assert_eq!(
+        Listen::parse("[::1]:80", "127.0.0.1").unwrap().addr,
+        "[::1]:80".parse().unwrap()
+    );
+    assert!(Listen::parse("localhost:80 http2", "127.0.0.1").is_err());
+
+    let req = Request::get("/")
+        .header("host", "Example.com:8080")
+        .body(())
+        .unwrap();
+    assert_eq!(request_host(&req).as_deref(), Some("example.com"));
+    let req = Request::get("/")
+        .header("host", "[::1]:8080")
+        .body(())
+        .unwrap();
+    assert_eq!(request_host(&req).as_deref(), Some("[::1]"));
+    // HTTP/2 requests carry the host in the URI authority.
+    let req = Request::get("https://h2.test/x").body(()).unwrap();
+    assert_eq!(request_host(&req).as_deref(), Some("h2.test"));
+    assert_eq!(request_host(&Request::get("/").body(()).unwrap()), None);
+}
+
+#[test]
+fn test_servers_from_config() {
+    let config = Config {
+        static_dir: Some("public".to_string()),
+        autoindex: Some(true),
+        tls: Some(TlsConfig {
+            port: Some(9443),
+            certificates: Vec::new(),
+        }),
+        ..Config::default()
+    };
+    let servers = config.servers();
+    assert_eq!(servers.len(), 1);
+    assert_eq!(
+        servers[0].listen,
+        Some(names(&["127.0.0.1:8080", "127.0.0.1:9443 ssl"]))
+    );
+    assert_eq!(servers[0].static_dir.as_deref(), Some("public"));
+
+    let config = Config {
+        servers: Some(vec![ServerConfig {
+            listen: Some(names(&["8081"])),
+            server_name: Some(names(&["docs.test"])),
+            static_dir: Some("docs".to_string()),
+            ..ServerConfig::default()
+        }]),
+        ..config
+    };
+    let servers = config.servers();
+    let site = config.for_server(&servers[0]);
+    assert_eq!(site.static_dir.as_deref(), Some("docs"));
+    assert_eq!(site.autoindex, Some(true));
+    assert!(site.servers.is_none());
+}
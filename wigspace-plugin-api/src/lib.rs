@@ -0,0 +1,72 @@
+//! The interface between the wigspace server and native plugins (C ABI
+//! `.so` files and Rust dylibs).
+//!
+//! Every native plugin exports `plugin_info`, which the server checks
+//! before using anything else:
+//!
+//! ```
+//! use wigspace_plugin_api::{PluginInfo, ABI_VERSION, CAP_LIFECYCLE};
+//!
+//! static INFO: PluginInfo = PluginInfo::new(c"hello", c"0.1.0", CAP_LIFECYCLE);
+//!
+//! #[no_mangle]
+//! pub extern "C" fn plugin_info() -> *const PluginInfo {
+//!     &INFO
+//! }
+//! # assert_eq!(INFO.abi_version, ABI_VERSION);
+//! ```
+//!
+//! Strings a plugin returns are handed back to its `free_string` export.
+use std::ffi::CStr;
+use std::os::raw::c_char;
+
+/// Version of this interface. The server refuses plugins built for another
+/// one; it changes whenever a layout or calling convention here does.
+pub const ABI_VERSION: u32 = 1;
+
+/// Version of the JSON request/response envelope, carried in its `version`
+/// field.
+pub const ENVELOPE_VERSION: u32 = 1;
+
+/// The plugin exports `handle_envelope` and wants JSON request/response
+/// envelopes instead of the request line.
+pub const CAP_ENVELOPE: u32 = 1 << 0;
+/// The plugin exports `plugin_init` and `plugin_shutdown`.
+pub const CAP_LIFECYCLE: u32 = 1 << 1;
+
+/// What `plugin_info` returns; must live as long as the library.
+#[repr(C)]
+pub struct PluginInfo {
+    pub abi_version: u32,
+    /// Null-terminated UTF-8.
+    pub name: *const c_char,
+    /// Null-terminated UTF-8, e.g. `1.2.0`.
+    pub version: *const c_char,
+    /// `CAP_*` flags.
+    pub capabilities: u32,
+}
+
+// The pointers are to static, immutable strings.
+unsafe impl Sync for PluginInfo {}
+
+impl PluginInfo {
+    /// Info for the current `ABI_VERSION`.
+    pub const fn new(name: &'static CStr, version: &'static CStr, capabilities: u32) -> Self {
+        PluginInfo {
+            abi_version: ABI_VERSION,
+            name: name.as_ptr(),
+            version: version.as_ptr(),
+            capabilities,
+        }
+    }
+}
+
+/// Signature of `plugin_info`.
+pub type PluginInfoFn = extern "C" fn() -> *const PluginInfo;
+
+/// Functions of a Rust dylib plugin, returned by its `get_plugin_vtable`.
+#[repr(C)]
+pub struct PluginVTable {
+    /// Takes the request line, returns a string for `free_string`.
+    pub handle: extern "C" fn(*const c_char) -> *mut c_char,
+}
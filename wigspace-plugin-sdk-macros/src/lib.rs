@@ -0,0 +1,126 @@
+//! `#[plugin]`, re-exported by `wigspace-plugin-sdk`; see that crate.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::Parser;
+use syn::{ItemFn, LitStr, Path};
+
+#[derive(Default)]
+struct Args {
+    name: Option<LitStr>,
+    version: Option<LitStr>,
+    init: Option<Path>,
+    shutdown: Option<Path>,
+}
+
+/// Export a `fn(Request) -> Response` as the plugin's handler, with
+/// optional `init = path` and `shutdown = path` hooks (`fn() -> i32`) and
+/// `name = "..."` / `version = "..."` overriding the crate's.
+#[proc_macro_attribute]
+pub fn plugin(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut args = Args::default();
+    let parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("name") {
+            args.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("version") {
+            args.version = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("init") {
+            args.init = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("shutdown") {
+            args.shutdown = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("expected `name`, `version`, `init` or `shutdown`"));
+        }
+        Ok(())
+    });
+    if let Err(e) = parser.parse(attr) {
+        return e.to_compile_error().into();
+    }
+    let handler = match syn::parse::<ItemFn>(item) {
+        Ok(f) => f,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    expand(args, handler).into()
+}
+
+fn expand(args: Args, handler: ItemFn) -> TokenStream2 {
+    let sdk = quote!(::wigspace_plugin_sdk);
+    let ident = &handler.sig.ident;
+    let cstr = |lit: Option<LitStr>, default: TokenStream2| match lit {
+        Some(lit) => {
+            let bytes = format!("{}\0", lit.value());
+            quote!(#sdk::__private::cstr(#bytes.as_bytes()))
+        }
+        None => quote!(#sdk::__private::cstr(concat!(#default, "\0").as_bytes())),
+    };
+    let name = cstr(args.name, quote!(env!("CARGO_PKG_NAME")));
+    let version = cstr(args.version, quote!(env!("CARGO_PKG_VERSION")));
+
+    let lifecycle = args.init.is_some() || args.shutdown.is_some();
+    let capabilities = if lifecycle {
+        quote!(#sdk::api::CAP_ENVELOPE | #sdk::api::CAP_LIFECYCLE)
+    } else {
+        quote!(#sdk::api::CAP_ENVELOPE)
+    };
+    let hook = |export: &str, path: Option<Path>| {
+        let export = syn::Ident::new(export, proc_macro2::Span::call_site());
+        let call = match path {
+            Some(path) => quote!(#sdk::__private::hook(#path)),
+            None => quote!(0),
+        };
+        quote! {
+            #[unsafe(no_mangle)]
+            pub extern "C" fn #export() -> i32 {
+                #call
+            }
+        }
+    };
+    let hooks = if lifecycle {
+        let init = hook("plugin_init", args.init);
+        let shutdown = hook("plugin_shutdown", args.shutdown);
+        quote!(#init #shutdown)
+    } else {
+        quote!()
+    };
+
+    quote! {
+        #handler
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn plugin_info() -> *const #sdk::api::PluginInfo {
+            static INFO: #sdk::api::PluginInfo =
+                #sdk::api::PluginInfo::new(#name, #version, #capabilities);
+            &INFO
+        }
+
+        #[unsafe(no_mangle)]
+        pub extern "C" fn get_plugin_vtable() -> *const #sdk::api::PluginVTable {
+            extern "C" fn handle_line(
+                input: *const ::std::os::raw::c_char,
+            ) -> *mut ::std::os::raw::c_char {
+                unsafe { #sdk::__private::handle_line(input, #ident) }
+            }
+            static VTABLE: #sdk::api::PluginVTable =
+                #sdk::api::PluginVTable { handle: handle_line };
+            &VTABLE
+        }
+
+        /// # Safety
+        /// `input` must be a null-terminated JSON request envelope.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn handle_envelope(
+            input: *const ::std::os::raw::c_char,
+        ) -> *mut ::std::os::raw::c_char {
+            unsafe { #sdk::__private::handle_envelope(input, #ident) }
+        }
+
+        /// # Safety
+        /// `ptr` must be NULL or a string this plugin returned.
+        #[unsafe(no_mangle)]
+        pub unsafe extern "C" fn free_string(ptr: *mut ::std::os::raw::c_char) {
+            unsafe { #sdk::__private::free_string(ptr) }
+        }
+
+        #hooks
+    }
+}
@@ -0,0 +1,193 @@
+//! Write wigspace plugins as safe Rust functions.
+//!
+//! ```ignore
+//! use wigspace_plugin_sdk as wigspace;
+//! use wigspace::{Request, Response};
+//!
+//! #[wigspace::plugin(init = start)]
+//! fn handle(req: Request) -> Response {
+//!     match req.param("id") {
+//!         Some(id) => Response::text(format!("user {}", id)),
+//!         None => Response::text("no user").with_status(404),
+//!     }
+//! }
+//!
+//! fn start() -> i32 {
+//!     0
+//! }
+//! ```
+//!
+//! Build the crate as a `cdylib`. The attribute exports everything the
+//! server looks for: `plugin_info`, `get_plugin_vtable`, `handle_envelope`,
+//! `free_string` and, with `init` or `shutdown`, `plugin_init` and
+//! `plugin_shutdown`. A panic in the handler becomes a 500 response; in a
+//! hook, the code -1. The name and version reported to the server default
+//! to the crate's and can be set with `name = "..."` and `version = "..."`.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub use wigspace_plugin_api as api;
+pub use wigspace_plugin_sdk_macros::plugin;
+
+/// The request the server passes to the handler.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Request {
+    pub method: String,
+    /// Path and query, e.g. `/users/7?full=1`.
+    pub uri: String,
+    /// Header names are lowercase; repeated headers appear once per value.
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+    /// Client address, `ip:port`.
+    pub client: Option<String>,
+    /// Params the route captured, e.g. `id` for `/users/:id`.
+    pub params: BTreeMap<String, String>,
+}
+
+impl Request {
+    /// The first value of header `name`, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    /// The path, without the query.
+    pub fn path(&self) -> &str {
+        self.uri.split('?').next().unwrap_or("")
+    }
+}
+
+/// What the handler answers with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Response {
+    version: u32,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// A 200 response with `body`.
+    pub fn new(body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            version: api::ENVELOPE_VERSION,
+            status: 200,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    /// A 200 `text/plain` response.
+    pub fn text(body: impl Into<String>) -> Self {
+        Response::new(body.into()).with_header("content-type", "text/plain; charset=utf-8")
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+mod base64_body {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Used by the code `#[plugin]` generates; not a stable interface.
+#[doc(hidden)]
+pub mod __private {
+    use super::{Request, Response};
+    use std::ffi::{CStr, CString};
+    use std::os::raw::c_char;
+    use std::panic::{catch_unwind, UnwindSafe};
+
+    /// `bytes`, which end in their only NUL, as a `CStr`.
+    pub const fn cstr(bytes: &'static [u8]) -> &'static CStr {
+        match CStr::from_bytes_with_nul(bytes) {
+            Ok(s) => s,
+            Err(_) => panic!("plugin name and version must not contain NUL"),
+        }
+    }
+
+    /// Hand `s` to the server, which gives it back to `free_string`.
+    fn into_raw(s: String) -> *mut c_char {
+        // Interior NULs would cut the string short; drop them instead.
+        let bytes: Vec<u8> = s.into_bytes().into_iter().filter(|&b| b != 0).collect();
+        CString::new(bytes).unwrap().into_raw()
+    }
+
+    fn run(handler: impl FnOnce() -> Response + UnwindSafe) -> Response {
+        catch_unwind(handler).unwrap_or_else(|_| Response::text("plugin panicked").with_status(500))
+    }
+
+    /// # Safety
+    /// `input` must be a null-terminated string.
+    pub unsafe fn handle_envelope(
+        input: *const c_char,
+        handler: fn(Request) -> Response,
+    ) -> *mut c_char {
+        let input = unsafe { CStr::from_ptr(input) }.to_string_lossy();
+        let resp = match serde_json::from_str::<Request>(&input) {
+            Ok(req) => run(move || handler(req)),
+            Err(e) => Response::text(format!("invalid request envelope: {}", e)).with_status(500),
+        };
+        into_raw(serde_json::to_string(&resp).unwrap())
+    }
+
+    /// For servers calling the vtable with just `METHOD URI`: the handler
+    /// gets a request without headers or body and only its body is
+    /// returned.
+    ///
+    /// # Safety
+    /// `input` must be a null-terminated string.
+    pub unsafe fn handle_line(
+        input: *const c_char,
+        handler: fn(Request) -> Response,
+    ) -> *mut c_char {
+        let input = unsafe { CStr::from_ptr(input) }.to_string_lossy();
+        let (method, uri) = input.split_once(' ').unwrap_or((&input, ""));
+        let req = Request {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            ..Request::default()
+        };
+        let resp = run(move || handler(req));
+        into_raw(String::from_utf8_lossy(&resp.body).into_owned())
+    }
+
+    /// # Safety
+    /// `ptr` must be NULL or a string returned by this plugin.
+    pub unsafe fn free_string(ptr: *mut c_char) {
+        if !ptr.is_null() {
+            drop(unsafe { CString::from_raw(ptr) });
+        }
+    }
+
+    /// Run an `init`/`shutdown` hook; a panic returns -1.
+    pub fn hook(f: fn() -> i32) -> i32 {
+        catch_unwind(f).unwrap_or(-1)
+    }
+}
@@ -0,0 +1,80 @@
+//! Tests for the exports `#[plugin]` generates, called as the server would
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use wigspace::api::{ABI_VERSION, CAP_ENVELOPE, CAP_LIFECYCLE};
+use wigspace::{Request, Response};
+use wigspace_plugin_sdk as wigspace;
+
+#[wigspace::plugin(name = "sdk-test", shutdown = stop)]
+fn handle(req: Request) -> Response {
+    match req.path() {
+        "/panic" => panic!("boom"),
+        _ => Response::text(format!(
+            "{} {} id={} x={}",
+            req.method,
+            req.uri,
+            req.param("id").unwrap_or("-"),
+            req.header("X-Test").unwrap_or("-")
+        ))
+        .with_status(201),
+    }
+}
+
+fn stop() -> i32 {
+    7
+}
+
+/// Call an export returning a plugin string, copy it and free it.
+fn call(f: unsafe extern "C" fn(*const c_char) -> *mut c_char, input: &str) -> String {
+    let input = CString::new(input).unwrap();
+    unsafe {
+        let ptr = f(input.as_ptr());
+        let out = CStr::from_ptr(ptr).to_str().unwrap().to_string();
+        free_string(ptr);
+        out
+    }
+}
+
+unsafe extern "C" fn envelope(input: *const c_char) -> *mut c_char {
+    unsafe { handle_envelope(input) }
+}
+
+unsafe extern "C" fn line(input: *const c_char) -> *mut c_char {
+    let vtable = unsafe { &*get_plugin_vtable() };
+    (vtable.handle)(input)
+}
+
+#[test]
+fn test_plugin_info() {
+    let info = unsafe { &*plugin_info() };
+    assert_eq!(info.abi_version, ABI_VERSION);
+    assert_eq!(info.capabilities, CAP_ENVELOPE | CAP_LIFECYCLE);
+    let text = |ptr| unsafe { CStr::from_ptr(ptr) }.to_str().unwrap();
+    assert_eq!(text(info.name), "sdk-test");
+    assert_eq!(text(info.version), env!("CARGO_PKG_VERSION"));
+    assert_eq!((plugin_init(), plugin_shutdown()), (0, 7));
+}
+
+#[test]
+fn test_envelope_round_trip() {
+    let req = r#"{"version": 1, "method": "POST", "uri": "/users/7?full=1",
+        "headers": [["x-test", "yes"]], "body": "", "client": null,
+        "params": {"id": "7"}}"#;
+    let resp: serde_json::Value = serde_json::from_str(&call(envelope, req)).unwrap();
+    assert_eq!(resp["version"], 1);
+    assert_eq!(resp["status"], 201);
+    assert_eq!(resp["body"], "UE9TVCAvdXNlcnMvNz9mdWxsPTEgaWQ9NyB4PXllcw==");
+
+    let panicked = r#"{"version": 1, "method": "GET", "uri": "/panic", "headers": [],
+        "body": "", "client": null, "params": {}}"#;
+    let resp: serde_json::Value = serde_json::from_str(&call(envelope, panicked)).unwrap();
+    assert_eq!(resp["status"], 500);
+
+    let resp: serde_json::Value = serde_json::from_str(&call(envelope, "not json")).unwrap();
+    assert_eq!(resp["status"], 500);
+}
+
+#[test]
+fn test_request_line_vtable() {
+    assert_eq!(call(line, "GET /a?b=1"), "GET /a?b=1 id=- x=-");
+}